@@ -0,0 +1,700 @@
+use crate::jpeg_utils::ScanReader;
+use crate::{dct, Marker};
+
+/// Qe, Next_Index_LPS, Next_Index_MPS and Switch_MPS of each state of the probability estimation
+/// state machine, 10918-1, Table D.2, P. 58. The last state isn't in the table; it's never left and,
+/// with Qe at about one half, is used for the decisions coded with a fixed probability.
+const STATES: [(u32, u8, u8, u8); 114] = [
+    (0x5A1D, 1, 1, 1), (0x2586, 14, 2, 0), (0x1114, 16, 3, 0), (0x080B, 18, 4, 0),
+    (0x03D8, 20, 5, 0), (0x01DA, 23, 6, 0), (0x00E5, 25, 7, 0), (0x006F, 28, 8, 0),
+    (0x0036, 30, 9, 0), (0x001A, 33, 10, 0), (0x000D, 35, 11, 0), (0x0006, 9, 12, 0),
+    (0x0003, 10, 13, 0), (0x0001, 12, 13, 0), (0x5A7F, 15, 15, 1), (0x3F25, 36, 16, 0),
+    (0x2CF2, 38, 17, 0), (0x207C, 39, 18, 0), (0x17B9, 40, 19, 0), (0x1182, 42, 20, 0),
+    (0x0CEF, 43, 21, 0), (0x09A1, 45, 22, 0), (0x072F, 46, 23, 0), (0x055C, 48, 24, 0),
+    (0x0406, 49, 25, 0), (0x0303, 51, 26, 0), (0x0240, 52, 27, 0), (0x01B1, 54, 28, 0),
+    (0x0144, 56, 29, 0), (0x00F5, 57, 30, 0), (0x00B7, 59, 31, 0), (0x008A, 60, 32, 0),
+    (0x0068, 62, 33, 0), (0x004E, 63, 34, 0), (0x003B, 32, 35, 0), (0x002C, 33, 9, 0),
+    (0x5AE1, 37, 37, 1), (0x484C, 64, 38, 0), (0x3A0D, 65, 39, 0), (0x2EF1, 67, 40, 0),
+    (0x261F, 68, 41, 0), (0x1F33, 69, 42, 0), (0x19A8, 70, 43, 0), (0x1518, 72, 44, 0),
+    (0x1177, 73, 45, 0), (0x0E74, 74, 46, 0), (0x0BFB, 75, 47, 0), (0x09F8, 77, 48, 0),
+    (0x0861, 78, 49, 0), (0x0706, 79, 50, 0), (0x05CD, 48, 51, 0), (0x04DE, 50, 52, 0),
+    (0x040F, 50, 53, 0), (0x0363, 51, 54, 0), (0x02D4, 52, 55, 0), (0x025C, 53, 56, 0),
+    (0x01F8, 54, 57, 0), (0x01A4, 55, 58, 0), (0x0160, 56, 59, 0), (0x0125, 57, 60, 0),
+    (0x00F6, 58, 61, 0), (0x00CB, 59, 62, 0), (0x00AB, 61, 63, 0), (0x008F, 61, 32, 0),
+    (0x5B12, 65, 65, 1), (0x4D04, 80, 66, 0), (0x412C, 81, 67, 0), (0x37D8, 82, 68, 0),
+    (0x2FE8, 83, 69, 0), (0x293C, 84, 70, 0), (0x2379, 86, 71, 0), (0x1EDF, 87, 72, 0),
+    (0x1AA9, 87, 73, 0), (0x174E, 72, 74, 0), (0x1424, 72, 75, 0), (0x119C, 74, 76, 0),
+    (0x0F6B, 74, 77, 0), (0x0D51, 75, 78, 0), (0x0BB6, 77, 79, 0), (0x0A40, 77, 48, 0),
+    (0x5832, 80, 81, 1), (0x4D1C, 88, 82, 0), (0x438E, 89, 83, 0), (0x3BDD, 90, 84, 0),
+    (0x34EE, 91, 85, 0), (0x2EAE, 92, 86, 0), (0x299A, 93, 87, 0), (0x2516, 86, 71, 0),
+    (0x5570, 88, 89, 1), (0x4CA9, 95, 90, 0), (0x44D9, 96, 91, 0), (0x3E22, 97, 92, 0),
+    (0x3824, 99, 93, 0), (0x32B4, 99, 94, 0), (0x2E17, 93, 86, 0), (0x56A8, 95, 96, 1),
+    (0x4F46, 101, 97, 0), (0x47E5, 102, 98, 0), (0x41CF, 103, 99, 0), (0x3C3D, 104, 100, 0),
+    (0x375E, 99, 93, 0), (0x5231, 105, 102, 0), (0x4C0F, 106, 103, 0), (0x4639, 107, 104, 0),
+    (0x415E, 103, 99, 0), (0x5627, 105, 106, 1), (0x50E7, 108, 107, 0), (0x4B85, 109, 103, 0),
+    (0x5597, 110, 109, 0), (0x504F, 111, 107, 0), (0x5A10, 110, 111, 1), (0x5522, 112, 109, 0),
+    (0x59EB, 112, 111, 1), (0x5A1D, 113, 113, 0),
+];
+
+/// The state of the decisions coded with a fixed probability estimate
+const FIXED_STATE: u8 = 113;
+
+/// Statistics bins needed by each kind of table, 10918-1, F.1.4.4.1.3 & F.1.4.4.2.1, P. 97-98
+pub(crate) const DC_STATISTICS: usize = 64;
+pub(crate) const AC_STATISTICS: usize = 256;
+pub(crate) const LOSSLESS_STATISTICS: usize = 158;
+
+/// The defaults for conditioning tables not given by a DAC segment, 10918-1, F.1.4.4.1.4, P. 98
+/// and F.1.4.4.2.1, P. 99; L = 0 and U = 1 for DC and lossless tables, Kx = 5 for AC tables.
+pub(crate) const DEFAULT_DC_CONDITIONING: u8 = 0x10;
+pub(crate) const DEFAULT_AC_CONDITIONING: u8 = 5;
+
+/// The QM-coder's decoder, 10918-1, D.2, P. 60, reading the coded bytes of a scan as it needs them.
+/// Once an interval's bytes run out it's fed zeros, as it would be after hitting a marker.
+/// A statistics bin is a single byte, holding the index of its state in the low 7 bits and the MPS in the top one.
+pub(crate) struct ArithmeticDecoder<'a> {
+    reader: ScanReader<'a>,
+    c: u32,  // Code register
+    a: u32,  // Probability interval
+    ct: i32, // Bits left in the code register before another byte is needed; negative while initialising
+}
+
+impl<'a> ArithmeticDecoder<'a> {
+    /// Initialisation is folded into the first renormalisation, as done by libjpeg, D.2.7, P. 66
+    pub(crate) fn new(reader: ScanReader<'a>) -> Self {
+        Self {
+            reader,
+            c: 0,
+            a: 0,
+            ct: -16,
+        }
+    }
+
+    /// Moves on to the next restart interval, where decoding is initialised again, F.1.4.1, P. 96
+    pub(crate) fn restart(&mut self) {
+        self.reader.next_interval();
+        self.c = 0;
+        self.a = 0;
+        self.ct = -16;
+    }
+
+    /// The reader, for skipping the rest of the scan
+    pub(crate) fn into_reader(self) -> ScanReader<'a> {
+        self.reader
+    }
+
+    /// Decodes a binary decision with the statistics bin `state`, updating its estimate, D.2.4 & D.2.5, P. 60-63
+    pub(crate) fn decode(&mut self, state: &mut u8) -> u8 {
+        // Renorm_D and Byte_in, D.2.6, P. 64
+        while self.a < 0x8000 {
+            self.ct -= 1;
+            if self.ct < 0 {
+                let byte = self.reader.next_byte().unwrap_or(0);
+                self.c = (self.c << 8) | byte as u32;
+                self.ct += 8;
+                if self.ct < 0 {
+                    self.ct += 1;
+                    if self.ct == 0 {
+                        // the two initial bytes are in, so A becomes 0x10000 once shifted below
+                        self.a = 0x8000;
+                    }
+                }
+            }
+            self.a <<= 1;
+        }
+
+        let mps = *state & 0x80;
+        let (q_e, next_lps, next_mps, switch_mps) = STATES[(*state & 0x7F) as usize];
+        let after_lps = mps ^ (switch_mps << 7) ^ next_lps;
+        let after_mps = mps ^ next_mps;
+
+        self.a -= q_e;
+        let shifted_a = self.a << self.ct;
+        let mut decision = mps >> 7;
+        if self.c >= shifted_a {
+            // the LPS sub-interval, unless it's been conditionally exchanged
+            self.c -= shifted_a;
+            if self.a < q_e {
+                *state = after_mps;
+            } else {
+                *state = after_lps;
+                decision ^= 1;
+            }
+            self.a = q_e;
+        } else if self.a < 0x8000 {
+            if self.a < q_e {
+                *state = after_lps;
+                decision ^= 1;
+            } else {
+                *state = after_mps;
+            }
+        }
+        decision
+    }
+}
+
+/// The QM-coder's encoder, 10918-1, D.1, P. 51, writing the coded bytes of a scan with their 0xFF bytes stuffed,
+/// as libjpeg's jcarith.c does. Bytes are held back while a carry could still reach them: the last byte below 0xFF,
+/// `buffer`, the 0xFF bytes stacked after it, and any zeros before it, which are dropped from the end of an interval.
+pub(crate) struct ArithmeticEncoder {
+    bytes: Vec<u8>,
+    c: u32,               // Code register
+    a: u32,               // Probability interval
+    ct: i32,              // Bits shifted into the code register before the next byte is out
+    buffer: Option<u8>,   // The last byte out below 0xFF, which a carry still reaches
+    stacked_ffs: usize,   // 0xFF bytes out after the buffered one
+    pending_zeros: usize, // 0x00 bytes out before the buffered one
+}
+
+impl ArithmeticEncoder {
+    /// Initialisation, Initenc, D.1.7, P. 56
+    pub(crate) fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            c: 0,
+            a: 0x10000,
+            ct: 11,
+            buffer: None,
+            stacked_ffs: 0,
+            pending_zeros: 0,
+        }
+    }
+
+    /// Encodes a binary decision with the statistics bin `state`, updating its estimate, D.1.4 & D.1.5, P. 51-54
+    pub(crate) fn encode(&mut self, state: &mut u8, decision: u8) {
+        let mps = *state & 0x80;
+        let (q_e, next_lps, next_mps, switch_mps) = STATES[(*state & 0x7F) as usize];
+
+        self.a -= q_e;
+        if decision != mps >> 7 {
+            // Code_LPS, where the sub-intervals are conditionally exchanged if the LPS's is the larger
+            if self.a >= q_e {
+                self.c += self.a;
+                self.a = q_e;
+            }
+            *state = mps ^ (switch_mps << 7) ^ next_lps;
+        } else {
+            // Code_MPS
+            if self.a >= 0x8000 {
+                return;
+            }
+            if self.a < q_e {
+                self.c += self.a;
+                self.a = q_e;
+            }
+            *state = mps ^ next_mps;
+        }
+
+        // Renorm_E, D.1.6, P. 56
+        while self.a < 0x8000 {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                self.byte_out();
+                self.c &= 0x7FFFF;
+                self.ct = 8;
+            }
+        }
+    }
+
+    /// Ends the restart interval and writes RSTm, then starts the next one, as libjpeg
+    pub(crate) fn write_restart_marker(&mut self, m: u8) {
+        self.flush();
+        self.bytes.extend_from_slice(&(Marker::RST0 as u16 + (m % 8) as u16).to_be_bytes());
+        let bytes = std::mem::take(&mut self.bytes);
+        *self = Self { bytes, ..Self::new() };
+    }
+
+    /// Ends the last interval and returns the coded bytes
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.flush();
+        self.bytes
+    }
+
+    /// Byte_out, D.1.6, P. 56, with the carry propagated over the held back bytes
+    fn byte_out(&mut self) {
+        let byte = self.c >> 19;
+        if byte > 0xFF {
+            self.carry();
+            // the spacer bits of C make sure the new byte can't be 0xFF
+            self.buffer = Some(byte as u8);
+        } else if byte == 0xFF {
+            self.stacked_ffs += 1;
+        } else {
+            self.release();
+            self.buffer = Some(byte as u8);
+        }
+    }
+
+    /// Adds the carry to the buffered byte, which turns the stacked 0xFF bytes into zeros
+    fn carry(&mut self) {
+        if let Some(buffer) = self.buffer {
+            self.write_pending_zeros();
+            self.write_byte(buffer + 1);
+        }
+        self.pending_zeros += self.stacked_ffs;
+        self.stacked_ffs = 0;
+    }
+
+    /// Writes the buffered byte and the stacked 0xFF bytes, which no carry can reach any more,
+    /// holding back zeros in case nothing else follows them
+    fn release(&mut self) {
+        match self.buffer {
+            Some(0) => self.pending_zeros += 1,
+            Some(buffer) => {
+                self.write_pending_zeros();
+                self.write_byte(buffer);
+            },
+            None => {},
+        }
+        if self.stacked_ffs > 0 {
+            self.write_pending_zeros();
+            for _ in 0..self.stacked_ffs {
+                self.write_byte(0xFF);
+            }
+            self.stacked_ffs = 0;
+        }
+    }
+
+    /// Flush, D.1.8, P. 57, picking the value in the final interval with the most trailing zeros,
+    /// so that as few bytes as possible need to be written, and leaving out any zeros at the end
+    fn flush(&mut self) {
+        let temp = (self.a - 1 + self.c) & 0xFFFF0000;
+        self.c = if temp < self.c { temp + 0x8000 } else { temp };
+        self.c <<= self.ct;
+        if self.c & 0xF8000000 != 0 {
+            self.carry();
+        } else {
+            self.release();
+        }
+        if self.c & 0x7FFF800 != 0 {
+            self.write_pending_zeros();
+            self.write_byte((self.c >> 19) as u8);
+            if self.c & 0x7F800 != 0 {
+                self.write_byte((self.c >> 11) as u8);
+            }
+        }
+    }
+
+    fn write_pending_zeros(&mut self) {
+        self.bytes.resize(self.bytes.len() + self.pending_zeros, 0);
+        self.pending_zeros = 0;
+    }
+
+    /// Writes a byte of coded data, stuffing a zero after 0xFF, B.1.1.5, P. 32
+    fn write_byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+        if byte == 0xFF {
+            self.bytes.push(0x00);
+        }
+    }
+}
+
+/// The conditioning category of a difference, F.1.4.4.1.2, P. 97: 0 for zero, 1 and 2 for small positive and negative,
+/// 3 and 4 for large positive and negative. `conditioning` is a DAC Cs value, holding U over L.
+fn conditioning_category(difference: i32, conditioning: u8) -> usize {
+    if difference == 0 {
+        return 0;
+    }
+    let l = conditioning & 0xF;
+    let u = conditioning >> 4;
+    // the magnitude category decoding works on |difference| - 1, and tops out at its highest bit
+    let magnitude = difference.unsigned_abs() - 1;
+    let m = if magnitude == 0 { 0 } else { 1 << (31 - magnitude.leading_zeros()) };
+    let sign = (difference < 0) as usize;
+    if m < (1u32 << l) >> 1 {
+        0
+    } else if m > (1u32 << u) >> 1 {
+        3 + sign
+    } else {
+        1 + sign
+    }
+}
+
+/// Decodes a DC or lossless difference, Figures F.19 & F.21-F.24, P. 93-95.
+/// `s0` is the context's first bin, with SS, SP and SN following it, and `x1` the first magnitude category bin,
+/// with the magnitude bits' bins 14 further along.
+fn decode_difference(decoder: &mut ArithmeticDecoder, statistics: &mut [u8], s0: usize, x1: usize) -> i32 {
+    if decoder.decode(&mut statistics[s0]) == 0 {
+        return 0;
+    }
+    let sign = decoder.decode(&mut statistics[s0 + 1]);
+    let mut bin = s0 + 2 + sign as usize;
+    let mut m: i32 = decoder.decode(&mut statistics[bin]) as i32;
+    if m != 0 {
+        bin = x1;
+        while decoder.decode(&mut statistics[bin]) != 0 {
+            m <<= 1;
+            assert!(m < 0x10000, "Arithmetic coded magnitude category out of range");
+            bin += 1;
+        }
+    }
+    decode_magnitude_bits(decoder, statistics, bin + 14, m, sign)
+}
+
+/// Decodes the bits below the top one of |v| - 1, Figure F.24, P. 95, and applies the sign
+fn decode_magnitude_bits(decoder: &mut ArithmeticDecoder, statistics: &mut [u8], bin: usize, m: i32, sign: u8) -> i32 {
+    let mut v = m;
+    let mut bit = m >> 1;
+    while bit != 0 {
+        if decoder.decode(&mut statistics[bin]) != 0 {
+            v |= bit;
+        }
+        bit >>= 1;
+    }
+    v += 1;
+    if sign == 1 {
+        -v
+    } else {
+        v
+    }
+}
+
+/// Decodes a DC difference, F.1.4.4.1, P. 93. `context` is the component's
+/// conditioning category, from its previous difference, and gets updated.
+pub(crate) fn decode_dc_difference(
+    decoder: &mut ArithmeticDecoder,
+    statistics: &mut [u8; DC_STATISTICS],
+    context: &mut usize,
+    conditioning: u8,
+) -> i32 {
+    // Table F.4, P. 97: S0 is at 4 times the category, X1 at 20
+    let difference = decode_difference(decoder, statistics, 4 * *context, 20);
+    *context = conditioning_category(difference, conditioning);
+    difference
+}
+
+/// Decodes a lossless difference, H.1.4.3, P. 135, with a two dimensional context made of the conditioning
+/// categories of the differences coded for the samples to the left, Da, and above, Db.
+/// The 25 contexts take 4 bins each, and the magnitudes are conditioned on whether Db is large or not.
+pub(crate) fn decode_lossless_difference(
+    decoder: &mut ArithmeticDecoder,
+    statistics: &mut [u8; LOSSLESS_STATISTICS],
+    d_a: i32,
+    d_b: i32,
+    conditioning: u8,
+) -> i32 {
+    let category_b = conditioning_category(d_b, conditioning);
+    let s0 = 4 * (5 * conditioning_category(d_a, conditioning) + category_b);
+    let x1 = if category_b > 2 { 129 } else { 100 };
+    decode_difference(decoder, statistics, s0, x1)
+}
+
+/// Decodes the AC coefficients `s_s` to `s_e` of a block, the whole band in sequential scans,
+/// F.1.4.4.2, P. 98, or the first scan of a band in progressive ones, G.1.3.2, P. 124.
+/// `k_x` is the band's conditioning, from a DAC segment.
+pub(crate) fn decode_ac_coefficients(
+    decoder: &mut ArithmeticDecoder,
+    statistics: &mut [u8; AC_STATISTICS],
+    (s_s, s_e): (usize, usize),
+    k_x: u8,
+    a_l: u8,
+    block: &mut [i32; 64],
+) {
+    let mut fixed = FIXED_STATE;
+    let mut k = s_s;
+    while k <= s_e {
+        // Table F.5, P. 98: SE, S0 and SS of each index follow each other
+        let mut se = 3 * (k - 1);
+        if decoder.decode(&mut statistics[se]) != 0 {
+            // EOB
+            break;
+        }
+        while decoder.decode(&mut statistics[se + 1]) == 0 {
+            se += 3;
+            k += 1;
+            assert!(k <= s_e, "Arithmetic coded coefficients run past the end of the band");
+        }
+        // the sign is coded with a fixed probability
+        let sign = decoder.decode(&mut fixed);
+        let mut bin = se + 2;
+        let mut m: i32 = decoder.decode(&mut statistics[bin]) as i32;
+        if m != 0 && decoder.decode(&mut statistics[bin]) != 0 {
+            m <<= 1;
+            bin = if k <= k_x as usize { 189 } else { 217 };
+            while decoder.decode(&mut statistics[bin]) != 0 {
+                m <<= 1;
+                assert!(m < 0x10000, "Arithmetic coded magnitude category out of range");
+                bin += 1;
+            }
+        }
+        let v = decode_magnitude_bits(decoder, statistics, bin + 14, m, sign);
+        block[dct::ZIGZAG[k]] = v << a_l;
+        k += 1;
+    }
+}
+
+/// Decodes the next bit of a DC coefficient, G.1.3.1, P. 124
+pub(crate) fn decode_dc_refine(decoder: &mut ArithmeticDecoder, a_l: u8, block: &mut [i32; 64]) {
+    let mut fixed = FIXED_STATE;
+    if decoder.decode(&mut fixed) != 0 {
+        block[0] |= 1 << a_l;
+    }
+}
+
+/// Decodes the next bit of the AC coefficients `s_s` to `s_e` of a block, G.1.3.3, P. 125.
+/// Coefficients that were already nonzero get a correction bit, and the others might become +-1 at bit `a_l`.
+pub(crate) fn decode_ac_refine(
+    decoder: &mut ArithmeticDecoder,
+    statistics: &mut [u8; AC_STATISTICS],
+    (s_s, s_e): (usize, usize),
+    a_l: u8,
+    block: &mut [i32; 64],
+) {
+    let mut fixed = FIXED_STATE;
+    let p1: i32 = 1 << a_l;
+    let m1: i32 = -1 << a_l;
+
+    // EOBx, where the previous stages ended
+    let mut eob_x = s_e;
+    while eob_x > 0 && block[dct::ZIGZAG[eob_x]] == 0 {
+        eob_x -= 1;
+    }
+
+    let mut k = s_s;
+    while k <= s_e {
+        let mut se = 3 * (k - 1);
+        if k > eob_x && decoder.decode(&mut statistics[se]) != 0 {
+            // EOB
+            break;
+        }
+        loop {
+            let coefficient = &mut block[dct::ZIGZAG[k]];
+            if *coefficient != 0 {
+                if decoder.decode(&mut statistics[se + 2]) != 0 {
+                    *coefficient += if *coefficient < 0 { m1 } else { p1 };
+                }
+                break;
+            }
+            if decoder.decode(&mut statistics[se + 1]) != 0 {
+                *coefficient = if decoder.decode(&mut fixed) != 0 { m1 } else { p1 };
+                break;
+            }
+            se += 3;
+            k += 1;
+            assert!(k <= s_e, "Arithmetic coded coefficients run past the end of the band");
+        }
+        k += 1;
+    }
+}
+
+/// Encodes a DC or lossless difference, Figures F.4 & F.6-F.9, P. 84-86, with the bins laid out as for decoding
+fn encode_difference(encoder: &mut ArithmeticEncoder, statistics: &mut [u8], s0: usize, x1: usize, difference: i32) {
+    if difference == 0 {
+        encoder.encode(&mut statistics[s0], 0);
+        return;
+    }
+    encoder.encode(&mut statistics[s0], 1);
+    let sign = (difference < 0) as u8;
+    encoder.encode(&mut statistics[s0 + 1], sign);
+    let mut bin = s0 + 2 + sign as usize;
+    let v = difference.unsigned_abs() - 1;
+    let mut m: u32 = 0;
+    if v != 0 {
+        encoder.encode(&mut statistics[bin], 1);
+        m = 1;
+        bin = x1;
+        let mut rest = v >> 1;
+        while rest != 0 {
+            encoder.encode(&mut statistics[bin], 1);
+            m <<= 1;
+            bin += 1;
+            rest >>= 1;
+        }
+    }
+    encoder.encode(&mut statistics[bin], 0);
+    encode_magnitude_bits(encoder, statistics, bin + 14, m, v);
+}
+
+/// Encodes the bits of `v`, |v| - 1 of a value, below its top one, `m`, Figure F.9, P. 86
+fn encode_magnitude_bits(encoder: &mut ArithmeticEncoder, statistics: &mut [u8], bin: usize, m: u32, v: u32) {
+    let mut bit = m >> 1;
+    while bit != 0 {
+        encoder.encode(&mut statistics[bin], (v & bit != 0) as u8);
+        bit >>= 1;
+    }
+}
+
+/// Encodes a DC difference, F.1.4.1, P. 82, updating the component's conditioning category
+pub(crate) fn encode_dc_difference(
+    encoder: &mut ArithmeticEncoder,
+    statistics: &mut [u8; DC_STATISTICS],
+    context: &mut usize,
+    conditioning: u8,
+    difference: i32,
+) {
+    encode_difference(encoder, statistics, 4 * *context, 20, difference);
+    *context = conditioning_category(difference, conditioning);
+}
+
+/// Encodes a lossless difference, H.1.4.3, P. 135, in the context of the differences `d_a` and `d_b`
+pub(crate) fn encode_lossless_difference(
+    encoder: &mut ArithmeticEncoder,
+    statistics: &mut [u8; LOSSLESS_STATISTICS],
+    d_a: i32,
+    d_b: i32,
+    conditioning: u8,
+    difference: i32,
+) {
+    let category_b = conditioning_category(d_b, conditioning);
+    let s0 = 4 * (5 * conditioning_category(d_a, conditioning) + category_b);
+    let x1 = if category_b > 2 { 129 } else { 100 };
+    encode_difference(encoder, statistics, s0, x1, difference);
+}
+
+/// Encodes the AC coefficients of a block of a sequential scan, F.1.4.2, P. 82, with an EOB after the last nonzero one
+pub(crate) fn encode_ac_coefficients(
+    encoder: &mut ArithmeticEncoder,
+    statistics: &mut [u8; AC_STATISTICS],
+    k_x: u8,
+    block: &[i32; 64],
+) {
+    let mut fixed = FIXED_STATE;
+    let end_of_block = (1..64).rev().find(|k| block[dct::ZIGZAG[*k]] != 0).unwrap_or(0);
+
+    let mut k = 1;
+    while k <= end_of_block {
+        let mut se = 3 * (k - 1);
+        encoder.encode(&mut statistics[se], 0);
+        while block[dct::ZIGZAG[k]] == 0 {
+            encoder.encode(&mut statistics[se + 1], 0);
+            se += 3;
+            k += 1;
+        }
+        encoder.encode(&mut statistics[se + 1], 1);
+        let coefficient = block[dct::ZIGZAG[k]];
+        encoder.encode(&mut fixed, (coefficient < 0) as u8);
+
+        let mut bin = se + 2;
+        let v = coefficient.unsigned_abs() - 1;
+        let mut m: u32 = 0;
+        if v != 0 {
+            encoder.encode(&mut statistics[bin], 1);
+            m = 1;
+            let mut rest = v >> 1;
+            if rest != 0 {
+                encoder.encode(&mut statistics[bin], 1);
+                m <<= 1;
+                bin = if k <= k_x as usize { 189 } else { 217 };
+                rest >>= 1;
+                while rest != 0 {
+                    encoder.encode(&mut statistics[bin], 1);
+                    m <<= 1;
+                    bin += 1;
+                    rest >>= 1;
+                }
+            }
+        }
+        encoder.encode(&mut statistics[bin], 0);
+        encode_magnitude_bits(encoder, statistics, bin + 14, m, v);
+        k += 1;
+    }
+    if k < 64 {
+        encoder.encode(&mut statistics[3 * (k - 1)], 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn conditioning_categories() {
+        // L = 0, U = 1
+        assert_eq!(conditioning_category(0, 0x10), 0);
+        assert_eq!(conditioning_category(1, 0x10), 1);
+        assert_eq!(conditioning_category(-2, 0x10), 2);
+        assert_eq!(conditioning_category(3, 0x10), 3);
+        assert_eq!(conditioning_category(-9, 0x10), 4);
+        // L = 2, U = 3; small differences count as zero
+        assert_eq!(conditioning_category(2, 0x32), 0);
+        assert_eq!(conditioning_category(-3, 0x32), 2);
+        assert_eq!(conditioning_category(5, 0x32), 1);
+        assert_eq!(conditioning_category(8, 0x32), 1);
+        assert_eq!(conditioning_category(9, 0x32), 3);
+    }
+
+    #[test]
+    fn fixed_state_never_adapts() {
+        let mut decoder = ArithmeticDecoder::new(ScanReader::new(&[0x12, 0xFE, 0x00, 0xA5, 0x3C], 0));
+        let mut fixed = FIXED_STATE;
+        for _ in 0..40 {
+            decoder.decode(&mut fixed);
+            assert_eq!(fixed, FIXED_STATE);
+        }
+    }
+
+    #[test]
+    fn encoded_differences_decode() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let differences: Vec<i32> = (0..2000)
+            .map(|i| match i % 3 {
+                0 => 0,
+                1 => rng.gen_range(-40..40),
+                _ => rng.gen_range(-32767..=32768),
+            })
+            .collect();
+
+        let mut encoder = ArithmeticEncoder::new();
+        let mut statistics = [0; LOSSLESS_STATISTICS];
+        let mut dc_statistics = [0; DC_STATISTICS];
+        let mut context = 0;
+        for (i, difference) in differences.iter().enumerate() {
+            let d_a = if i > 0 { differences[i - 1] } else { 0 };
+            encode_lossless_difference(&mut encoder, &mut statistics, d_a, 7, 0x31, *difference);
+            encode_dc_difference(&mut encoder, &mut dc_statistics, &mut context, 0x10, *difference);
+        }
+
+        let bytes = encoder.finish();
+        let mut decoder = ArithmeticDecoder::new(ScanReader::new(&bytes, 0));
+        let mut statistics = [0; LOSSLESS_STATISTICS];
+        let mut dc_statistics = [0; DC_STATISTICS];
+        let mut context = 0;
+        for (i, difference) in differences.iter().enumerate() {
+            let d_a = if i > 0 { differences[i - 1] } else { 0 };
+            assert_eq!(decode_lossless_difference(&mut decoder, &mut statistics, d_a, 7, 0x31), *difference);
+            assert_eq!(decode_dc_difference(&mut decoder, &mut dc_statistics, &mut context, 0x10), *difference);
+        }
+    }
+
+    #[test]
+    fn encoded_blocks_decode() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let blocks: Vec<[i32; 64]> = (0..200)
+            .map(|_| {
+                // mostly zeros, and sometimes none past the DC or all the way to the last coefficient
+                let end = rng.gen_range(0..64);
+                core::array::from_fn(|k| if k <= end && rng.gen_bool(0.3) { rng.gen_range(-1500..1500) } else { 0 })
+            })
+            .collect();
+
+        let mut encoder = ArithmeticEncoder::new();
+        let mut statistics = [0; AC_STATISTICS];
+        for block in &blocks {
+            encode_ac_coefficients(&mut encoder, &mut statistics, 12, block);
+        }
+
+        let bytes = encoder.finish();
+        let mut decoder = ArithmeticDecoder::new(ScanReader::new(&bytes, 0));
+        let mut statistics = [0; AC_STATISTICS];
+        for block in &blocks {
+            let mut decoded = [0; 64];
+            decoded[0] = block[0];
+            decode_ac_coefficients(&mut decoder, &mut statistics, (1, 63), 12, 0, &mut decoded);
+            assert_eq!(decoded, *block);
+        }
+    }
+
+    #[test]
+    fn first_decision_of_zeros_is_the_mps() {
+        // A is 0x10000 - Qe after the first subtraction, so no renormalisation or estimate update is needed
+        let mut decoder = ArithmeticDecoder::new(ScanReader::new(&[], 0));
+        let mut state: u8 = 0;
+        assert_eq!(decoder.decode(&mut state), 0);
+        assert_eq!(state, 0);
+    }
+}
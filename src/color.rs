@@ -0,0 +1,80 @@
+use crate::ColorSpace;
+
+/// The precision of libjpeg's fixed point color conversion
+const SCALE_BITS: u32 = 16;
+const ONE_HALF: i64 = 1 << (SCALE_BITS - 1);
+
+/// `x` as a fixed point number, as libjpeg's FIX macro
+fn fix(x: f64) -> i64 {
+    (x * (1 << SCALE_BITS) as f64 + 0.5) as i64
+}
+
+/// YCbCr to RGB, ITU T.871, 7, P. 4, done with the same fixed point arithmetic as libjpeg (jdcolor.c)
+/// so the results match it exactly. Cb and Cr are centred on half the sample range.
+fn ycbcr_to_rgb(y: i64, cb: i64, cr: i64, p_: u8) -> [u32; 3] {
+    let max = (1i64 << p_) - 1;
+    let center = 1i64 << (p_ - 1);
+    let (cb, cr) = (cb - center, cr - center);
+
+    let r = y + ((fix(1.40200) * cr + ONE_HALF) >> SCALE_BITS);
+    let g = y + ((-fix(0.34414) * cb + ONE_HALF - fix(0.71414) * cr) >> SCALE_BITS);
+    let b = y + ((fix(1.77200) * cb + ONE_HALF) >> SCALE_BITS);
+    [r, g, b].map(|sample| sample.clamp(0, max) as u32)
+}
+
+/// RGB to YCbCr, ITU T.871, 7, P. 4, done with the same fixed point arithmetic as libjpeg (jccolor.c)
+/// so the results match it exactly. Cb and Cr are centred on half the sample range.
+pub(crate) fn rgb_to_ycbcr(r: i64, g: i64, b: i64, p_: u8) -> [i32; 3] {
+    let cbcr_offset = (1i64 << (p_ - 1)) << SCALE_BITS;
+
+    let y = fix(0.29900) * r + fix(0.58700) * g + fix(0.11400) * b + ONE_HALF;
+    let cb = -fix(0.16874) * r - fix(0.33126) * g + fix(0.5) * b + cbcr_offset + ONE_HALF - 1;
+    let cr = fix(0.5) * r - fix(0.41869) * g - fix(0.08131) * b + cbcr_offset + ONE_HALF - 1;
+    [y, cb, cr].map(|sample| (sample >> SCALE_BITS) as i32)
+}
+
+/// Converts a line of interleaved samples, in the color space they were coded in, to what gets output:
+/// YCbCr becomes RGB, and YCCK and Adobe's inverted CMYK become CMYK, where 0 is no ink.
+/// Anything else is left as it is.
+pub(crate) fn convert(line: &mut [u32], color_space: ColorSpace, p_: u8) {
+    let max = (1u32 << p_) - 1;
+    match color_space {
+        ColorSpace::YCbCr => {
+            for pixel in line.chunks_exact_mut(3) {
+                let rgb = ycbcr_to_rgb(pixel[0] as i64, pixel[1] as i64, pixel[2] as i64, p_);
+                pixel.copy_from_slice(&rgb);
+            }
+        },
+        // YCCK is the YCbCr of Adobe's inverted C, M and Y, inverted once more, with its inverted K kept as it is
+        ColorSpace::Ycck => {
+            for pixel in line.chunks_exact_mut(4) {
+                let rgb = ycbcr_to_rgb(pixel[0] as i64, pixel[1] as i64, pixel[2] as i64, p_);
+                pixel[..3].copy_from_slice(&rgb);
+                pixel[3] = max - pixel[3];
+            }
+        },
+        ColorSpace::InvertedCmyk => line.iter_mut().for_each(|sample| *sample = max - *sample),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_ycbcr() {
+        let mut line: Vec<u32> = Vec::from([128, 128, 128, 0, 128, 128, 255, 128, 128, 76, 85, 255]);
+        convert(&mut line, ColorSpace::YCbCr, 8);
+
+        assert_eq!(line, [128, 128, 128, 0, 0, 0, 255, 255, 255, 254, 0, 0]);
+    }
+
+    #[test]
+    fn rgb_to_ycbcr_extremes() {
+        assert_eq!(rgb_to_ycbcr(0, 0, 0, 8), [0, 128, 128]);
+        assert_eq!(rgb_to_ycbcr(255, 255, 255, 8), [255, 128, 128]);
+        assert_eq!(rgb_to_ycbcr(255, 0, 0, 8), [76, 85, 255]);
+        assert_eq!(rgb_to_ycbcr(0, 0, 255, 8), [29, 255, 107]);
+    }
+}
@@ -0,0 +1,976 @@
+use crate::jpeg_utils;
+use crate::{Component, FrameHeader, SSSSTable};
+
+/// The natural (row major) index of each coefficient, given in zig-zag order, 10918-1, Figure A.6, P. 30
+pub(crate) const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+    20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
+    59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Where a component's blocks sit, 10918-1, A.2, P. 24
+pub(crate) struct ComponentGeometry {
+    pub(crate) blocks_wide: usize,        // Blocks needed to cover a line, as used by non-interleaved scans
+    pub(crate) blocks_high: usize,        // Blocks needed to cover the lines, as used by non-interleaved scans
+    pub(crate) padded_blocks_wide: usize, // Blocks per line when padded out to whole MCUs
+    pub(crate) padded_blocks_high: usize, // Block rows when padded out to whole MCUs
+}
+
+impl FrameHeader {
+    /// The largest horizontal and vertical sampling factors, H_max and V_max
+    pub(crate) fn max_sampling_factors(&self) -> (usize, usize) {
+        let h_max = self.components.values().map(|c| c.h_).max().unwrap_or(1) as usize;
+        let v_max = self.components.values().map(|c| c.v_).max().unwrap_or(1) as usize;
+        (h_max, v_max)
+    }
+
+    /// The number of MCUs across and down an interleaved scan
+    pub(crate) fn mcus(&self) -> (usize, usize) {
+        let (h_max, v_max) = self.max_sampling_factors();
+        (
+            (self.x_ as usize).div_ceil(8 * h_max),
+            (self.y_ as usize).div_ceil(8 * v_max),
+        )
+    }
+
+    pub(crate) fn component_geometry(&self, component: &Component) -> ComponentGeometry {
+        let (h_max, v_max) = self.max_sampling_factors();
+        let (mcus_wide, mcus_high) = self.mcus();
+        let width = (self.x_ as usize * component.h_ as usize).div_ceil(h_max);
+        let height = (self.y_ as usize * component.v_ as usize).div_ceil(v_max);
+        ComponentGeometry {
+            blocks_wide: width.div_ceil(8),
+            blocks_high: height.div_ceil(8),
+            padded_blocks_wide: mcus_wide * component.h_ as usize,
+            padded_blocks_high: mcus_high * component.v_ as usize,
+        }
+    }
+}
+
+/// Decodes the Huffman coded coefficients of one block in a sequential scan into `block`,
+/// in natural order. `prediction` is the previous DC value of the component,
+/// and gets updated. 10918-1, F.2.2, P. 104
+pub(crate) fn decode_block(
+    dc_table: &SSSSTable,
+    ac_table: &SSSSTable,
+    image_bits: &mut impl Iterator<Item = u8>,
+    prediction: &mut i32,
+    block: &mut [i32; 64],
+) {
+    // F.2.2.1, DC coefficient
+    let t = jpeg_utils::get_huffmaned_symbol(dc_table, image_bits);
+    *prediction += jpeg_utils::receive_extend(t, image_bits);
+    block[0] = *prediction;
+
+    // F.2.2.2, AC coefficients
+    let mut k: usize = 1;
+    while k < 64 {
+        let rs = jpeg_utils::get_huffmaned_symbol(ac_table, image_bits);
+        let r = (rs >> 4) as usize;
+        let s = rs & 0xF;
+        if s == 0 {
+            if r == 15 {
+                // ZRL, a run of 16 zeros
+                k += 16;
+                continue;
+            }
+            // EOB, the rest are all zero
+            break;
+        }
+        k += r;
+        assert!(k <= 63, "A run of zero coefficients went past the end of the block");
+        block[ZIGZAG[k]] = jpeg_utils::receive_extend(s, image_bits);
+        k += 1;
+    }
+}
+
+/// Decodes the first scan of the DC coefficients of a block in a progressive frame,
+/// `a_l` being the successive approximation bit position, 10918-1, G.1.2.1, P. 122
+pub(crate) fn decode_dc_first(
+    dc_table: &SSSSTable,
+    image_bits: &mut impl Iterator<Item = u8>,
+    prediction: &mut i32,
+    a_l: u8,
+    block: &mut [i32; 64],
+) {
+    let t = jpeg_utils::get_huffmaned_symbol(dc_table, image_bits);
+    *prediction += jpeg_utils::receive_extend(t, image_bits);
+    block[0] = *prediction << a_l;
+}
+
+/// Decodes a refining scan of the DC coefficients of a block, which sends one more bit of each,
+/// 10918-1, G.1.2.1, P. 122
+pub(crate) fn decode_dc_refine(image_bits: &mut impl Iterator<Item = u8>, a_l: u8, block: &mut [i32; 64]) {
+    if image_bits.next().unwrap() == 1 {
+        block[0] |= 1 << a_l;
+    }
+}
+
+/// Reads the length of an end of band run once its EOBn symbol has been decoded, G.1.2.2, P. 123
+fn receive_eob_run(r: u8, image_bits: &mut impl Iterator<Item = u8>) -> u32 {
+    (1 << r) + jpeg_utils::receive(r, image_bits) as u32
+}
+
+/// Decodes the first scan of the band of AC coefficients `spectral_selection`, (Ss, Se),
+/// of a block in a progressive frame. A block inside an end of band run has nothing coded for it.
+/// 10918-1, G.1.2.2, P. 123
+pub(crate) fn decode_ac_first(
+    ac_table: &SSSSTable,
+    image_bits: &mut impl Iterator<Item = u8>,
+    spectral_selection: (usize, usize),
+    a_l: u8,
+    eob_run: &mut u32,
+    block: &mut [i32; 64],
+) {
+    if *eob_run > 0 {
+        *eob_run -= 1;
+        return;
+    }
+
+    let (s_s, s_e) = spectral_selection;
+    let mut k = s_s;
+    while k <= s_e {
+        let rs = jpeg_utils::get_huffmaned_symbol(ac_table, image_bits);
+        let r = rs >> 4;
+        let s = rs & 0xF;
+        if s == 0 {
+            if r == 15 {
+                k += 16;
+                continue;
+            }
+            // EOBn, this block and the next EOBRUN - 1 blocks are done
+            *eob_run = receive_eob_run(r, image_bits) - 1;
+            break;
+        }
+        k += r as usize;
+        assert!(k <= s_e, "A run of zero coefficients went past the end of the spectral band");
+        block[ZIGZAG[k]] = jpeg_utils::receive_extend(s, image_bits) << a_l;
+        k += 1;
+    }
+}
+
+/// Adds a correction bit to a coefficient that's already nonzero,
+/// increasing its magnitude when the bit's set, G.1.2.3, P. 124
+fn refine_nonzero(coefficient: &mut i32, image_bits: &mut impl Iterator<Item = u8>, a_l: u8) {
+    if image_bits.next().unwrap() == 1 && *coefficient & (1 << a_l) == 0 {
+        if *coefficient >= 0 {
+            *coefficient += 1 << a_l;
+        } else {
+            *coefficient -= 1 << a_l;
+        }
+    }
+}
+
+/// Decodes a refining scan of the band of AC coefficients `spectral_selection` of a block.
+/// Coefficients that were zero may become +/- 1 << `a_l`,
+/// and each of those already nonzero gets a correction bit, 10918-1, G.1.2.3, P. 124
+pub(crate) fn decode_ac_refine(
+    ac_table: &SSSSTable,
+    image_bits: &mut impl Iterator<Item = u8>,
+    spectral_selection: (usize, usize),
+    a_l: u8,
+    eob_run: &mut u32,
+    block: &mut [i32; 64],
+) {
+    let (s_s, s_e) = spectral_selection;
+    let mut k = s_s;
+
+    if *eob_run == 0 {
+        while k <= s_e {
+            let rs = jpeg_utils::get_huffmaned_symbol(ac_table, image_bits);
+            let mut r = (rs >> 4) as i32;
+            let s = rs & 0xF;
+            let mut new_coefficient: i32 = 0;
+            if s != 0 {
+                // the magnitude of a newly nonzero coefficient is always one, so only its sign is sent
+                new_coefficient = if image_bits.next().unwrap() == 1 {
+                    1 << a_l
+                } else {
+                    -1 << a_l
+                };
+            } else if r != 15 {
+                // EOBn, the rest of this block is handled as part of the run
+                *eob_run = receive_eob_run(r as u8, image_bits);
+                break;
+            }
+
+            // Skip past r coefficients that are still zero, refining the nonzero ones along the way
+            while k <= s_e {
+                let coefficient = &mut block[ZIGZAG[k]];
+                if *coefficient != 0 {
+                    refine_nonzero(coefficient, image_bits, a_l);
+                } else {
+                    r -= 1;
+                    if r < 0 {
+                        break;
+                    }
+                }
+                k += 1;
+            }
+            if new_coefficient != 0 && k <= s_e {
+                block[ZIGZAG[k]] = new_coefficient;
+            }
+            k += 1;
+        }
+    }
+
+    if *eob_run > 0 {
+        // Blocks in an end of band run still get correction bits for their nonzero coefficients
+        while k <= s_e {
+            let coefficient = &mut block[ZIGZAG[k]];
+            if *coefficient != 0 {
+                refine_nonzero(coefficient, image_bits, a_l);
+            }
+            k += 1;
+        }
+        *eob_run -= 1;
+    }
+}
+
+/// Clamps the output of the IDCT to the sample range the same way libjpeg's range limit table does,
+/// including its wrapping around of wildly out of range values.
+fn range_limit(value: i64, p_: u8) -> i32 {
+    let max_sample: i64 = (1 << p_) - 1;
+    let center: i64 = 1 << (p_ - 1);
+    let index = value & (4 * (max_sample + 1) - 1);
+    (if index < center {
+        index + center
+    } else if index < 2 * (max_sample + 1) {
+        max_sample
+    } else if index < 4 * (max_sample + 1) - center {
+        0
+    } else {
+        index - (4 * (max_sample + 1) - center)
+    }) as i32
+}
+
+fn descale(x: i64, n: u8) -> i64 {
+    (x + (1 << (n - 1))) >> n
+}
+
+const CONST_BITS: u8 = 13;
+const FIX_0_298631336: i64 = 2446;
+const FIX_0_390180644: i64 = 3196;
+const FIX_0_541196100: i64 = 4433;
+const FIX_0_765366865: i64 = 6270;
+const FIX_0_899976223: i64 = 7373;
+const FIX_1_175875602: i64 = 9633;
+const FIX_1_501321110: i64 = 12299;
+const FIX_1_847759065: i64 = 15137;
+const FIX_1_961570560: i64 = 16069;
+const FIX_2_053119869: i64 = 16819;
+const FIX_2_562915447: i64 = 20995;
+const FIX_3_072711026: i64 = 25172;
+
+/// The even and odd halves of the 1D IDCT shared by both passes of `idct_islow`,
+/// taking the 8 inputs in natural order and returning the 8 outputs before descaling
+fn idct_islow_1d(input: [i64; 8]) -> [i64; 8] {
+    // Even part
+    let z2 = input[2];
+    let z3 = input[6];
+    let z1 = (z2 + z3) * FIX_0_541196100;
+    let tmp2 = z1 - z3 * FIX_1_847759065;
+    let tmp3 = z1 + z2 * FIX_0_765366865;
+
+    let tmp0 = (input[0] + input[4]) << CONST_BITS;
+    let tmp1 = (input[0] - input[4]) << CONST_BITS;
+
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+
+    // Odd part
+    let mut tmp0 = input[7];
+    let mut tmp1 = input[5];
+    let mut tmp2 = input[3];
+    let mut tmp3 = input[1];
+
+    let mut z1 = tmp0 + tmp3;
+    let mut z2 = tmp1 + tmp2;
+    let mut z3 = tmp0 + tmp2;
+    let mut z4 = tmp1 + tmp3;
+    let z5 = (z3 + z4) * FIX_1_175875602;
+
+    tmp0 *= FIX_0_298631336;
+    tmp1 *= FIX_2_053119869;
+    tmp2 *= FIX_3_072711026;
+    tmp3 *= FIX_1_501321110;
+    z1 *= -FIX_0_899976223;
+    z2 *= -FIX_2_562915447;
+    z3 *= -FIX_1_961570560;
+    z4 *= -FIX_0_390180644;
+
+    z3 += z5;
+    z4 += z5;
+
+    tmp0 += z1 + z3;
+    tmp1 += z2 + z4;
+    tmp2 += z2 + z3;
+    tmp3 += z1 + z4;
+
+    [
+        tmp10 + tmp3,
+        tmp11 + tmp2,
+        tmp12 + tmp1,
+        tmp13 + tmp0,
+        tmp13 - tmp0,
+        tmp12 - tmp1,
+        tmp11 - tmp2,
+        tmp10 - tmp3,
+    ]
+}
+
+/// Dequantizes and inverse transforms one block, in natural order, into level shifted samples.
+/// This is the accurate integer IDCT of libjpeg (jidctint.c), and gives the same results.
+/// 10918-1, A.3.3, P. 27
+pub(crate) fn idct_islow(block: &[i32; 64], quantization: &[u16; 64], p_: u8) -> [i32; 64] {
+    idct_islow_with(block, quantization, p_, range_limit)
+}
+
+/// As `idct_islow` but for the differential frames of hierarchical images, whose output
+/// is neither level shifted nor clamped, since it's a difference from the reference, 10918-1, J.2.3, P. 153
+pub(crate) fn idct_islow_differential(block: &[i32; 64], quantization: &[u16; 64], p_: u8) -> [i32; 64] {
+    idct_islow_with(block, quantization, p_, |value, _| value as i32)
+}
+
+/// The accurate integer IDCT, with `output` turning the descaled results into samples
+fn idct_islow_with(block: &[i32; 64], quantization: &[u16; 64], p_: u8, output: fn(i64, u8) -> i32) -> [i32; 64] {
+    // 12 bit samples, from extended sequential frames, get one less bit of extra precision, as in libjpeg
+    let pass1_bits: u8 = if p_ > 8 { 1 } else { 2 };
+    let mut workspace: [i64; 64] = [0; 64];
+    let mut samples: [i32; 64] = [0; 64];
+
+    // Pass 1: process the columns, storing the results in the workspace
+    for column in 0..8 {
+        let input: [i64; 8] =
+            core::array::from_fn(|row| block[row * 8 + column] as i64 * quantization[row * 8 + column] as i64);
+        if input[1..].iter().all(|coefficient| *coefficient == 0) {
+            // AC terms all zero, so the column's just the DC term
+            for row in 0..8 {
+                workspace[row * 8 + column] = input[0] << pass1_bits;
+            }
+            continue;
+        }
+        let output = idct_islow_1d(input);
+        for row in 0..8 {
+            workspace[row * 8 + column] = descale(output[row], CONST_BITS - pass1_bits);
+        }
+    }
+
+    // Pass 2: process the rows from the workspace, storing the level shifted samples
+    for row in 0..8 {
+        let input: [i64; 8] = core::array::from_fn(|column| workspace[row * 8 + column]);
+        if input[1..].iter().all(|coefficient| *coefficient == 0) {
+            let sample = output(descale(input[0], pass1_bits + 3), p_);
+            samples[row * 8..row * 8 + 8].fill(sample);
+            continue;
+        }
+        let results = idct_islow_1d(input);
+        for column in 0..8 {
+            samples[row * 8 + column] = output(descale(results[column], CONST_BITS + pass1_bits + 3), p_);
+        }
+    }
+
+    samples
+}
+
+/// The scale factors of the AAN IDCT, times 2^14, for each coefficient in natural order
+const AAN_SCALES: [i64; 64] = [
+    16384, 22725, 21407, 19266, 16384, 12873, 8867, 4520, 22725, 31521, 29692, 26722, 22725, 17855, 12299, 6270,
+    21407, 29692, 27969, 25172, 21407, 16819, 11585, 5906, 19266, 26722, 25172, 22654, 19266, 15137, 10426, 5315,
+    16384, 22725, 21407, 19266, 16384, 12873, 8867, 4520, 12873, 17855, 16819, 15137, 12873, 10114, 6967, 3552,
+    8867, 12299, 11585, 10426, 8867, 6967, 4799, 2446, 4520, 6270, 5906, 5315, 4520, 3552, 2446, 1247,
+];
+
+/// The scale factors of the AAN IDCT for each row or column, cos(k * pi / 16) * sqrt(2) other than for 0
+const AAN_SCALE_FACTORS: [f64; 8] = [
+    1.0, 1.387039845, 1.306562965, 1.175875602, 1.0, 0.785694958, 0.541196100, 0.275899379,
+];
+
+/// How many more bits of precision the multipliers of the fast integer IDCT have than the quantization table
+fn ifast_scale_bits(p_: u8) -> u8 {
+    if p_ > 8 { 13 } else { 2 }
+}
+
+/// A quantization table with the scaling of the fast integer IDCT folded into it, as libjpeg does (jddctmgr.c)
+pub(crate) fn ifast_multipliers(quantization: &[u16; 64], p_: u8) -> [i64; 64] {
+    core::array::from_fn(|k| descale(quantization[k] as i64 * AAN_SCALES[k], 14 - ifast_scale_bits(p_)))
+}
+
+/// A quantization table with the scaling of the floating point IDCT folded into it, as libjpeg-turbo does
+pub(crate) fn float_multipliers(quantization: &[u16; 64]) -> [f32; 64] {
+    core::array::from_fn(|k| {
+        (quantization[k] as f64 * AAN_SCALE_FACTORS[k / 8] * AAN_SCALE_FACTORS[k % 8] * 0.125) as f32
+    })
+}
+
+const IFAST_CONST_BITS: u8 = 8;
+const IFAST_FIX_1_082392200: i64 = 277;
+const IFAST_FIX_1_414213562: i64 = 362;
+const IFAST_FIX_1_847759065: i64 = 473;
+const IFAST_FIX_2_613125930: i64 = 669;
+
+/// The 1D AAN IDCT shared by both passes of `idct_ifast`, whose multiplications are truncated rather than rounded
+fn idct_ifast_1d(input: [i64; 8]) -> [i64; 8] {
+    let multiply = |value: i64, constant: i64| (value * constant) >> IFAST_CONST_BITS;
+
+    // Even part
+    let tmp10 = input[0] + input[4];
+    let tmp11 = input[0] - input[4];
+    let tmp13 = input[2] + input[6];
+    let tmp12 = multiply(input[2] - input[6], IFAST_FIX_1_414213562) - tmp13;
+
+    let tmp0 = tmp10 + tmp13;
+    let tmp3 = tmp10 - tmp13;
+    let tmp1 = tmp11 + tmp12;
+    let tmp2 = tmp11 - tmp12;
+
+    // Odd part
+    let z13 = input[5] + input[3];
+    let z10 = input[5] - input[3];
+    let z11 = input[1] + input[7];
+    let z12 = input[1] - input[7];
+
+    let tmp7 = z11 + z13;
+    let tmp11 = multiply(z11 - z13, IFAST_FIX_1_414213562);
+    let z5 = multiply(z10 + z12, IFAST_FIX_1_847759065);
+    let tmp10 = multiply(z12, IFAST_FIX_1_082392200) - z5;
+    let tmp12 = multiply(z10, -IFAST_FIX_2_613125930) + z5;
+
+    let tmp6 = tmp12 - tmp7;
+    let tmp5 = tmp11 - tmp6;
+    let tmp4 = tmp10 + tmp5;
+
+    [
+        tmp0 + tmp7,
+        tmp1 + tmp6,
+        tmp2 + tmp5,
+        tmp3 - tmp4,
+        tmp3 + tmp4,
+        tmp2 - tmp5,
+        tmp1 - tmp6,
+        tmp0 - tmp7,
+    ]
+}
+
+/// Dequantizes and inverse transforms one block, in natural order, into level shifted samples
+/// with the fast, less accurate, integer IDCT of libjpeg (jidctfst.c), by Arai, Agui and Nakajima.
+/// `multipliers` are from `ifast_multipliers`.
+pub(crate) fn idct_ifast(block: &[i32; 64], multipliers: &[i64; 64], p_: u8) -> [i32; 64] {
+    let pass1_bits: u8 = if p_ > 8 { 1 } else { 2 };
+    // 12 bit samples have more precise multipliers, which are brought down to the first pass's precision
+    let dequantize_shift = ifast_scale_bits(p_) - pass1_bits;
+    let mut workspace: [i64; 64] = [0; 64];
+    let mut samples: [i32; 64] = [0; 64];
+
+    // Pass 1: process the columns, storing the results in the workspace
+    for column in 0..8 {
+        let input: [i64; 8] = core::array::from_fn(|row| {
+            (block[row * 8 + column] as i64 * multipliers[row * 8 + column]) >> dequantize_shift
+        });
+        let output = idct_ifast_1d(input);
+        for row in 0..8 {
+            workspace[row * 8 + column] = output[row];
+        }
+    }
+
+    // Pass 2: process the rows from the workspace, storing the level shifted samples
+    for row in 0..8 {
+        let input: [i64; 8] = core::array::from_fn(|column| workspace[row * 8 + column]);
+        let output = idct_ifast_1d(input);
+        for column in 0..8 {
+            samples[row * 8 + column] = range_limit(output[column] >> (pass1_bits + 3), p_);
+        }
+    }
+
+    samples
+}
+
+/// The 1D AAN IDCT shared by both passes of `idct_float`, in single precision, in the same order as libjpeg
+fn idct_float_1d(input: [f32; 8]) -> [f32; 8] {
+    // Even part
+    let tmp10 = input[0] + input[4];
+    let tmp11 = input[0] - input[4];
+    let tmp13 = input[2] + input[6];
+    let tmp12 = (input[2] - input[6]) * core::f32::consts::SQRT_2 - tmp13;
+
+    let tmp0 = tmp10 + tmp13;
+    let tmp3 = tmp10 - tmp13;
+    let tmp1 = tmp11 + tmp12;
+    let tmp2 = tmp11 - tmp12;
+
+    // Odd part
+    let z13 = input[5] + input[3];
+    let z10 = input[5] - input[3];
+    let z11 = input[1] + input[7];
+    let z12 = input[1] - input[7];
+
+    let tmp7 = z11 + z13;
+    let tmp11 = (z11 - z13) * core::f32::consts::SQRT_2;
+    let z5 = (z10 + z12) * 1.847_759;
+    let tmp10 = z5 - z12 * 1.082_392_2;
+    let tmp12 = z5 - z10 * 2.613_126;
+
+    let tmp6 = tmp12 - tmp7;
+    let tmp5 = tmp11 - tmp6;
+    let tmp4 = tmp10 - tmp5;
+
+    [
+        tmp0 + tmp7,
+        tmp1 + tmp6,
+        tmp2 + tmp5,
+        tmp3 + tmp4,
+        tmp3 - tmp4,
+        tmp2 - tmp5,
+        tmp1 - tmp6,
+        tmp0 - tmp7,
+    ]
+}
+
+/// Dequantizes and inverse transforms one block, in natural order, into level shifted samples
+/// with the floating point IDCT of libjpeg-turbo (jidctflt.c). `multipliers` are from `float_multipliers`.
+pub(crate) fn idct_float(block: &[i32; 64], multipliers: &[f32; 64], p_: u8) -> [i32; 64] {
+    let max_sample: i64 = (1 << p_) - 1;
+    let center: i64 = 1 << (p_ - 1);
+    let mut workspace: [f32; 64] = [0.0; 64];
+    let mut samples: [i32; 64] = [0; 64];
+
+    // Pass 1: process the columns, storing the results in the workspace
+    for column in 0..8 {
+        let input: [f32; 8] =
+            core::array::from_fn(|row| block[row * 8 + column] as f32 * multipliers[row * 8 + column]);
+        let output = idct_float_1d(input);
+        for row in 0..8 {
+            workspace[row * 8 + column] = output[row];
+        }
+    }
+
+    // Pass 2: process the rows, with the level shift, and a half for rounding, added to the DC term
+    for row in 0..8 {
+        let mut input: [f32; 8] = core::array::from_fn(|column| workspace[row * 8 + column]);
+        input[0] += center as f32 + 0.5;
+        let output = idct_float_1d(input);
+        for column in 0..8 {
+            // libjpeg's sample range limit table, whose wrapping around is offset from `range_limit`'s
+            let index = output[column] as i64 & (4 * (max_sample + 1) - 1);
+            samples[row * 8 + column] = (if index <= max_sample {
+                index
+            } else if index < 2 * (max_sample + 1) + center {
+                max_sample
+            } else {
+                0
+            }) as i32;
+        }
+    }
+
+    samples
+}
+
+const FIX_0_211164243: i64 = 1730;
+const FIX_0_509795579: i64 = 4176;
+const FIX_0_601344887: i64 = 4926;
+const FIX_0_720959822: i64 = 5906;
+const FIX_0_850430095: i64 = 6967;
+const FIX_1_061594337: i64 = 8697;
+const FIX_1_272758580: i64 = 10426;
+const FIX_1_451774981: i64 = 11893;
+const FIX_2_172734803: i64 = 17799;
+const FIX_3_624509785: i64 = 29692;
+
+/// Dequantizes and inverse transforms one block into `size` by `size` level shifted samples, 8, 4, 2 or 1,
+/// for decoding at 1/1, 1/2, 1/4 or 1/8 scale. The samples are in the first `size * size` places, row by row.
+/// The reduced sizes use libjpeg's (jidctred.c), which only look at the coefficients they need.
+pub(crate) fn idct_scaled(block: &[i32; 64], quantization: &[u16; 64], p_: u8, size: usize) -> [i32; 64] {
+    match size {
+        8 => idct_islow(block, quantization, p_),
+        4 => idct_4x4(block, quantization, p_),
+        2 => idct_2x2(block, quantization, p_),
+        1 => {
+            let mut samples: [i32; 64] = [0; 64];
+            samples[0] = range_limit(descale(block[0] as i64 * quantization[0] as i64, 3), p_);
+            samples
+        },
+        _ => panic!("Blocks can only be scaled to 8, 4, 2 or 1 samples square"),
+    }
+}
+
+/// The 1D IDCT of `idct_4x4`, from the inputs 0 to 7 less 4, returning its 4 outputs before descaling
+fn idct_4_1d(input: [i64; 8]) -> [i64; 4] {
+    // Even part
+    let tmp0 = input[0] << (CONST_BITS + 1);
+    let tmp2 = input[2] * FIX_1_847759065 - input[6] * FIX_0_765366865;
+    let tmp10 = tmp0 + tmp2;
+    let tmp12 = tmp0 - tmp2;
+
+    // Odd part
+    let (z1, z2, z3, z4) = (input[7], input[5], input[3], input[1]);
+    let tmp0 = -z1 * FIX_0_211164243 + z2 * FIX_1_451774981 - z3 * FIX_2_172734803 + z4 * FIX_1_061594337;
+    let tmp2 = -z1 * FIX_0_509795579 - z2 * FIX_0_601344887 + z3 * FIX_0_899976223 + z4 * FIX_2_562915447;
+
+    [tmp10 + tmp2, tmp12 + tmp0, tmp12 - tmp0, tmp10 - tmp2]
+}
+
+/// The 1D IDCT of `idct_2x2`, from the odd inputs and the DC, returning its 2 outputs before descaling
+fn idct_2_1d(input: [i64; 8]) -> [i64; 2] {
+    let tmp10 = input[0] << (CONST_BITS + 2);
+    let tmp0 = -input[7] * FIX_0_720959822 + input[5] * FIX_0_850430095 - input[3] * FIX_1_272758580
+        + input[1] * FIX_3_624509785;
+    [tmp10 + tmp0, tmp10 - tmp0]
+}
+
+/// Applies a reduced 1D IDCT to the columns then the rows of a block, as libjpeg's reduced IDCTs do,
+/// for `SIZE` outputs each way. `shift` is the extra descaling of the reduced transform.
+fn idct_reduced<const SIZE: usize>(
+    block: &[i32; 64],
+    quantization: &[u16; 64],
+    p_: u8,
+    idct_1d: fn([i64; 8]) -> [i64; SIZE],
+    shift: u8,
+) -> [i32; 64] {
+    let pass1_bits: u8 = if p_ > 8 { 1 } else { 2 };
+    let mut workspace: [i64; 64] = [0; 64];
+    let mut samples: [i32; 64] = [0; 64];
+
+    // Pass 1: the columns, other than those the second pass doesn't look at, like the 1D IDCT doesn't
+    for column in 0..8 {
+        if SIZE == 4 && column == 4 || SIZE == 2 && column != 0 && column % 2 == 0 {
+            continue;
+        }
+        let input: [i64; 8] =
+            core::array::from_fn(|row| block[row * 8 + column] as i64 * quantization[row * 8 + column] as i64);
+        let output = idct_1d(input);
+        for row in 0..SIZE {
+            workspace[row * 8 + column] = descale(output[row], CONST_BITS - pass1_bits + shift);
+        }
+    }
+
+    // Pass 2: the rows of the workspace, into level shifted samples
+    for row in 0..SIZE {
+        let input: [i64; 8] = core::array::from_fn(|column| workspace[row * 8 + column]);
+        let output = idct_1d(input);
+        for column in 0..SIZE {
+            samples[row * SIZE + column] = range_limit(descale(output[column], CONST_BITS + pass1_bits + 3 + shift), p_);
+        }
+    }
+
+    samples
+}
+
+/// The 4x4 IDCT of libjpeg's jidctred.c, for 1/2 scale
+fn idct_4x4(block: &[i32; 64], quantization: &[u16; 64], p_: u8) -> [i32; 64] {
+    idct_reduced::<4>(block, quantization, p_, idct_4_1d, 1)
+}
+
+/// The 2x2 IDCT of libjpeg's jidctred.c, for 1/4 scale
+fn idct_2x2(block: &[i32; 64], quantization: &[u16; 64], p_: u8) -> [i32; 64] {
+    idct_reduced::<2>(block, quantization, p_, idct_2_1d, 2)
+}
+
+/// The 1D DCT shared by both passes of `fdct_islow`, taking 8 samples and returning the 8 outputs in natural order.
+/// Outputs 0 and 4 are left unscaled, while the rest are scaled up by 2^CONST_BITS.
+fn fdct_islow_1d(input: [i64; 8]) -> [i64; 8] {
+    let tmp0 = input[0] + input[7];
+    let tmp7 = input[0] - input[7];
+    let tmp1 = input[1] + input[6];
+    let tmp6 = input[1] - input[6];
+    let tmp2 = input[2] + input[5];
+    let tmp5 = input[2] - input[5];
+    let tmp3 = input[3] + input[4];
+    let tmp4 = input[3] - input[4];
+
+    // Even part
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+
+    let z1 = (tmp12 + tmp13) * FIX_0_541196100;
+
+    // Odd part
+    let mut z1_odd = tmp4 + tmp7;
+    let mut z2 = tmp5 + tmp6;
+    let mut z3 = tmp4 + tmp6;
+    let mut z4 = tmp5 + tmp7;
+    let z5 = (z3 + z4) * FIX_1_175875602;
+
+    let tmp4 = tmp4 * FIX_0_298631336;
+    let tmp5 = tmp5 * FIX_2_053119869;
+    let tmp6 = tmp6 * FIX_3_072711026;
+    let tmp7 = tmp7 * FIX_1_501321110;
+    z1_odd *= -FIX_0_899976223;
+    z2 *= -FIX_2_562915447;
+    z3 *= -FIX_1_961570560;
+    z4 *= -FIX_0_390180644;
+
+    z3 += z5;
+    z4 += z5;
+
+    [
+        tmp10 + tmp11,
+        tmp7 + z1_odd + z4,
+        z1 + tmp13 * FIX_0_765366865,
+        tmp6 + z2 + z3,
+        tmp10 - tmp11,
+        tmp5 + z2 + z4,
+        z1 - tmp12 * FIX_1_847759065,
+        tmp4 + z1_odd + z3,
+    ]
+}
+
+/// Transforms one block of level shifted samples, in natural order, into coefficients that are scaled up by 8.
+/// This is the accurate integer forward DCT of libjpeg (jfdctint.c), and gives the same results.
+/// 10918-1, A.3.3, P. 27
+pub(crate) fn fdct_islow(samples: &[i32; 64]) -> [i32; 64] {
+    const PASS1_BITS: u8 = 2;
+    let mut workspace: [i64; 64] = [0; 64];
+    let mut coefficients: [i32; 64] = [0; 64];
+
+    // Pass 1: process the rows, storing the results, scaled up by 2^PASS1_BITS, in the workspace
+    for row in 0..8 {
+        let output = fdct_islow_1d(core::array::from_fn(|column| samples[row * 8 + column] as i64));
+        for (column, value) in output.into_iter().enumerate() {
+            workspace[row * 8 + column] = match column {
+                0 | 4 => value << PASS1_BITS,
+                _ => descale(value, CONST_BITS - PASS1_BITS),
+            };
+        }
+    }
+
+    // Pass 2: process the columns, removing the extra precision but leaving the factor of 8
+    for column in 0..8 {
+        let output = fdct_islow_1d(core::array::from_fn(|row| workspace[row * 8 + column]));
+        for (row, value) in output.into_iter().enumerate() {
+            coefficients[row * 8 + column] = match row {
+                0 | 4 => descale(value, PASS1_BITS),
+                _ => descale(value, CONST_BITS + PASS1_BITS),
+            } as i32;
+        }
+    }
+
+    coefficients
+}
+
+/// Divides the coefficients of `fdct_islow` by the quantization table, and the 8 they're scaled up by,
+/// rounding halves away from zero, as libjpeg does (jcdctmgr.c), 10918-1, A.3.4, P. 28
+pub(crate) fn quantize(coefficients: &[i32; 64], quantization: &[u16; 64]) -> [i32; 64] {
+    core::array::from_fn(|k| {
+        let divisor = quantization[k] as i32 * 8;
+        let quotient = (coefficients[k].abs() + (divisor >> 1)) / divisor;
+        if coefficients[k] < 0 {
+            -quotient
+        } else {
+            quotient
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idct_scaled_dc_only() {
+        // without AC terms every size gives the same flat block
+        let mut block = [0; 64];
+        block[0] = -50;
+        let quantization = [4; 64];
+
+        for size in [8, 4, 2, 1] {
+            let samples = idct_scaled(&block, &quantization, 8, size);
+            assert_eq!(samples[..size * size], vec![103; size * size]);
+        }
+    }
+
+    #[test]
+    fn fdct_islow_round_trip() {
+        let samples: [i32; 64] = core::array::from_fn(|i| ((i * 37) % 200) as i32 - 100);
+        let coefficients = quantize(&fdct_islow(&samples), &[1; 64]);
+
+        // a flat block only has a DC term, of 8 times its average
+        assert_eq!(quantize(&fdct_islow(&[10; 64]), &[1; 64])[..2], [80, 0]);
+        // and with no quantization the IDCT gets back to within 1 of the samples
+        let reconstructed = idct_islow(&coefficients, &[1; 64], 8);
+        for (sample, reconstructed) in samples.iter().zip(reconstructed) {
+            assert!((sample + 128 - reconstructed).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn zigzag_visits_every_coefficient() {
+        let mut visited = ZIGZAG.to_vec();
+        visited.sort();
+        assert_eq!(visited, (0..64).collect::<Vec<usize>>());
+        assert_eq!(ZIGZAG[2], 8);
+        assert_eq!(ZIGZAG[63], 63);
+    }
+
+    fn eob_only_table() -> SSSSTable {
+        // EOB2 as 0, RS 0/1 as 10 and ZRL as 11
+        let mut code_lengths: [Vec<u8>; 16] = Default::default();
+        code_lengths[0] = Vec::from([0x20]);
+        code_lengths[1] = Vec::from([0x01, 0xF0]);
+        let (table, min_code_length, max_code_length) = jpeg_utils::make_ssss_table(code_lengths);
+        SSSSTable {
+            t_c: 1,
+            t_h: 0,
+            table,
+            min_code_length,
+            max_code_length,
+        }
+    }
+
+    /// A table with a single code, 0, for `symbol`
+    fn single_symbol_table(t_c: u8, symbol: u8) -> SSSSTable {
+        let mut code_lengths: [Vec<u8>; 16] = Default::default();
+        code_lengths[0] = Vec::from([symbol]);
+        let (table, min_code_length, max_code_length) = jpeg_utils::make_ssss_table(code_lengths);
+        SSSSTable {
+            t_c,
+            t_h: 0,
+            table,
+            min_code_length,
+            max_code_length,
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "A run of zero coefficients went past the end of the block")]
+    fn decode_block_run_past_the_end() {
+        let dc_table = single_symbol_table(0, 0);
+        // RS 5/1, so coefficients 6, 12, ..., 60 and then 66
+        let ac_table = single_symbol_table(1, 0x51);
+        let mut image_bits = [0].into_iter().chain([0, 1].repeat(11));
+
+        decode_block(&dc_table, &ac_table, &mut image_bits, &mut 0, &mut [0; 64]);
+    }
+
+    #[test]
+    #[should_panic(expected = "A run of zero coefficients went past the end of the spectral band")]
+    fn decode_ac_first_run_past_the_band() {
+        let ac_table = single_symbol_table(1, 0x51);
+        let mut image_bits = [0, 1].into_iter();
+
+        decode_ac_first(&ac_table, &mut image_bits, (1, 5), 0, &mut 0, &mut [0; 64]);
+    }
+
+    #[test]
+    fn decode_ac_first_eob_run() {
+        let ac_table = eob_only_table();
+        // RS 0/1 with a 1, then EOB2 with run bits 01, so a run of 5 blocks including this one
+        let image_bits: Vec<u8> = Vec::from([1, 0, 1, 0, 0, 1]);
+        let mut image_bits = image_bits.into_iter();
+        let mut eob_run: u32 = 0;
+        let mut block = [0; 64];
+
+        decode_ac_first(&ac_table, &mut image_bits, (1, 5), 1, &mut eob_run, &mut block);
+        assert_eq!(block[1], 2);
+        assert_eq!(eob_run, 4);
+        assert!(image_bits.next().is_none());
+
+        let mut next_block = [0; 64];
+        decode_ac_first(&ac_table, &mut image_bits, (1, 5), 1, &mut eob_run, &mut next_block);
+        assert_eq!(next_block, [0; 64]);
+        assert_eq!(eob_run, 3);
+    }
+
+    #[test]
+    fn decode_ac_refine_corrects_nonzero_coefficients() {
+        let ac_table = eob_only_table();
+        let mut block = [0; 64];
+        block[ZIGZAG[1]] = 2;
+        block[ZIGZAG[2]] = -2;
+        // RS 0/1, negative, skipping past the refined 1 and 2 to land on 3, then EOB2 with run bits 00,
+        // refining the coefficient at 4 on the way out
+        block[ZIGZAG[4]] = 4;
+        let image_bits: Vec<u8> = Vec::from([1, 0, 0, 0, 1, 0, 0, 0, 1]);
+        let mut image_bits = image_bits.into_iter();
+        let mut eob_run: u32 = 0;
+
+        decode_ac_refine(&ac_table, &mut image_bits, (1, 5), 0, &mut eob_run, &mut block);
+
+        assert_eq!(block[ZIGZAG[1]], 2);
+        assert_eq!(block[ZIGZAG[2]], -3);
+        assert_eq!(block[ZIGZAG[3]], -1);
+        assert_eq!(block[ZIGZAG[4]], 5);
+        assert_eq!(eob_run, 3);
+    }
+
+    #[test]
+    fn decode_dc_refine_sets_bit() {
+        let image_bits: Vec<u8> = Vec::from([1, 0]);
+        let mut image_bits = image_bits.into_iter();
+        let mut block = [0; 64];
+        block[0] = 8;
+
+        decode_dc_refine(&mut image_bits, 2, &mut block);
+        assert_eq!(block[0], 12);
+        decode_dc_refine(&mut image_bits, 1, &mut block);
+        assert_eq!(block[0], 12);
+    }
+
+    #[test]
+    fn range_limit_8_bit() {
+        assert_eq!(range_limit(0, 8), 128);
+        assert_eq!(range_limit(127, 8), 255);
+        assert_eq!(range_limit(200, 8), 255);
+        assert_eq!(range_limit(-128, 8), 0);
+        assert_eq!(range_limit(-300, 8), 0);
+        assert_eq!(range_limit(-1, 8), 127);
+    }
+
+    #[test]
+    fn idct_islow_dc_only() {
+        let mut block = [0; 64];
+        block[0] = 10;
+        let quantization = [8; 64];
+
+        // 10 * 8 / 8 = 10 above the level shift everywhere
+        assert_eq!(idct_islow(&block, &quantization, 8), [138; 64]);
+        assert_eq!(idct_islow(&block, &quantization, 12), [2058; 64]);
+    }
+
+    #[test]
+    fn idct_islow_first_horizontal_frequency() {
+        let mut block = [0; 64];
+        block[1] = 100;
+        let quantization = [1; 64];
+
+        let samples = idct_islow(&block, &quantization, 8);
+
+        // every row's the same, decreasing from left to right and symmetric about the level shift
+        for row in 0..8 {
+            assert_eq!(samples[row * 8..row * 8 + 8], samples[0..8]);
+        }
+        for column in 0..8 {
+            assert_eq!(samples[column] - 128, 128 - samples[7 - column]);
+        }
+        assert!(samples.windows(2).take(7).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn component_geometry_420() {
+        let frame_header = FrameHeader {
+            marker: 0xFFC0,
+            p_: 8,
+            y_: 29,
+            x_: 43,
+            components: std::collections::HashMap::from([
+                (1, Component { c_: 1, h_: 2, v_: 2, t_q: 0 }),
+                (2, Component { c_: 2, h_: 1, v_: 1, t_q: 1 }),
+            ]),
+            component_order: vec![1, 2],
+        };
+
+        assert_eq!(frame_header.max_sampling_factors(), (2, 2));
+        assert_eq!(frame_header.mcus(), (3, 2));
+
+        let luma = frame_header.component_geometry(&frame_header.components[&1]);
+        assert_eq!((luma.blocks_wide, luma.blocks_high), (6, 4));
+        assert_eq!((luma.padded_blocks_wide, luma.padded_blocks_high), (6, 4));
+
+        let chroma = frame_header.component_geometry(&frame_header.components[&2]);
+        assert_eq!((chroma.blocks_wide, chroma.blocks_high), (3, 2));
+        assert_eq!((chroma.padded_blocks_wide, chroma.padded_blocks_high), (3, 2));
+    }
+}
@@ -0,0 +1,1038 @@
+use std::collections::HashMap;
+
+use crate::arithmetic::{self, ArithmeticEncoder};
+use crate::encoder::{self, BitWriter, Symbol};
+use crate::jpeg_utils::number_of_used_bits;
+use crate::{color, dct, ChromaSubsampling, Marker, ProgressiveScan};
+
+/// The example luminance quantization table, 10918-1, Table K.1, P. 143, in natural order
+const LUMINANCE_QUANTIZATION: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51,
+    87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// The example chrominance quantization table, 10918-1, Table K.2, P. 143, in natural order
+const CHROMINANCE_QUANTIZATION: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99,
+];
+
+/// Scales one of the example quantization tables for a quality of 1 to 100 the way libjpeg does (jcparam.c),
+/// where 50 gives the table as it is, limiting the results to 1 to 255 for 8 bit tables
+fn scaled_quantization_table(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let scale_factor: u32 = if quality < 50 { 5000 / quality as u32 } else { 200 - quality as u32 * 2 };
+    table.map(|q| ((q as u32 * scale_factor + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// A component of the frame being encoded, with its quantized blocks in natural order
+pub(crate) struct EncoderComponent {
+    pub(crate) c_: u8,  // Component identifier
+    pub(crate) h_: u8,  // Horizontal sampling factor
+    pub(crate) v_: u8,  // Vertical sampling factor
+    pub(crate) t_q: u8, // Quantization table destination selector
+    pub(crate) t_h: u8, // Huffman or arithmetic coding table destination identifier, of both the DC and the AC table
+    pub(crate) blocks_wide: usize,
+    pub(crate) blocks_high: usize,
+    pub(crate) blocks: Vec<[i32; 64]>,
+}
+
+/// The blocks of an MCU in the order they're coded, each with the index of its component in the scan
+type Mcu = Vec<(usize, [i32; 64])>;
+
+/// How often the scans of an image are restarted, 10918-1, B.2.4.4, P. 43
+#[derive(Clone, Copy)]
+enum RestartInterval {
+    Mcus(u16),
+    Rows(u16), // rows of MCUs, which differ between interleaved and non-interleaved scans, as libjpeg's restart_in_rows
+}
+
+impl RestartInterval {
+    /// The number of MCUs in each interval, Ri, of a scan with `mcus_per_row` MCUs in each row
+    fn mcus(self, mcus_per_row: usize) -> u16 {
+        match self {
+            RestartInterval::Mcus(mcus) => mcus,
+            RestartInterval::Rows(rows) => (rows as usize * mcus_per_row).min(u16::MAX as usize) as u16,
+        }
+    }
+}
+
+/// Encodes 8 bit grayscale or RGB samples as a baseline DCT image (SOF0), 10918-1, F.1, P. 87,
+/// a progressive one (SOF2), G.1, P. 119, or an arithmetic coded sequential one (SOF9), F.1.4, P. 82,
+/// converting RGB to YCbCr and writing a JFIF segment, as libjpeg does with its defaults
+pub struct DctEncoder {
+    samples: Vec<u32>, // interleaved, row by row
+    width: u16,
+    height: u16,
+    component_count: usize,
+    quality: u8,
+    chroma_subsampling: ChromaSubsampling,
+    optimize_huffman_tables: bool, // whether the Huffman tables are built for the image rather than the example ones
+    scan_script: Option<Vec<ProgressiveScan>>, // the scans of a progressive image; a sequential one if not set
+    restart_interval: Option<RestartInterval>,
+    arithmetic_coding: bool,
+    arithmetic_conditioning: (u8, u8), // the DAC Cs values of every DC table, U over L, and of every AC table, Kx
+}
+
+impl DctEncoder {
+    /// An encoder for `width` by `height` pixels of 1 (grayscale) or 3 (RGB) interleaved 8 bit samples,
+    /// at quality 75 with 4:2:0 chroma subsampling until others are chosen
+    pub fn new(samples: Vec<u32>, width: u16, height: u16, component_count: usize) -> Self {
+        assert!(component_count == 1 || component_count == 3, "Only grayscale and RGB images can be encoded");
+        assert!(width > 0 && height > 0, "An image must have at least one pixel");
+        assert_eq!(
+            samples.len(),
+            width as usize * height as usize * component_count,
+            "There must be a sample of each component for every pixel"
+        );
+        assert!(samples.iter().all(|sample| *sample < 256), "Baseline samples are 8 bits");
+
+        Self {
+            samples,
+            width,
+            height,
+            component_count,
+            quality: 75,
+            chroma_subsampling: ChromaSubsampling::Ratio420,
+            optimize_huffman_tables: false,
+            scan_script: None,
+            restart_interval: None,
+            arithmetic_coding: false,
+            arithmetic_conditioning: (arithmetic::DEFAULT_DC_CONDITIONING, arithmetic::DEFAULT_AC_CONDITIONING),
+        }
+    }
+
+    /// An encoder for blocks that have already been transformed and quantized, given to `write_frame`,
+    /// of an image of `width` by `height` pixels with 1 to 4 components
+    pub(crate) fn for_coefficients(width: u16, height: u16, component_count: usize) -> Self {
+        assert!((1..=4).contains(&component_count), "A frame can have 1 to 4 components");
+        Self {
+            samples: Vec::new(),
+            width,
+            height,
+            component_count,
+            quality: 75,
+            chroma_subsampling: ChromaSubsampling::Ratio444,
+            optimize_huffman_tables: false,
+            scan_script: None,
+            restart_interval: None,
+            arithmetic_coding: false,
+            arithmetic_conditioning: (arithmetic::DEFAULT_DC_CONDITIONING, arithmetic::DEFAULT_AC_CONDITIONING),
+        }
+    }
+
+    /// The quality, 1 to 100, the example quantization tables are scaled for, as libjpeg's
+    pub fn set_quality(&mut self, quality: u8) {
+        assert!((1..=100).contains(&quality), "The quality is 1 to 100");
+        self.quality = quality;
+    }
+
+    /// How the chroma components of RGB images are sampled
+    pub fn set_chroma_subsampling(&mut self, chroma_subsampling: ChromaSubsampling) {
+        self.chroma_subsampling = chroma_subsampling;
+    }
+
+    /// Whether to code the image with the Huffman tables that make it smallest, 10918-1, K.2, P. 144,
+    /// rather than the example ones of K.3, P. 149
+    pub fn set_optimize_huffman_tables(&mut self, optimize_huffman_tables: bool) {
+        self.optimize_huffman_tables = optimize_huffman_tables;
+    }
+
+    /// Restarts each scan every `mcus` MCUs, with a DRI segment and RSTm markers, 10918-1, B.2.1, P. 34,
+    /// so that its intervals can be decoded independently; 0 doesn't restart them
+    pub fn set_restart_interval(&mut self, mcus: u16) {
+        self.restart_interval = (mcus > 0).then_some(RestartInterval::Mcus(mcus));
+    }
+
+    /// Restarts each scan every `rows` rows of its MCUs; 0 doesn't restart them
+    pub fn set_restart_interval_in_rows(&mut self, rows: u16) {
+        self.restart_interval = (rows > 0).then_some(RestartInterval::Rows(rows));
+    }
+
+    /// Whether to code the scans with the QM-coder, 10918-1, F.1.4, P. 82, for an extended sequential image (SOF9)
+    /// rather than a Huffman coded one. It takes the place of the Huffman tables, so progressive images can't have it.
+    pub fn set_arithmetic_coding(&mut self, arithmetic_coding: bool) {
+        self.arithmetic_coding = arithmetic_coding;
+    }
+
+    /// The conditioning of the arithmetic coding statistics, written in a DAC segment, 10918-1, B.2.4.3, P. 40:
+    /// DC differences of magnitude below 2^(L - 1) count as zero, and those above 2^(U - 1) as large,
+    /// F.1.4.4.1.2, P. 97, while AC magnitudes are conditioned differently below and above coefficient `k_x`,
+    /// F.1.4.4.2, P. 98. libjpeg's defaults are L = 0, U = 1 and Kx = 5.
+    pub fn set_arithmetic_conditioning(&mut self, l: u8, u: u8, k_x: u8) {
+        assert!(l <= u && u <= 15, "The DC conditioning bounds must have 0 <= L <= U <= 15");
+        assert!((1..=63).contains(&k_x), "The AC conditioning must be 1 to 63");
+        self.arithmetic_conditioning = ((u << 4) | l, k_x);
+    }
+
+    /// Whether to write a progressive image (SOF2) with the default scan script, as libjpeg's, rather than a sequential one
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.scan_script = progressive.then(|| Self::default_scan_script(self.component_count));
+    }
+
+    /// Writes a progressive image with the given scans, which must send every DC coefficient and can't send
+    /// any bit of a coefficient more than once, 10918-1, G.1.1.1.1, P. 120
+    pub fn set_scan_script(&mut self, scan_script: Vec<ProgressiveScan>) {
+        // the successive approximation bit each coefficient of each component was last sent to
+        let mut last_bit_positions: Vec<[Option<u8>; 64]> = vec![[None; 64]; self.component_count];
+        for scan in &scan_script {
+            assert!(!scan.component_order.is_empty() && scan.component_order.len() <= 4, "A scan has 1 to 4 components");
+            assert!(
+                scan.component_order.iter().all(|c_| (1..=self.component_count as u8).contains(c_)),
+                "Scans can only have the image's components"
+            );
+            // B.2.3, P. 38
+            assert!(
+                scan.component_order.iter().enumerate().all(|(i, c_)| !scan.component_order[..i].contains(c_)),
+                "A scan can't have a component more than once"
+            );
+            assert!(
+                scan.component_order.windows(2).all(|pair| pair[0] < pair[1]),
+                "A scan's components must be in the order they're in the frame"
+            );
+            assert!(scan.s_s <= scan.s_e && scan.s_e <= 63, "The spectral selection must be within the block");
+            assert!(scan.s_s > 0 || scan.s_e == 0, "DC coefficients are sent in scans of their own");
+            assert!(scan.s_s == 0 || scan.component_order.len() == 1, "AC scans have only one component");
+            assert!(scan.a_l <= 13, "Coefficients can't be sent from past their 13th bit");
+
+            for c_ in &scan.component_order {
+                let last_bit_positions = &mut last_bit_positions[*c_ as usize - 1];
+                assert!(scan.s_s == 0 || last_bit_positions[0].is_some(), "AC scans must follow the component's DC scan");
+                for last_bit_position in &mut last_bit_positions[scan.s_s as usize..=scan.s_e as usize] {
+                    match *last_bit_position {
+                        None => assert_eq!(scan.a_h, 0, "The first scan of a coefficient must have Ah = 0"),
+                        Some(a_l) => assert!(
+                            scan.a_h == a_l && scan.a_l + 1 == scan.a_h,
+                            "Refinement scans must send the next bit of coefficients that were sent before"
+                        ),
+                    }
+                    *last_bit_position = Some(scan.a_l);
+                }
+            }
+        }
+        assert!(
+            last_bit_positions.iter().all(|last_bit_positions| last_bit_positions[0].is_some()),
+            "The DC coefficients of every component must be sent"
+        );
+
+        self.scan_script = Some(scan_script);
+    }
+
+    /// libjpeg's default progression (jpeg_simple_progression), which sends the DC coefficients, and a first
+    /// approximation of the low and high frequencies before refining them, luma first and in the most detail
+    pub fn default_scan_script(component_count: usize) -> Vec<ProgressiveScan> {
+        let scan = |component_order: &[u8], s_s: u8, s_e: u8, a_h: u8, a_l: u8| ProgressiveScan {
+            component_order: component_order.to_vec(),
+            s_s,
+            s_e,
+            a_h,
+            a_l,
+        };
+        if component_count == 3 {
+            vec![
+                scan(&[1, 2, 3], 0, 0, 0, 1),
+                scan(&[1], 1, 5, 0, 2),
+                scan(&[3], 1, 63, 0, 1),
+                scan(&[2], 1, 63, 0, 1),
+                scan(&[1], 6, 63, 0, 2),
+                scan(&[1], 1, 63, 2, 1),
+                scan(&[1, 2, 3], 0, 0, 1, 0),
+                scan(&[3], 1, 63, 1, 0),
+                scan(&[2], 1, 63, 1, 0),
+                scan(&[1], 1, 63, 1, 0),
+            ]
+        } else {
+            vec![
+                scan(&[1], 0, 0, 0, 1),
+                scan(&[1], 1, 5, 0, 2),
+                scan(&[1], 6, 63, 0, 2),
+                scan(&[1], 1, 63, 2, 1),
+                scan(&[1], 0, 0, 1, 0),
+                scan(&[1], 1, 63, 1, 0),
+            ]
+        }
+    }
+
+    /// Writes the image, with the same markers and in the same order as libjpeg
+    pub fn encode(&self) -> Vec<u8> {
+        let quantization_tables = [
+            scaled_quantization_table(&LUMINANCE_QUANTIZATION, self.quality),
+            scaled_quantization_table(&CHROMINANCE_QUANTIZATION, self.quality),
+        ];
+        let components = self.transform_components(&quantization_tables);
+
+        let mut output: Vec<u8> = Vec::new();
+        output.extend_from_slice(&(Marker::SOI as u16).to_be_bytes());
+        encoder::write_jfif_segment(&mut output);
+
+        // Quantization tables, 10918-1, B.2.4.1, P. 39
+        for (t_q, table) in quantization_tables.iter().enumerate().take(components.len().min(2)) {
+            let mut parameters: Vec<u8> = vec![t_q as u8];
+            parameters.extend(dct::ZIGZAG.iter().map(|k| table[*k] as u8));
+            encoder::write_segment(&mut output, Marker::DQT, &parameters);
+        }
+
+        self.write_frame(&mut output, &components, 8, false);
+        output.extend_from_slice(&(Marker::EOI as u16).to_be_bytes());
+        output
+    }
+
+    /// Writes the frame header and the scans of the components' blocks, which have `precision` bit samples,
+    /// and whose quantization tables are 16 bit if `sixteen_bit_tables` is set.
+    /// Frames of 8 bit samples and 8 bit tables are baseline (SOF0), unless they're progressive (SOF2)
+    /// or arithmetic coded (SOF9), and others extended sequential (SOF1), as baseline frames can't have
+    /// 16 bit tables, 10918-1, B.2.4.1, P. 40.
+    pub(crate) fn write_frame(
+        &self,
+        output: &mut Vec<u8>,
+        components: &[EncoderComponent],
+        precision: u8,
+        sixteen_bit_tables: bool,
+    ) {
+        // Frame header, 10918-1, B.2.2, P. 35
+        let mut frame_header: Vec<u8> = vec![precision];
+        frame_header.extend_from_slice(&self.height.to_be_bytes());
+        frame_header.extend_from_slice(&self.width.to_be_bytes());
+        frame_header.push(components.len() as u8);
+        for component in components {
+            frame_header.extend_from_slice(&[component.c_, (component.h_ << 4) | component.v_, component.t_q]);
+        }
+        let marker = match (&self.scan_script, self.arithmetic_coding, precision, sixteen_bit_tables) {
+            (Some(_), false, _, _) => Marker::SOF2,
+            (Some(_), true, _, _) => panic!("Progressive images can't be arithmetic coded"),
+            (None, true, _, _) => Marker::SOF9,
+            (None, false, 8, false) => Marker::SOF0,
+            (None, false, _, _) => Marker::SOF1,
+        };
+        encoder::write_segment(output, marker, &frame_header);
+
+        // a DRI segment is only written before the scans whose interval differs from the one before, as in libjpeg
+        let mut restart_interval: u16 = 0;
+        match &self.scan_script {
+            None => {
+                let scan = ProgressiveScan {
+                    component_order: components.iter().map(|component| component.c_).collect(),
+                    s_s: 0,
+                    s_e: 63,
+                    a_h: 0,
+                    a_l: 0,
+                };
+                self.write_scan(output, components, &scan, &mut restart_interval);
+            },
+            Some(scan_script) => {
+                for scan in scan_script {
+                    self.write_scan(output, components, scan, &mut restart_interval);
+                }
+            },
+        }
+    }
+
+    /// Writes the Huffman tables or arithmetic coding conditioning a scan needs, followed by the scan.
+    /// Sequential scans are described by a scan of all of the coefficients, 0 to 63, which can't be a progressive one.
+    /// `restart_interval` is the one last defined, which is updated if the scan needs another.
+    fn write_scan(
+        &self,
+        output: &mut Vec<u8>,
+        components: &[EncoderComponent],
+        scan: &ProgressiveScan,
+        restart_interval: &mut u16,
+    ) {
+        let scan_components: Vec<&EncoderComponent> = scan
+            .component_order
+            .iter()
+            .map(|c_| components.iter().find(|component| component.c_ == *c_).unwrap())
+            .collect();
+        let maximum_sampling_factors = (
+            components.iter().map(|component| component.h_).max().unwrap(),
+            components.iter().map(|component| component.v_).max().unwrap(),
+        );
+        let (mcus_per_row, mcus) = self.scan_mcus(&scan_components, maximum_sampling_factors);
+        let scan_restart_interval = self.restart_interval.map_or(0, |interval| interval.mcus(mcus_per_row));
+
+        // The tables each component uses, in the order libjpeg writes them, 10918-1, B.2.4.2, P. 40;
+        // DC refinement scans need none, and progressive scans only need either the DC or the AC table
+        let mut tables: Vec<(u8, u8)> = Vec::new();
+        for component in &scan_components {
+            let dc_table = (scan.s_s == 0 && scan.a_h == 0).then_some((0, component.t_h));
+            let ac_table = (scan.s_e > 0).then_some((1, component.t_h));
+            for table in dc_table.into_iter().chain(ac_table) {
+                if !tables.contains(&table) {
+                    tables.push(table);
+                }
+            }
+        }
+
+        let data = if self.arithmetic_coding {
+            self.write_arithmetic_conditioning(output, &tables);
+            self.arithmetic_coded_scan(&scan_components, &mcus, scan_restart_interval)
+        } else {
+            let symbols = self.scan_symbols(&scan_components, mcus, scan, scan_restart_interval);
+            let codes = self.write_huffman_tables(output, &tables, &symbols);
+            let mut writer = BitWriter::new();
+            writer.write_symbols(symbols.iter(), &codes);
+            writer.finish()
+        };
+
+        // Restart interval definition, 10918-1, B.2.4.4, P. 43
+        if scan_restart_interval != *restart_interval {
+            encoder::write_segment(output, Marker::DRI, &scan_restart_interval.to_be_bytes());
+            *restart_interval = scan_restart_interval;
+        }
+
+        // Scan header, 10918-1, B.2.3, P. 37, with 0 for the tables that aren't used, as libjpeg
+        let mut scan_header: Vec<u8> = vec![scan_components.len() as u8];
+        for component in &scan_components {
+            let t_d = if scan.s_s == 0 && scan.a_h == 0 { component.t_h } else { 0 };
+            let t_a = if scan.s_e > 0 { component.t_h } else { 0 };
+            scan_header.extend_from_slice(&[component.c_, (t_d << 4) | t_a]);
+        }
+        scan_header.extend_from_slice(&[scan.s_s, scan.s_e, (scan.a_h << 4) | scan.a_l]);
+        encoder::write_segment(output, Marker::SOS, &scan_header);
+        output.extend(data);
+    }
+
+    /// Writes the Huffman tables, as (Tc, Th), a scan is coded with, and returns their codes
+    fn write_huffman_tables(
+        &self,
+        output: &mut Vec<u8>,
+        tables: &[(u8, u8)],
+        symbols: &[Symbol],
+    ) -> HashMap<(u8, u8), encoder::HuffmanCodes> {
+        let progressive = self.scan_script.is_some();
+        let mut codes: HashMap<(u8, u8), encoder::HuffmanCodes> = HashMap::new();
+        for (t_c, t_h) in tables.iter().copied() {
+            // progressive scans are always coded with optimal tables, as the example ones have no EOB runs
+            let code_lengths = if self.optimize_huffman_tables || progressive {
+                encoder::optimal_code_lengths(&encoder::symbol_frequencies(symbols.iter(), (t_c, t_h)))
+            } else {
+                let code_lengths = match (t_c, t_h) {
+                    (0, 0) => encoder::LUMINANCE_DC_CODE_LENGTHS,
+                    (1, 0) => encoder::LUMINANCE_AC_CODE_LENGTHS,
+                    (0, _) => encoder::CHROMINANCE_DC_CODE_LENGTHS,
+                    _ => encoder::CHROMINANCE_AC_CODE_LENGTHS,
+                };
+                code_lengths.map(|row| row.to_vec())
+            };
+            encoder::write_huffman_table(output, t_c, t_h, &code_lengths);
+            codes.insert((t_c, t_h), encoder::huffman_codes(&code_lengths));
+        }
+        codes
+    }
+
+    /// Writes the conditioning of the arithmetic coding tables, as (Tc, Tb), a scan uses, 10918-1, B.2.4.3, P. 40.
+    /// libjpeg writes it for every scan, even if it's the default, in order of the table, DC before AC.
+    fn write_arithmetic_conditioning(&self, output: &mut Vec<u8>, tables: &[(u8, u8)]) {
+        let mut tables = tables.to_vec();
+        tables.sort_by_key(|(t_c, t_b)| (*t_b, *t_c));
+        let mut parameters: Vec<u8> = Vec::new();
+        for (t_c, t_b) in tables {
+            let c_s = if t_c == 0 { self.arithmetic_conditioning.0 } else { self.arithmetic_conditioning.1 };
+            parameters.extend_from_slice(&[(t_c << 4) | t_b, c_s]);
+        }
+        encoder::write_segment(output, Marker::DAC, &parameters);
+    }
+
+    /// The arithmetic coded data of a sequential scan, F.1.4, P. 82, restarted every `restart_interval` MCUs
+    /// unless that's 0, with the statistics, the DC predictions and their contexts starting afresh in each interval
+    fn arithmetic_coded_scan(
+        &self,
+        scan_components: &[&EncoderComponent],
+        mcus: &[Mcu],
+        restart_interval: u16,
+    ) -> Vec<u8> {
+        let (dc_conditioning, k_x) = self.arithmetic_conditioning;
+        let interval_length = match restart_interval {
+            0 => mcus.len(),
+            restart_interval => restart_interval as usize,
+        };
+
+        let mut encoder = ArithmeticEncoder::new();
+        for (interval, interval_mcus) in mcus.chunks(interval_length).enumerate() {
+            if interval > 0 {
+                encoder.write_restart_marker(((interval - 1) % 8) as u8);
+            }
+            let mut dc_statistics: HashMap<u8, [u8; arithmetic::DC_STATISTICS]> = HashMap::new();
+            let mut ac_statistics: HashMap<u8, [u8; arithmetic::AC_STATISTICS]> = HashMap::new();
+            let mut predictions: Vec<i32> = vec![0; scan_components.len()];
+            let mut contexts: Vec<usize> = vec![0; scan_components.len()];
+            for (index, block) in interval_mcus.iter().flatten() {
+                let t_h = scan_components[*index].t_h;
+                arithmetic::encode_dc_difference(
+                    &mut encoder,
+                    dc_statistics.entry(t_h).or_insert([0; arithmetic::DC_STATISTICS]),
+                    &mut contexts[*index],
+                    dc_conditioning,
+                    block[0] - predictions[*index],
+                );
+                predictions[*index] = block[0];
+                arithmetic::encode_ac_coefficients(
+                    &mut encoder,
+                    ac_statistics.entry(t_h).or_insert([0; arithmetic::AC_STATISTICS]),
+                    k_x,
+                    block,
+                );
+            }
+        }
+        encoder.finish()
+    }
+
+    /// Converts the samples to YCbCr, for RGB images, then downsamples, transforms and quantizes each component
+    fn transform_components(&self, quantization_tables: &[[u16; 64]; 2]) -> Vec<EncoderComponent> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let planes: Vec<Vec<i32>> = if self.component_count == 3 {
+            let pixels: Vec<[i32; 3]> = self
+                .samples
+                .chunks_exact(3)
+                .map(|rgb| color::rgb_to_ycbcr(rgb[0] as i64, rgb[1] as i64, rgb[2] as i64, 8))
+                .collect();
+            (0..3).map(|c| pixels.iter().map(|pixel| pixel[c]).collect()).collect()
+        } else {
+            vec![self.samples.iter().map(|sample| *sample as i32).collect()]
+        };
+
+        let (h_max, v_max) = self.maximum_sampling_factors();
+
+        planes
+            .iter()
+            .enumerate()
+            .map(|(index, plane)| {
+                let (h_, v_) = if index == 0 { (h_max, v_max) } else { (1, 1) };
+                let blocks_wide = (width * h_ as usize).div_ceil(h_max as usize * 8);
+                let blocks_high = (height * v_ as usize).div_ceil(v_max as usize * 8);
+                let expansion = ((h_max / h_) as usize, (v_max / v_) as usize);
+                let samples = downsample(plane, (width, height), expansion, (blocks_wide, blocks_high));
+
+                let t_q = index.min(1) as u8;
+                let mut blocks: Vec<[i32; 64]> = Vec::with_capacity(blocks_wide * blocks_high);
+                for block_y in 0..blocks_high {
+                    for block_x in 0..blocks_wide {
+                        let block: [i32; 64] = core::array::from_fn(|i| {
+                            samples[(block_y * 8 + i / 8) * blocks_wide * 8 + block_x * 8 + i % 8] - 128
+                        });
+                        blocks.push(dct::quantize(&dct::fdct_islow(&block), &quantization_tables[t_q as usize]));
+                    }
+                }
+
+                EncoderComponent {
+                    c_: index as u8 + 1,
+                    h_,
+                    v_,
+                    t_q,
+                    t_h: t_q,
+                    blocks_wide,
+                    blocks_high,
+                    blocks,
+                }
+            })
+            .collect()
+    }
+
+    /// The number of MCUs in each row of a scan, and its MCUs. A scan of one component isn't interleaved,
+    /// so its MCUs are its blocks, 10918-1, A.2.2, P. 24, while the MCUs of interleaved scans can have blocks
+    /// past the edges of the components, A.2.4, P. 25, whose size is set by the frame's largest sampling factors.
+    fn scan_mcus(&self, scan_components: &[&EncoderComponent], (h_max, v_max): (u8, u8)) -> (usize, Vec<Mcu>) {
+        if let [component] = scan_components {
+            return (component.blocks_wide, component.blocks.iter().map(|block| vec![(0, *block)]).collect());
+        }
+
+        let mcus_wide = (self.width as usize).div_ceil(h_max as usize * 8);
+        let mcus_high = (self.height as usize).div_ceil(v_max as usize * 8);
+        let mut mcus: Vec<Mcu> = Vec::with_capacity(mcus_wide * mcus_high);
+        for mcu_y in 0..mcus_high {
+            for mcu_x in 0..mcus_wide {
+                let mut blocks: Mcu = Vec::new();
+                for (index, component) in scan_components.iter().enumerate() {
+                    let (h_, v_) = (component.h_ as usize, component.v_ as usize);
+                    for y in 0..v_ {
+                        for x in 0..h_ {
+                            let (block_x, block_y) = (mcu_x * h_ + x, mcu_y * v_ + y);
+                            if block_x < component.blocks_wide && block_y < component.blocks_high {
+                                blocks.push((index, component.blocks[block_y * component.blocks_wide + block_x]));
+                            } else {
+                                // blocks past the edge of the component that only fill out the MCU
+                                // just repeat the DC of the one before, as in libjpeg
+                                let mut dummy_block: [i32; 64] = [0; 64];
+                                dummy_block[0] = blocks.last().unwrap().1[0];
+                                blocks.push((index, dummy_block));
+                            }
+                        }
+                    }
+                }
+                mcus.push(blocks);
+            }
+        }
+        (mcus_wide, mcus)
+    }
+
+    /// The Huffman coded symbols of a scan, 10918-1, F.1.2, P. 88 for sequential scans and G.1.2, P. 122
+    /// for progressive ones, restarted every `restart_interval` MCUs unless that's 0
+    fn scan_symbols(
+        &self,
+        scan_components: &[&EncoderComponent],
+        mcus: Vec<Mcu>,
+        scan: &ProgressiveScan,
+        restart_interval: u16,
+    ) -> Vec<Symbol> {
+        let progressive = self.scan_script.is_some();
+        let mut symbols: Vec<Symbol> = Vec::new();
+        let mut predictions: Vec<i32> = vec![0; scan_components.len()];
+        let mut eob_run = EobRun::new(scan_components[0].t_h);
+
+        let restart_interval = restart_interval as usize;
+        for (mcu, blocks) in mcus.into_iter().enumerate() {
+            // the DC predictions and any EOB run start afresh in each restart interval, 10918-1, F.1.2.1.2, P. 88
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                eob_run.flush(&mut symbols);
+                predictions.fill(0);
+                symbols.push(Symbol::Restart((mcu / restart_interval - 1) as u8 % 8));
+            }
+            for (index, block) in blocks {
+                let t_h = scan_components[index].t_h;
+                match (progressive, scan.s_s, scan.a_h) {
+                    (false, _, _) => block_symbols(&block, &mut predictions[index], t_h, &mut symbols),
+                    // the point transform of DC coefficients is an arithmetic shift right, G.1.2.1, P. 122
+                    (true, 0, 0) => {
+                        let dc = block[0] >> scan.a_l;
+                        symbols.push(Symbol::value((0, t_h), 0, dc - predictions[index]));
+                        predictions[index] = dc;
+                    },
+                    (true, 0, _) => {
+                        symbols.push(Symbol::Bits { bits: ((block[0] >> scan.a_l) & 1) as u32, length: 1 })
+                    },
+                    (true, _, 0) => ac_first_symbols(&block, scan, &mut eob_run, &mut symbols),
+                    (true, _, _) => ac_refine_symbols(&block, scan, &mut eob_run, &mut symbols),
+                }
+            }
+        }
+        eob_run.flush(&mut symbols);
+        symbols
+    }
+
+    /// The sampling factors of the luma component, which has the largest, of color images
+    fn maximum_sampling_factors(&self) -> (u8, u8) {
+        match (self.component_count, self.chroma_subsampling) {
+            (1, _) | (_, ChromaSubsampling::Ratio444) => (1, 1),
+            (_, ChromaSubsampling::Ratio422) => (2, 1),
+            (_, ChromaSubsampling::Ratio420) => (2, 2),
+        }
+    }
+}
+
+/// Averages a component down by `expansion`, as libjpeg's jcsample.c does, with its alternating rounding,
+/// after repeating the last column and row of the full size samples so that the result covers whole blocks
+fn downsample(
+    plane: &[i32],
+    (width, height): (usize, usize),
+    (h_expansion, v_expansion): (usize, usize),
+    (blocks_wide, blocks_high): (usize, usize),
+) -> Vec<i32> {
+    let sample = |x: usize, y: usize| -> i32 { plane[y.min(height - 1) * width + x.min(width - 1)] };
+    // libjpeg pads the image to whole rows of the downsampled component, then repeats the last of those
+    let last_row = height.div_ceil(v_expansion) - 1;
+
+    let mut downsampled: Vec<i32> = Vec::with_capacity(blocks_wide * 8 * blocks_high * 8);
+    for output_y in 0..blocks_high * 8 {
+        let y = output_y.min(last_row) * v_expansion;
+        for output_x in 0..blocks_wide * 8 {
+            let x = output_x * h_expansion;
+            let bias = (output_x % 2) as i32;
+            downsampled.push(match (h_expansion, v_expansion) {
+                (1, 1) => sample(x, y),
+                (2, 1) => (sample(x, y) + sample(x + 1, y) + bias) >> 1,
+                (2, 2) => {
+                    (sample(x, y) + sample(x + 1, y) + sample(x, y + 1) + sample(x + 1, y + 1) + 1 + bias) >> 2
+                },
+                _ => panic!("Components can only be downsampled by half horizontally, or in both directions"),
+            });
+        }
+    }
+    downsampled
+}
+
+/// The symbols of one block of a sequential scan, in natural order, given the DC of the component's previous block,
+/// 10918-1, F.1.2.1, P. 88 and F.1.2.2, P. 89
+fn block_symbols(block: &[i32; 64], prediction: &mut i32, t_h: u8, symbols: &mut Vec<Symbol>) {
+    symbols.push(Symbol::value((0, t_h), 0, block[0] - *prediction));
+    *prediction = block[0];
+
+    let mut run: u8 = 0;
+    for k in 1..64 {
+        let coefficient = block[dct::ZIGZAG[k]];
+        if coefficient == 0 {
+            run += 1;
+            continue;
+        }
+        // ZRL, for each run of 16 zeros
+        while run > 15 {
+            symbols.push(Symbol::bare((1, t_h), 0xF0));
+            run -= 16;
+        }
+        symbols.push(Symbol::value((1, t_h), run, coefficient));
+        run = 0;
+    }
+    // EOB
+    if run > 0 {
+        symbols.push(Symbol::bare((1, t_h), 0x00));
+    }
+}
+
+/// The most correction bits that are buffered up during an EOB run, as in libjpeg
+const MAX_CORRECTION_BITS: usize = 1000;
+
+/// A run of blocks with no more coefficients in the band, 10918-1, G.1.2.2, P. 123,
+/// along with the correction bits of the refinement scan blocks in it, which follow its code
+struct EobRun {
+    t_h: u8,
+    length: u32,
+    correction_bits: Vec<u32>,
+}
+
+impl EobRun {
+    fn new(t_h: u8) -> Self {
+        Self { t_h, length: 0, correction_bits: Vec::new() }
+    }
+
+    /// Writes the run as EOBn, with the length less its most significant bit as the additional bits
+    fn flush(&mut self, symbols: &mut Vec<Symbol>) {
+        if self.length == 0 {
+            return;
+        }
+        let n = number_of_used_bits(&self.length) as u8 - 1;
+        symbols.push(Symbol::Coded {
+            table: (1, self.t_h),
+            symbol: n << 4,
+            additional_bits: self.length & ((1 << n) - 1),
+            additional_length: n,
+        });
+        symbols.extend(self.correction_bits.drain(..).map(|bit| Symbol::Bits { bits: bit, length: 1 }));
+        self.length = 0;
+    }
+}
+
+/// The symbols of the first scan of a band of AC coefficients of one block, G.1.2.2, P. 123, as libjpeg's jcphuff.c
+fn ac_first_symbols(block: &[i32; 64], scan: &ProgressiveScan, eob_run: &mut EobRun, symbols: &mut Vec<Symbol>) {
+    let mut run: u8 = 0;
+    for k in scan.s_s as usize..=scan.s_e as usize {
+        // the point transform of AC coefficients divides them, rounding towards 0
+        let coefficient = block[dct::ZIGZAG[k]];
+        let value = coefficient.signum() * (coefficient.abs() >> scan.a_l);
+        if value == 0 {
+            run += 1;
+            continue;
+        }
+        eob_run.flush(symbols);
+        while run > 15 {
+            symbols.push(Symbol::bare((1, eob_run.t_h), 0xF0));
+            run -= 16;
+        }
+        symbols.push(Symbol::value((1, eob_run.t_h), run, value));
+        run = 0;
+    }
+    if run > 0 {
+        eob_run.length += 1;
+        if eob_run.length == 0x7FFF {
+            eob_run.flush(symbols);
+        }
+    }
+}
+
+/// The symbols of a refinement scan of a band of AC coefficients of one block, G.1.2.3, P. 125, as libjpeg's jcphuff.c.
+/// Coefficients that become nonzero are coded like those of the first scan, with a sign bit in place of the magnitude,
+/// while those that were already nonzero get a correction bit, which is held back until the next coded symbol.
+fn ac_refine_symbols(block: &[i32; 64], scan: &ProgressiveScan, eob_run: &mut EobRun, symbols: &mut Vec<Symbol>) {
+    let magnitudes: Vec<i32> =
+        (scan.s_s as usize..=scan.s_e as usize).map(|k| block[dct::ZIGZAG[k]].abs() >> scan.a_l).collect();
+    // the last coefficient that becomes nonzero in this scan, past which runs of 16 are left to the EOB
+    let last_new = magnitudes.iter().rposition(|magnitude| *magnitude == 1);
+
+    let mut run: u8 = 0;
+    let mut correction_bits: Vec<u32> = Vec::new();
+    for (i, magnitude) in magnitudes.iter().enumerate() {
+        if *magnitude == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 && last_new.is_some_and(|last_new| i <= last_new) {
+            eob_run.flush(symbols);
+            symbols.push(Symbol::bare((1, eob_run.t_h), 0xF0));
+            run -= 16;
+            symbols.extend(correction_bits.drain(..).map(|bit| Symbol::Bits { bits: bit, length: 1 }));
+        }
+        if *magnitude > 1 {
+            correction_bits.push(*magnitude as u32 & 1);
+            continue;
+        }
+        eob_run.flush(symbols);
+        let sign = (block[dct::ZIGZAG[scan.s_s as usize + i]] > 0) as u32;
+        symbols.push(Symbol::Coded {
+            table: (1, eob_run.t_h),
+            symbol: (run << 4) | 1,
+            additional_bits: sign,
+            additional_length: 1,
+        });
+        symbols.extend(correction_bits.drain(..).map(|bit| Symbol::Bits { bits: bit, length: 1 }));
+        run = 0;
+    }
+    if run > 0 || !correction_bits.is_empty() {
+        eob_run.length += 1;
+        eob_run.correction_bits.append(&mut correction_bits);
+        if eob_run.length == 0x7FFF || eob_run.correction_bits.len() > MAX_CORRECTION_BITS - 64 + 1 {
+            eob_run.flush(symbols);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common");
+        path.push(name);
+        fs::read(path).unwrap()
+    }
+
+    fn encode(
+        input: &str,
+        component_count: usize,
+        quality: u8,
+        chroma_subsampling: ChromaSubsampling,
+        optimize_huffman_tables: bool,
+    ) -> Vec<u8> {
+        let samples: Vec<u32> = fixture(input).into_iter().map(|sample| sample as u32).collect();
+        let mut encoder = DctEncoder::new(samples, 37, 23, component_count);
+        encoder.set_quality(quality);
+        encoder.set_chroma_subsampling(chroma_subsampling);
+        encoder.set_optimize_huffman_tables(optimize_huffman_tables);
+        encoder.encode()
+    }
+
+    #[test]
+    fn scaled_quantization_tables() {
+        assert_eq!(scaled_quantization_table(&LUMINANCE_QUANTIZATION, 50), LUMINANCE_QUANTIZATION);
+        assert_eq!(scaled_quantization_table(&LUMINANCE_QUANTIZATION, 100), [1; 64]);
+        assert_eq!(scaled_quantization_table(&CHROMINANCE_QUANTIZATION, 1)[63], 255);
+        assert_eq!(scaled_quantization_table(&LUMINANCE_QUANTIZATION, 75)[..3], [8, 6, 5]);
+    }
+
+    // The expected images were written by libjpeg-turbo from the same samples and settings
+    #[test]
+    fn encode_color_as_libjpeg() {
+        use ChromaSubsampling::*;
+
+        assert_eq!(encode("dct_37x23.rgb", 3, 75, Ratio444, false), fixture("dct_444_q75.jpg"));
+        assert_eq!(encode("dct_37x23.rgb", 3, 75, Ratio422, false), fixture("dct_422_q75.jpg"));
+        assert_eq!(encode("dct_37x23.rgb", 3, 75, Ratio420, false), fixture("dct_420_q75.jpg"));
+        assert_eq!(encode("dct_37x23.rgb", 3, 10, Ratio444, false), fixture("dct_444_q10.jpg"));
+        assert_eq!(encode("dct_37x23.rgb", 3, 90, Ratio420, true), fixture("dct_420_q90_optimized.jpg"));
+    }
+
+    #[test]
+    fn encode_grayscale_as_libjpeg() {
+        // the chroma subsampling doesn't apply to a single component
+        let encoded = encode("dct_37x23.gray", 1, 50, ChromaSubsampling::Ratio420, false);
+        assert_eq!(encoded, fixture("dct_gray_q50.jpg"));
+        let encoded = encode("dct_37x23.gray", 1, 100, ChromaSubsampling::Ratio420, true);
+        assert_eq!(encoded, fixture("dct_gray_q100_optimized.jpg"));
+    }
+
+    #[test]
+    fn round_trip_at_full_quality() {
+        let samples: Vec<u32> = fixture("dct_37x23.rgb").into_iter().map(|sample| sample as u32).collect();
+        let mut encoder = DctEncoder::new(samples.clone(), 37, 23, 3);
+        encoder.set_quality(100);
+        encoder.set_chroma_subsampling(ChromaSubsampling::Ratio444);
+        let mut jpeg = crate::Jpeg::from_encoded_vec(encoder.encode());
+        jpeg.decode();
+
+        // only the rounding of the color conversions and the DCTs is lost
+        for (sample, decoded) in samples.iter().zip(jpeg.raw_image()) {
+            assert!(sample.abs_diff(*decoded) <= 3);
+        }
+    }
+
+    #[test]
+    fn encode_progressive_as_libjpeg() {
+        let encode_progressive = |input: &str, component_count: usize, quality: u8, chroma_subsampling| {
+            let samples: Vec<u32> = fixture(input).into_iter().map(|sample| sample as u32).collect();
+            let mut encoder = DctEncoder::new(samples, 37, 23, component_count);
+            encoder.set_quality(quality);
+            encoder.set_chroma_subsampling(chroma_subsampling);
+            encoder.set_progressive(true);
+            encoder.encode()
+        };
+
+        assert_eq!(
+            encode_progressive("dct_37x23.rgb", 3, 75, ChromaSubsampling::Ratio420),
+            fixture("dct_420_q75_progressive.jpg")
+        );
+        assert_eq!(
+            encode_progressive("dct_37x23.rgb", 3, 90, ChromaSubsampling::Ratio444),
+            fixture("dct_444_q90_progressive.jpg")
+        );
+        assert_eq!(
+            encode_progressive("dct_37x23.gray", 1, 75, ChromaSubsampling::Ratio444),
+            fixture("dct_gray_q75_progressive.jpg")
+        );
+    }
+
+    #[test]
+    fn encode_with_restarts_as_libjpeg() {
+        let encode_with_restarts = |input: &str, component_count: usize, rows: u16, progressive: bool| {
+            let samples: Vec<u32> = fixture(input).into_iter().map(|sample| sample as u32).collect();
+            let mut encoder = DctEncoder::new(samples, 37, 23, component_count);
+            encoder.set_restart_interval_in_rows(rows);
+            encoder.set_progressive(progressive);
+            encoder.encode()
+        };
+
+        assert_eq!(encode_with_restarts("dct_37x23.rgb", 3, 1, false), fixture("dct_420_q75_restart.jpg"));
+        // the non-interleaved scans of a progressive image have rows of blocks, and their own intervals
+        assert_eq!(
+            encode_with_restarts("dct_37x23.rgb", 3, 1, true),
+            fixture("dct_420_q75_progressive_restart.jpg")
+        );
+        assert_eq!(encode_with_restarts("dct_37x23.gray", 1, 2, false), fixture("dct_gray_q75_restart.jpg"));
+    }
+
+    #[test]
+    fn encode_arithmetic_coded_as_libjpeg() {
+        let encode_arithmetic_coded =
+            |input: &str, component_count: usize, quality: u8, chroma_subsampling, rows: u16, conditioned: bool| {
+                let samples: Vec<u32> = fixture(input).into_iter().map(|sample| sample as u32).collect();
+                let mut encoder = DctEncoder::new(samples, 37, 23, component_count);
+                encoder.set_quality(quality);
+                encoder.set_chroma_subsampling(chroma_subsampling);
+                encoder.set_restart_interval_in_rows(rows);
+                encoder.set_arithmetic_coding(true);
+                if conditioned {
+                    encoder.set_arithmetic_conditioning(1, 4, 12);
+                }
+                encoder.encode()
+            };
+
+        use ChromaSubsampling::*;
+        assert_eq!(
+            encode_arithmetic_coded("dct_37x23.rgb", 3, 75, Ratio420, 0, false),
+            fixture("dct_420_q75_arithmetic.jpg")
+        );
+        assert_eq!(
+            encode_arithmetic_coded("dct_37x23.rgb", 3, 90, Ratio444, 0, true),
+            fixture("dct_444_q90_arithmetic_conditioned.jpg")
+        );
+        assert_eq!(
+            encode_arithmetic_coded("dct_37x23.rgb", 3, 75, Ratio420, 1, false),
+            fixture("dct_420_q75_arithmetic_restart.jpg")
+        );
+        assert_eq!(
+            encode_arithmetic_coded("dct_37x23.gray", 1, 75, Ratio420, 1, false),
+            fixture("dct_gray_q75_arithmetic_restart.jpg")
+        );
+    }
+
+    #[test]
+    fn restart_interval_in_mcus_keeps_the_image() {
+        let samples: Vec<u32> = fixture("dct_37x23.rgb").into_iter().map(|sample| sample as u32).collect();
+        let mut encoder = DctEncoder::new(samples, 37, 23, 3);
+        let decode = |encoded: Vec<u8>| {
+            let mut jpeg = crate::Jpeg::from_encoded_vec(encoded);
+            jpeg.decode();
+            jpeg.raw_image().to_vec()
+        };
+        let expected = decode(encoder.encode());
+
+        for progressive in [false, true] {
+            encoder.set_progressive(progressive);
+            for mcus in [1, 2, 4, 7] {
+                encoder.set_restart_interval(mcus);
+                let encoded = encoder.encode();
+
+                assert_eq!(crate::Jpeg::probe(&encoded).unwrap().restart_interval, mcus);
+                assert_eq!(decode(encoded), expected, "restarted every {mcus} MCUs, progressive: {progressive}");
+            }
+        }
+    }
+
+    #[test]
+    fn custom_scan_script_keeps_the_coefficients() {
+        let samples: Vec<u32> = fixture("dct_37x23.rgb").into_iter().map(|sample| sample as u32).collect();
+        let mut encoder = DctEncoder::new(samples, 37, 23, 3);
+        encoder.set_quality(95);
+        let mut sequential = crate::Jpeg::from_encoded_vec(encoder.encode());
+
+        let scan = |component_order: &[u8], s_s: u8, s_e: u8, a_h: u8, a_l: u8| ProgressiveScan {
+            component_order: component_order.to_vec(),
+            s_s,
+            s_e,
+            a_h,
+            a_l,
+        };
+        // chroma DC on its own, and bands refined over several bits
+        encoder.set_scan_script(vec![
+            scan(&[1], 0, 0, 0, 2),
+            scan(&[2, 3], 0, 0, 0, 0),
+            scan(&[1], 1, 2, 0, 3),
+            scan(&[1], 3, 63, 0, 0),
+            scan(&[1], 0, 0, 2, 1),
+            scan(&[2], 1, 63, 0, 0),
+            scan(&[1], 1, 2, 3, 2),
+            scan(&[1], 1, 2, 2, 1),
+            scan(&[3], 1, 63, 0, 0),
+            scan(&[1], 0, 0, 1, 0),
+            scan(&[1], 1, 2, 1, 0),
+        ]);
+        let mut progressive = crate::Jpeg::from_encoded_vec(encoder.encode());
+
+        // only the blocks that fill out MCUs differ, since luma isn't in an interleaved scan here
+        let within_components = |jpeg: &mut crate::Jpeg| -> Vec<Vec<[i32; 64]>> {
+            jpeg.read_coefficients()
+                .into_iter()
+                .map(|component| {
+                    (0..component.blocks_high * component.padded_blocks_wide)
+                        .filter(|index| index % component.padded_blocks_wide < component.blocks_wide)
+                        .map(|index| component.blocks[index])
+                        .collect()
+                })
+                .collect()
+        };
+        assert_eq!(within_components(&mut progressive), within_components(&mut sequential));
+    }
+
+    #[test]
+    #[should_panic(expected = "Refinement scans must send the next bit")]
+    fn scan_script_refining_by_two_bits() {
+        let mut encoder = DctEncoder::new(vec![0; 64], 8, 8, 1);
+        encoder.set_scan_script(vec![
+            ProgressiveScan { component_order: vec![1], s_s: 0, s_e: 0, a_h: 0, a_l: 2 },
+            ProgressiveScan { component_order: vec![1], s_s: 0, s_e: 0, a_h: 2, a_l: 0 },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "A scan can't have a component more than once")]
+    fn scan_script_with_a_component_twice() {
+        let mut encoder = DctEncoder::new(vec![0; 64 * 3], 8, 8, 3);
+        encoder.set_scan_script(vec![ProgressiveScan { component_order: vec![1, 2, 2], s_s: 0, s_e: 0, a_h: 0, a_l: 0 }]);
+    }
+
+    #[test]
+    #[should_panic(expected = "A scan's components must be in the order they're in the frame")]
+    fn scan_script_out_of_frame_order() {
+        let mut encoder = DctEncoder::new(vec![0; 64 * 3], 8, 8, 3);
+        encoder.set_scan_script(vec![ProgressiveScan { component_order: vec![1, 3, 2], s_s: 0, s_e: 0, a_h: 0, a_l: 0 }]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Progressive images can't be arithmetic coded")]
+    fn progressive_arithmetic_coding() {
+        let mut encoder = DctEncoder::new(vec![0; 64], 8, 8, 1);
+        encoder.set_progressive(true);
+        encoder.set_arithmetic_coding(true);
+        encoder.encode();
+    }
+}
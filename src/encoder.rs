@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+
+use crate::jpeg_utils;
+use crate::Marker;
+
+/// A Huffman table for lossless differences, covering every SSSS category, 0 to 16.
+/// The codes for categories 0 to 11 are those of the example luminance DC table, 10918-1, K.3, P. 149,
+/// with the rest hung off its unused all 1s code so that none of them is all 1s either
+pub(crate) const DEFAULT_LOSSLESS_CODE_LENGTHS: [&[u8]; 16] = [
+    &[],
+    &[0],
+    &[1, 2, 3, 4, 5],
+    &[6],
+    &[7],
+    &[8],
+    &[9],
+    &[10],
+    &[11],
+    &[12],
+    &[13],
+    &[14],
+    &[15],
+    &[16],
+    &[],
+    &[],
+];
+
+/// The example Huffman table for luminance DC differences, 10918-1, Table K.3, P. 149
+pub(crate) const LUMINANCE_DC_CODE_LENGTHS: [&[u8]; 16] = [
+    &[],
+    &[0x00],
+    &[0x01, 0x02, 0x03, 0x04, 0x05],
+    &[0x06],
+    &[0x07],
+    &[0x08],
+    &[0x09],
+    &[0x0A],
+    &[0x0B],
+    &[],
+    &[],
+    &[],
+    &[],
+    &[],
+    &[],
+    &[],
+];
+
+/// The example Huffman table for luminance AC run lengths and sizes, 10918-1, Table K.5, P. 150
+pub(crate) const LUMINANCE_AC_CODE_LENGTHS: [&[u8]; 16] = [
+    &[],
+    &[0x01, 0x02],
+    &[0x03],
+    &[0x00, 0x04, 0x11],
+    &[0x05, 0x12, 0x21],
+    &[0x31, 0x41],
+    &[0x06, 0x13, 0x51, 0x61],
+    &[0x07, 0x22, 0x71],
+    &[0x14, 0x32, 0x81, 0x91, 0xA1],
+    &[0x08, 0x23, 0x42, 0xB1, 0xC1],
+    &[0x15, 0x52, 0xD1, 0xF0],
+    &[0x24, 0x33, 0x62, 0x72],
+    &[],
+    &[],
+    &[0x82],
+    &[
+        0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x34, 0x35, 0x36,
+        0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56,
+        0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76,
+        0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95,
+        0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3,
+        0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA,
+        0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7,
+        0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+];
+
+/// The example Huffman table for chrominance DC differences, 10918-1, Table K.4, P. 149
+pub(crate) const CHROMINANCE_DC_CODE_LENGTHS: [&[u8]; 16] = [
+    &[],
+    &[0x00, 0x01, 0x02],
+    &[0x03],
+    &[0x04],
+    &[0x05],
+    &[0x06],
+    &[0x07],
+    &[0x08],
+    &[0x09],
+    &[0x0A],
+    &[0x0B],
+    &[],
+    &[],
+    &[],
+    &[],
+    &[],
+];
+
+/// The example Huffman table for chrominance AC run lengths and sizes, 10918-1, Table K.6, P. 154
+pub(crate) const CHROMINANCE_AC_CODE_LENGTHS: [&[u8]; 16] = [
+    &[],
+    &[0x00, 0x01],
+    &[0x02],
+    &[0x03, 0x11],
+    &[0x04, 0x05, 0x21, 0x31],
+    &[0x06, 0x12, 0x41, 0x51],
+    &[0x07, 0x61, 0x71],
+    &[0x13, 0x22, 0x32, 0x81],
+    &[0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1],
+    &[0x09, 0x23, 0x33, 0x52, 0xF0],
+    &[0x15, 0x62, 0x72, 0xD1],
+    &[0x0A, 0x16, 0x24, 0x34],
+    &[],
+    &[0xE1],
+    &[0x25, 0xF1],
+    &[
+        0x17, 0x18, 0x19, 0x1A, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43,
+        0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63,
+        0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82,
+        0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+        0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7,
+        0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5,
+        0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3,
+        0xF4, 0xF5, 0xF6, 0xF7, 0xF8, 0xF9, 0xFA,
+    ],
+];
+
+/// The code and its length in bits of each symbol of a Huffman table
+pub(crate) type HuffmanCodes = HashMap<u8, (u32, u8)>;
+
+/// Writes entropy coded data a bit at a time, stuffing a zero byte after each 0xFF, 10918-1, F.1.2.3, P. 91
+pub(crate) struct BitWriter {
+    bytes: Vec<u8>,
+    byte: u8,
+    bit_count: u8, // bits in `byte` so far
+}
+
+impl BitWriter {
+    pub(crate) fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            byte: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Writes the low `length` bits of `value`, most significant first
+    pub(crate) fn write_bits(&mut self, value: u32, length: u8) {
+        for i in (0..length).rev() {
+            self.byte = (self.byte << 1) | ((value >> i) & 1) as u8;
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.bytes.push(self.byte);
+                if self.byte == 0xFF {
+                    self.bytes.push(0x00);
+                }
+                self.byte = 0;
+                self.bit_count = 0;
+            }
+        }
+    }
+
+    /// Writes a difference as the Huffman code of its SSSS category followed by its additional bits,
+    /// 10918-1, H.1.2.2, P. 136. The difference of 32768, category 16, has no additional bits.
+    pub(crate) fn write_difference(&mut self, codes: &HuffmanCodes, difference: i32) {
+        let ssss = ssss(difference);
+        let (code, length) = codes[&ssss];
+        self.write_bits(code, length);
+        if ssss < 16 {
+            self.write_bits(additional_bits(difference, ssss), ssss);
+        }
+    }
+
+    /// Writes symbols with the codes of their tables, keyed by table class and destination identifier
+    pub(crate) fn write_symbols<'a>(
+        &mut self,
+        symbols: impl Iterator<Item = &'a Symbol>,
+        codes: &HashMap<(u8, u8), HuffmanCodes>,
+    ) {
+        for symbol in symbols {
+            match symbol {
+                Symbol::Coded { table, symbol, additional_bits, additional_length } => {
+                    let (code, length) = codes[table][symbol];
+                    self.write_bits(code, length);
+                    self.write_bits(*additional_bits, *additional_length);
+                },
+                Symbol::Bits { bits, length } => self.write_bits(*bits, *length),
+                Symbol::Restart(m) => self.write_restart_marker(*m),
+            }
+        }
+    }
+
+    /// Ends a restart interval, padding its last byte and writing the restart marker RSTm, 10918-1, B.2.1, P. 34,
+    /// where `m` counts the intervals modulo 8
+    pub(crate) fn write_restart_marker(&mut self, m: u8) {
+        self.pad();
+        self.bytes.extend_from_slice(&(Marker::RST0 as u16 + (m % 8) as u16).to_be_bytes());
+    }
+
+    /// Pads the last byte with 1s, 10918-1, F.1.2.3, P. 91, and returns the coded bytes
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.pad();
+        self.bytes
+    }
+
+    /// Fills out the last byte with 1 bits, so that the next code starts on a byte boundary
+    fn pad(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0xFF, 8 - self.bit_count);
+        }
+    }
+}
+
+/// The SSSS category of a difference, the number of bits of its magnitude, Table H.2, P. 136;
+/// 32768 is category 16
+pub(crate) fn ssss(difference: i32) -> u8 {
+    if difference == 32768 {
+        16
+    } else {
+        jpeg_utils::number_of_used_bits(&difference.unsigned_abs()) as u8
+    }
+}
+
+/// The SSSS additional bits of a value, which for negative values are those of the value - 1,
+/// 10918-1, F.1.2.1, P. 88
+pub(crate) fn additional_bits(value: i32, ssss: u8) -> u32 {
+    let value = if value < 0 { value - 1 } else { value };
+    value as u32 & ((1 << ssss) - 1)
+}
+
+/// What's written to a scan's entropy coded data
+pub(crate) enum Symbol {
+    // a Huffman coded symbol, from the table given by its class and destination identifier,
+    // and the additional bits that follow it
+    Coded { table: (u8, u8), symbol: u8, additional_bits: u32, additional_length: u8 },
+    // bits that are written as they are, such as those that refine coefficients in progressive scans
+    Bits { bits: u32, length: u8 },
+    // the end of a restart interval, followed by RSTm
+    Restart(u8),
+}
+
+impl Symbol {
+    /// A value, coded as its SSSS category, with the run of zero coefficients before it in the high 4 bits
+    /// for AC coefficients, followed by its additional bits, 10918-1, F.1.2.1, P. 88 and F.1.2.2, P. 89
+    pub(crate) fn value(table: (u8, u8), run: u8, value: i32) -> Self {
+        let ssss = ssss(value);
+        Symbol::Coded {
+            table,
+            symbol: (run << 4) | ssss,
+            additional_bits: additional_bits(value, ssss),
+            additional_length: ssss,
+        }
+    }
+
+    /// A symbol with no additional bits, such as EOB or ZRL
+    pub(crate) fn bare(table: (u8, u8), symbol: u8) -> Self {
+        Symbol::Coded { table, symbol, additional_bits: 0, additional_length: 0 }
+    }
+}
+
+/// The number of times each symbol of a table occurs, to build an optimal table from
+pub(crate) fn symbol_frequencies<'a>(symbols: impl Iterator<Item = &'a Symbol>, table: (u8, u8)) -> [u32; 256] {
+    let mut frequencies: [u32; 256] = [0; 256];
+    for symbol in symbols {
+        if let Symbol::Coded { table: symbol_table, symbol, .. } = symbol {
+            if *symbol_table == table {
+                frequencies[*symbol as usize] += 1;
+            }
+        }
+    }
+    frequencies
+}
+
+/// Builds the Huffman table that codes symbols occurring with the given frequencies in the fewest bits,
+/// with no code longer than 16 bits or made of all 1s, 10918-1, K.2, P. 144, as libjpeg's
+/// jpeg_gen_optimal_table does. Returns the symbols of each code length, as a DHT segment lists them.
+pub(crate) fn optimal_code_lengths(frequencies: &[u32; 256]) -> [Vec<u8>; 16] {
+    // a reserved symbol with the least frequency takes the all 1s code, Figure K.1, P. 145
+    let mut frequency: Vec<u64> = frequencies.iter().map(|f| *f as u64).collect();
+    frequency.push(1);
+    let mut code_size: [usize; 257] = [0; 257];
+    let mut others: [Option<usize>; 257] = [None; 257];
+
+    // the least frequent symbol, the last of any that tie, other than `excluded`
+    let least_frequent = |frequency: &[u64], excluded: Option<usize>| -> Option<usize> {
+        let mut least: Option<usize> = None;
+        for (symbol, f) in frequency.iter().enumerate() {
+            if *f > 0 && Some(symbol) != excluded && least.is_none_or(|l| *f <= frequency[l]) {
+                least = Some(symbol);
+            }
+        }
+        least
+    };
+
+    // Join the two least frequent branches until there's one tree, lengthening the codes of both
+    while let (Some(v1), Some(v2)) = {
+        let v1 = least_frequent(&frequency, None);
+        (v1, least_frequent(&frequency, v1))
+    } {
+        frequency[v1] += frequency[v2];
+        frequency[v2] = 0;
+
+        let mut v = v1;
+        code_size[v] += 1;
+        while let Some(other) = others[v] {
+            v = other;
+            code_size[v] += 1;
+        }
+        others[v] = Some(v2);
+
+        let mut v = v2;
+        code_size[v] += 1;
+        while let Some(other) = others[v] {
+            v = other;
+            code_size[v] += 1;
+        }
+    }
+
+    // Figure K.2, P. 146
+    let mut bits: [u32; 33] = [0; 33];
+    for size in code_size.iter().filter(|size| **size > 0) {
+        assert!(*size <= 32, "Huffman codes can't be longer than 32 bits before they're limited to 16");
+        bits[*size] += 1;
+    }
+
+    // Shorten codes longer than 16 bits, two at a time, by moving a shorter code down the tree, Figure K.3, P. 147
+    for i in (17..=32).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    // and take the reserved symbol's code, the longest, back out
+    let longest = (1..=16).rev().find(|i| bits[*i] > 0).unwrap();
+    bits[longest] -= 1;
+
+    // Symbols are given codes in order of their code size, then their value, Figure K.4, P. 147
+    let mut symbols: Vec<u8> = (0..256).filter(|symbol| code_size[*symbol] > 0).map(|symbol| symbol as u8).collect();
+    symbols.sort_by_key(|symbol| code_size[*symbol as usize]);
+    let mut symbols = symbols.into_iter();
+    core::array::from_fn(|i| symbols.by_ref().take(bits[i + 1] as usize).collect())
+}
+
+/// The code and its length in bits of each symbol in a Huffman table, given as the symbols of each code length,
+/// worked out the same way the decoder does, 10918-1, C.2, P. 51
+pub(crate) fn huffman_codes(code_lengths: &[Vec<u8>; 16]) -> HuffmanCodes {
+    let (table, _, _) = jpeg_utils::make_ssss_table(code_lengths.clone());
+    table
+        .into_iter()
+        .map(|(code, symbol)| {
+            // the decoder's codes are preceded by a 1 so they can have leading zeros
+            let length = jpeg_utils::number_of_used_bits(&code) - 1;
+            (symbol, (code & ((1 << length) - 1), length as u8))
+        })
+        .collect()
+}
+
+/// Writes a marker followed by its segment's length and parameters, 10918-1, B.1.1.4, P. 33
+pub(crate) fn write_segment(output: &mut Vec<u8>, marker: Marker, parameters: &[u8]) {
+    output.extend_from_slice(&(marker as u16).to_be_bytes());
+    output.extend_from_slice(&(parameters.len() as u16 + 2).to_be_bytes());
+    output.extend_from_slice(parameters);
+}
+
+/// Writes a JFIF 1.01 APP0 segment, JFIF 1.02, P. 5, with a 1:1 pixel aspect ratio and no thumbnail
+pub(crate) fn write_jfif_segment(output: &mut Vec<u8>) {
+    write_segment(output, Marker::APP, &[b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0]);
+}
+
+/// Writes a DHT segment with one table, 10918-1, B.2.4.2, P. 40
+pub(crate) fn write_huffman_table(output: &mut Vec<u8>, t_c: u8, t_h: u8, code_lengths: &[Vec<u8>; 16]) {
+    let mut parameters: Vec<u8> = vec![(t_c << 4) | t_h];
+    parameters.extend(code_lengths.iter().map(|row| row.len() as u8));
+    code_lengths.iter().for_each(|row| parameters.extend_from_slice(row));
+    write_segment(output, Marker::DHT, &parameters);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_bits_stuffs_and_pads() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0xFF, 8);
+        writer.write_bits(0b010, 3);
+
+        assert_eq!(writer.finish(), [0xFF, 0x00, 0x5F]);
+    }
+
+    #[test]
+    fn write_restart_marker_pads_first() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b1, 1);
+        writer.write_restart_marker(9);
+        writer.write_bits(0b0, 1);
+        writer.write_restart_marker(2);
+        writer.write_restart_marker(3);
+
+        assert_eq!(writer.finish(), [0xFF, 0x00, 0xFF, 0xD1, 0x7F, 0xFF, 0xD2, 0xFF, 0xD3]);
+    }
+
+    #[test]
+    fn default_lossless_codes() {
+        let codes = huffman_codes(&DEFAULT_LOSSLESS_CODE_LENGTHS.map(|row| row.to_vec()));
+
+        assert_eq!(codes[&0], (0b00, 2));
+        assert_eq!(codes[&5], (0b110, 3));
+        assert_eq!(codes[&11], (0b111111110, 9));
+        assert_eq!(codes[&16], (0b11111111111110, 14));
+    }
+
+    #[test]
+    fn optimal_code_lengths_small() {
+        let mut frequencies: [u32; 256] = [0; 256];
+        frequencies[..4].copy_from_slice(&[4, 2, 1, 1]);
+        let code_lengths = optimal_code_lengths(&frequencies);
+
+        assert_eq!(code_lengths[..4], [vec![0], vec![1], vec![2], vec![3]]);
+        assert!(code_lengths[4..].iter().all(|row| row.is_empty()));
+    }
+
+    #[test]
+    fn optimal_code_lengths_limited_to_16_bits() {
+        // Fibonacci frequencies make a tree as deep as there are symbols
+        let mut frequencies: [u32; 256] = [0; 256];
+        let (mut a, mut b) = (1, 1);
+        for frequency in frequencies.iter_mut().take(24) {
+            *frequency = a;
+            (a, b) = (b, a + b);
+        }
+        let code_lengths = optimal_code_lengths(&frequencies);
+
+        let mut symbols: Vec<u8> = code_lengths.concat();
+        symbols.sort();
+        assert_eq!(symbols, (0..24).collect::<Vec<u8>>());
+        // room is left for the all 1s code
+        let kraft_sum: u32 = code_lengths.iter().enumerate().map(|(i, row)| row.len() as u32 * (1 << (15 - i))).sum();
+        assert!(kraft_sum < 1 << 16);
+    }
+}
@@ -0,0 +1,75 @@
+/// The sample at position `x` of a line that's been doubled in length, 10918-1, J.1.1.2, P. 150:
+/// even positions keep the reference's own samples, and odd ones get the average of the two on either side,
+/// rounded down, with the last sample repeated past the end of the line
+fn interpolate(line: &[i32], x: usize) -> i32 {
+    let left = line[x / 2];
+    if x.is_multiple_of(2) {
+        left
+    } else {
+        let right = line[(x / 2 + 1).min(line.len() - 1)];
+        (left + right) >> 1
+    }
+}
+
+/// Expands a reference component, of `width` by `height` samples, horizontally and/or vertically by two, as given by
+/// an EXP segment, 10918-1, B.3.3, P. 46, with horizontal expansion done first. The result is cropped
+/// to the size of the differential frame it's a reference for, since that might have an odd number of lines or samples.
+pub(crate) fn expand(
+    reference: &[u32],
+    (width, height): (usize, usize),
+    (e_h, e_v): (u8, u8),
+    (frame_width, frame_height): (usize, usize),
+) -> Vec<i32> {
+    let mut plane: Vec<i32> = reference.iter().map(|sample| *sample as i32).collect();
+    let mut width = width;
+    let mut height = height;
+
+    if e_h == 1 {
+        plane = plane
+            .chunks(width)
+            .flat_map(|line| (0..2 * width).map(move |x| interpolate(line, x)))
+            .collect();
+        width *= 2;
+    }
+    if e_v == 1 {
+        let mut expanded: Vec<i32> = Vec::with_capacity(plane.len() * 2);
+        for y in 0..2 * height {
+            for x in 0..width {
+                let column: [i32; 2] = [
+                    plane[(y / 2) * width + x],
+                    plane[(y / 2 + 1).min(height - 1) * width + x],
+                ];
+                expanded.push(interpolate(&column, y % 2));
+            }
+        }
+        plane = expanded;
+        height *= 2;
+    }
+
+    assert!(
+        width >= frame_width && height >= frame_height,
+        "A reference component must be at least as large as the differential frame once expanded"
+    );
+    plane
+        .chunks(width)
+        .take(frame_height)
+        .flat_map(|line| line[..frame_width].iter().copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_horizontally_and_vertically() {
+        let reference: [u32; 4] = [10, 21, 30, 40];
+
+        assert_eq!(expand(&reference, (2, 2), (1, 0), (4, 2)), [10, 15, 21, 21, 30, 35, 40, 40]);
+        assert_eq!(expand(&reference, (2, 2), (0, 1), (2, 4)), [10, 21, 20, 30, 30, 40, 30, 40]);
+        assert_eq!(
+            expand(&reference, (2, 2), (1, 1), (3, 3)),
+            [10, 15, 21, 20, 25, 30, 30, 35, 40]
+        );
+    }
+}
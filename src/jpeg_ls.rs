@@ -0,0 +1,480 @@
+use crate::jpeg_utils::{self, ContextContext, ScanReader};
+
+/// The run lengths' order, J, indexed by RUNindex, ITU T.87, A.7.1.1, P. 21
+const J: [u32; 32] = [
+    0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+/// The bias correction values' limits, MIN_C and MAX_C, T.87, A.6.2, P. 19
+const MIN_C: i32 = -128;
+const MAX_C: i32 = 127;
+
+/// The coding parameters given by an LSE segment with ID 1, T.87, C.2.4.1.1, P. 32.
+/// Zeros stand for the defaults.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PresetParameters {
+    pub(crate) maxval: u16,
+    pub(crate) t1: u16,
+    pub(crate) t2: u16,
+    pub(crate) t3: u16,
+    pub(crate) reset: u16,
+}
+
+/// The parameters a scan is coded with, T.87, A.2.1, P. 10
+pub(crate) struct Parameters {
+    maxval: i32,
+    near: i32,
+    range: i32,
+    qbpp: u32,
+    limit: u32,
+    t1: i32,
+    t2: i32,
+    t3: i32,
+    reset: i32,
+}
+
+/// Takes `value` unless it's outside of `low..=high`, in which case it's `low`, T.87, C.2.4.1.1.1, P. 33
+fn bounded(value: i32, low: i32, high: i32) -> i32 {
+    if value > high || value < low {
+        low
+    } else {
+        value
+    }
+}
+
+impl Parameters {
+    pub(crate) fn new(p_: u8, near: u8, presets: &PresetParameters) -> Self {
+        let maxval: i32 = match presets.maxval {
+            0 => (1 << p_) - 1,
+            maxval => maxval as i32,
+        };
+        let near = near as i32;
+        let range = (maxval + 2 * near) / (2 * near + 1) + 1;
+        let qbpp = jpeg_utils::number_of_used_bits(&(range as u32 - 1)) as u32;
+        let bpp = (jpeg_utils::number_of_used_bits(&(maxval as u32)) as u32).max(2);
+        let limit = 2 * (bpp + bpp.max(8));
+
+        // the default thresholds, C.2.4.1.1.1, P. 33, from the basic ones, 3, 7 and 21
+        let (t1, t2, t3) = if maxval >= 128 {
+            let factor = (maxval.min(4095) + 128) / 256;
+            let t1 = bounded(factor + 2 + 3 * near, near + 1, maxval);
+            let t2 = bounded(factor * 4 + 3 + 5 * near, t1, maxval);
+            (t1, t2, bounded(factor * 17 + 4 + 7 * near, t2, maxval))
+        } else {
+            let factor = 256 / (maxval + 1);
+            let t1 = bounded((3 / factor + 3 * near).max(2), near + 1, maxval);
+            let t2 = bounded((7 / factor + 5 * near).max(3), t1, maxval);
+            (t1, t2, bounded((21 / factor + 7 * near).max(4), t2, maxval))
+        };
+
+        Self {
+            maxval,
+            near,
+            range,
+            qbpp,
+            limit,
+            t1: if presets.t1 == 0 { t1 } else { presets.t1 as i32 },
+            t2: if presets.t2 == 0 { t2 } else { presets.t2 as i32 },
+            t3: if presets.t3 == 0 { t3 } else { presets.t3 as i32 },
+            reset: if presets.reset == 0 { 64 } else { presets.reset as i32 },
+        }
+    }
+
+    /// Quantizes a local gradient into one of 9 regions, T.87, A.3.3, P. 12
+    fn quantize_gradient(&self, d: i32) -> i32 {
+        if d <= -self.t3 {
+            -4
+        } else if d <= -self.t2 {
+            -3
+        } else if d <= -self.t1 {
+            -2
+        } else if d < -self.near {
+            -1
+        } else if d <= self.near {
+            0
+        } else if d < self.t1 {
+            1
+        } else if d < self.t2 {
+            2
+        } else if d < self.t3 {
+            3
+        } else {
+            4
+        }
+    }
+
+    /// Adds a dequantized prediction error to a prediction, reducing the result modulo the range
+    /// and clamping it to the sample range, T.87, A.4.2 & A.5.2, P. 14-15
+    fn reconstruct(&self, prediction: i32, errval: i32) -> i32 {
+        let mut sample = prediction + errval * (2 * self.near + 1);
+        if sample < -self.near {
+            sample += self.range * (2 * self.near + 1);
+        } else if sample > self.maxval + self.near {
+            sample -= self.range * (2 * self.near + 1);
+        }
+        sample.clamp(0, self.maxval)
+    }
+}
+
+/// The variables of one of the 365 regular mode contexts, T.87, A.2.2, P. 10
+#[derive(Clone)]
+struct Context {
+    a: i32, // Accumulated prediction error magnitudes
+    b: i32, // Bias
+    c: i32, // Prediction correction
+    n: i32, // Occurrences
+}
+
+/// The variables of the two run interruption contexts, T.87, A.7.2, P. 23
+struct RunContext {
+    a: i32,
+    n: i32,
+    nn: i32, // Negative prediction errors
+    ri_type: i32,
+}
+
+struct Decoder<'a, 'b> {
+    parameters: &'a Parameters,
+    contexts: Vec<Context>,
+    run_contexts: [RunContext; 2],
+    image_bits: &'a mut ScanReader<'b>,
+}
+
+impl Decoder<'_, '_> {
+    fn read_bits(&mut self, count: u32) -> i32 {
+        let mut value: i32 = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.image_bits.next().expect("Ran out of JPEG-LS coded bits") as i32;
+        }
+        value
+    }
+
+    /// Decodes a limited length Golomb code, LG(k, glimit), T.87, A.5.3, P. 17
+    fn decode_value(&mut self, k: u32, limit: u32) -> i32 {
+        let mut high_bits: u32 = 0;
+        while self.read_bits(1) == 0 {
+            high_bits += 1;
+        }
+        if high_bits >= limit - self.parameters.qbpp - 1 {
+            self.read_bits(self.parameters.qbpp) + 1
+        } else {
+            ((high_bits as i32) << k) + self.read_bits(k)
+        }
+    }
+
+    /// Decodes a sample in regular mode, T.87, A.4 to A.6, P. 13-19.
+    /// `q` is the context number, before merging the contexts of opposite signs.
+    fn decode_regular(&mut self, q: i32, prediction: i32) -> i32 {
+        let sign = if q < 0 { -1 } else { 1 };
+        let index = q.unsigned_abs() as usize;
+        let Context { a, b, c, n } = self.contexts[index];
+
+        let mut k: u32 = 0;
+        while (n << k) < a {
+            k += 1;
+        }
+        let prediction = (prediction + sign * c).clamp(0, self.parameters.maxval);
+
+        let m_errval = self.decode_value(k, self.parameters.limit);
+        // inverse of the error mapping, A.5.2, P. 15, including the one for k = 0 and a negative bias
+        let mut errval = if m_errval % 2 == 0 { m_errval / 2 } else { -(m_errval + 1) / 2 };
+        if k == 0 && self.parameters.near == 0 && 2 * b + n - 1 < 0 {
+            errval = -errval - 1;
+        }
+        self.update_context(index, errval);
+
+        self.parameters.reconstruct(prediction, sign * errval)
+    }
+
+    /// Updates a regular mode context's variables and bias correction, T.87, A.6, P. 18
+    fn update_context(&mut self, index: usize, errval: i32) {
+        let reset = self.parameters.reset;
+        let near = self.parameters.near;
+        let context = &mut self.contexts[index];
+        context.b += errval * (2 * near + 1);
+        context.a += errval.abs();
+        if context.n == reset {
+            context.a >>= 1;
+            context.b >>= 1;
+            context.n >>= 1;
+        }
+        context.n += 1;
+
+        if context.b <= -context.n {
+            context.b += context.n;
+            if context.c > MIN_C {
+                context.c -= 1;
+            }
+            if context.b <= -context.n {
+                context.b = -context.n + 1;
+            }
+        } else if context.b > 0 {
+            context.b -= context.n;
+            if context.c < MAX_C {
+                context.c += 1;
+            }
+            if context.b > 0 {
+                context.b = 0;
+            }
+        }
+    }
+
+    /// Decodes the length of a run of `available` or fewer samples, T.87, A.7.1, P. 21
+    fn decode_run_length(&mut self, run_index: &mut usize, available: usize) -> usize {
+        let mut length: usize = 0;
+        while length < available && self.read_bits(1) == 1 {
+            let count = (1usize << J[*run_index]).min(available - length);
+            length += count;
+            if count == 1 << J[*run_index] {
+                *run_index = (*run_index + 1).min(31);
+            }
+        }
+        if length < available {
+            // the run was interrupted, and the rest of its length is given in J[RUNindex] bits
+            length += self.read_bits(J[*run_index]) as usize;
+            assert!(length <= available, "JPEG-LS run past the end of the line");
+        }
+        length
+    }
+
+    /// Decodes the prediction error of a run interruption sample, T.87, A.7.2, P. 23
+    fn decode_run_interruption_error(&mut self, ri_type: usize, run_index: usize) -> i32 {
+        let RunContext { a, n, nn, .. } = self.run_contexts[ri_type];
+        let ri_type_value = self.run_contexts[ri_type].ri_type;
+        let temp = a + (n >> 1) * ri_type_value;
+        let mut k: u32 = 0;
+        while (n << k) < temp {
+            k += 1;
+        }
+
+        let em_errval = self.decode_value(k, self.parameters.limit - J[run_index] - 1);
+        let map_value = em_errval + ri_type_value;
+        let map = map_value & 1;
+        let errval_magnitude = (map_value + map) / 2;
+        let errval = if (k != 0 || 2 * nn >= n) == (map == 1) {
+            -errval_magnitude
+        } else {
+            errval_magnitude
+        };
+
+        let context = &mut self.run_contexts[ri_type];
+        if errval < 0 {
+            context.nn += 1;
+        }
+        context.a += (em_errval + 1 - ri_type_value) >> 1;
+        if context.n == self.parameters.reset {
+            context.a >>= 1;
+            context.n >>= 1;
+            context.nn >>= 1;
+        }
+        context.n += 1;
+        errval
+    }
+}
+
+/// Sign(n) of T.87, which is never zero
+fn sign(n: i32) -> i32 {
+    if n < 0 {
+        -1
+    } else {
+        1
+    }
+}
+
+/// The median edge detecting predictor, T.87, A.4.1, P. 13
+fn predict(r_a: i32, r_b: i32, r_c: i32) -> i32 {
+    if r_c >= r_a.max(r_b) {
+        r_a.min(r_b)
+    } else if r_c <= r_a.min(r_b) {
+        r_a.max(r_b)
+    } else {
+        r_a + r_b - r_c
+    }
+}
+
+/// The neighbouring samples Ra, Rb, Rc and Rd of a sample on the current line, T.87, A.2.1, P. 10,
+/// along with the edge rules of A.2.1: the line above the first is all zeros, Ra and Rc
+/// of the first sample of a line are its Rb and the Rb of the first sample of the line before,
+/// and Rd of the last sample is its Rb.
+fn neighbours(context: &ContextContext, first_r_b: i32) -> (i32, i32, i32, i32) {
+    let r_b = context.r_b();
+    let (r_a, r_c) = if context.x_position == 0 {
+        (r_b, first_r_b)
+    } else {
+        (context.r_a(), context.r_c())
+    };
+    let r_d = if context.x_position + 1 == context.width { r_b } else { context.r_d() };
+    (r_a, r_b, r_c, r_d)
+}
+
+/// Decodes a JPEG-LS scan, T.87, A, P. 9, of `component_count` components, which are interleaved
+/// by line (ILV 1) or by sample (ILV 2), or there's only one (ILV 0). The context variables are shared
+/// by the components, and each gets its own RUNindex, other than with sample interleaving.
+/// Each line of samples, interleaved by component, is handed to `on_line`.
+pub(crate) fn decode_scan(
+    image_bits: &mut ScanReader,
+    parameters: &Parameters,
+    (width, height): (usize, usize),
+    component_count: usize,
+    interleave: u8,
+    on_line: &mut dyn FnMut(usize, &[u32]),
+) {
+    let a_init = ((parameters.range + 32) / 64).max(2);
+    let mut decoder = Decoder {
+        parameters,
+        contexts: vec![Context { a: a_init, b: 0, c: 0, n: 1 }; 365],
+        run_contexts: [
+            RunContext { a: a_init, n: 1, nn: 0, ri_type: 0 },
+            RunContext { a: a_init, n: 1, nn: 0, ri_type: 1 },
+        ],
+        image_bits,
+    };
+    let mut run_indices: Vec<usize> = vec![0; component_count];
+    // the Rb of each component's first sample on the previous line, for the Rc of the first on this line
+    let mut first_r_bs: Vec<i32> = vec![0; component_count];
+
+    // the previous line, starting out as zeros, then the current one
+    let row_length = width * component_count;
+    let mut rows: Vec<u32> = vec![0; 2 * row_length];
+
+    for y in 0..height {
+        if interleave == 2 {
+            decode_sample_interleaved_line(&mut decoder, &mut rows, width, component_count, &mut run_indices[0], &mut first_r_bs);
+        } else {
+            for component in 0..component_count {
+                decode_component_line(
+                    &mut decoder,
+                    &mut rows,
+                    width,
+                    (component, component_count),
+                    &mut run_indices[component],
+                    &mut first_r_bs[component],
+                );
+            }
+        }
+        on_line(y, &rows[row_length..]);
+        rows.copy_within(row_length.., 0);
+    }
+}
+
+fn context_at<'a>(rows: &'a Vec<u32>, x: usize, width: usize, (component, component_count): (usize, usize)) -> ContextContext<'a> {
+    ContextContext {
+        component,
+        x_position: x,
+        y_position: 1,
+        width,
+        component_count,
+        p_t: 0,
+        p_: 0,
+        img: rows,
+    }
+}
+
+/// Decodes one line of one component, for scans that aren't sample interleaved
+fn decode_component_line(
+    decoder: &mut Decoder,
+    rows: &mut Vec<u32>,
+    width: usize,
+    (component, component_count): (usize, usize),
+    run_index: &mut usize,
+    first_r_b: &mut i32,
+) {
+    let row_length = width * component_count;
+    let first_r_c = *first_r_b;
+    *first_r_b = rows[component] as i32;
+
+    let mut x: usize = 0;
+    while x < width {
+        let (r_a, r_b, r_c, r_d) = neighbours(&context_at(rows, x, width, (component, component_count)), first_r_c);
+        let parameters = decoder.parameters;
+        let q1 = parameters.quantize_gradient(r_d - r_b);
+        let q2 = parameters.quantize_gradient(r_b - r_c);
+        let q3 = parameters.quantize_gradient(r_c - r_a);
+
+        if q1 == 0 && q2 == 0 && q3 == 0 {
+            // run mode, A.7, P. 20
+            let length = decoder.decode_run_length(run_index, width - x);
+            for run_x in x..x + length {
+                rows[row_length + run_x * component_count + component] = r_a as u32;
+            }
+            x += length;
+            if x == width {
+                break;
+            }
+            let r_b = rows[x * component_count + component] as i32;
+            let sample = if (r_a - r_b).abs() <= parameters.near {
+                let errval = decoder.decode_run_interruption_error(1, *run_index);
+                parameters.reconstruct(r_a, errval)
+            } else {
+                let errval = decoder.decode_run_interruption_error(0, *run_index);
+                parameters.reconstruct(r_b, errval * sign(r_b - r_a))
+            };
+            rows[row_length + x * component_count + component] = sample as u32;
+            *run_index = run_index.saturating_sub(1);
+        } else {
+            let q = 81 * q1 + 9 * q2 + q3;
+            let sample = decoder.decode_regular(q, predict(r_a, r_b, r_c));
+            rows[row_length + x * component_count + component] = sample as u32;
+        }
+        x += 1;
+    }
+}
+
+/// Decodes one line of a sample interleaved scan, where run mode is only used when it could be
+/// for every component, and run interruption samples always use the first run interruption context
+fn decode_sample_interleaved_line(
+    decoder: &mut Decoder,
+    rows: &mut Vec<u32>,
+    width: usize,
+    component_count: usize,
+    run_index: &mut usize,
+    first_r_bs: &mut [i32],
+) {
+    let row_length = width * component_count;
+    let first_r_cs: Vec<i32> = first_r_bs.to_vec();
+    for (component, first_r_b) in first_r_bs.iter_mut().enumerate() {
+        *first_r_b = rows[component] as i32;
+    }
+
+    let mut x: usize = 0;
+    while x < width {
+        let parameters = decoder.parameters;
+        let neighbourhoods: Vec<(i32, i32, i32, i32)> = (0..component_count)
+            .map(|component| neighbours(&context_at(rows, x, width, (component, component_count)), first_r_cs[component]))
+            .collect();
+        let contexts: Vec<i32> = neighbourhoods
+            .iter()
+            .map(|(r_a, r_b, r_c, r_d)| {
+                81 * parameters.quantize_gradient(r_d - r_b)
+                    + 9 * parameters.quantize_gradient(r_b - r_c)
+                    + parameters.quantize_gradient(r_c - r_a)
+            })
+            .collect();
+
+        if contexts.iter().all(|q| *q == 0) {
+            let length = decoder.decode_run_length(run_index, width - x);
+            for run_x in x..x + length {
+                for (component, (r_a, ..)) in neighbourhoods.iter().enumerate() {
+                    rows[row_length + run_x * component_count + component] = *r_a as u32;
+                }
+            }
+            x += length;
+            if x == width {
+                break;
+            }
+            for (component, (r_a, ..)) in neighbourhoods.iter().enumerate() {
+                let r_b = rows[x * component_count + component] as i32;
+                let errval = decoder.decode_run_interruption_error(0, *run_index);
+                rows[row_length + x * component_count + component] =
+                    parameters.reconstruct(r_b, errval * sign(r_b - r_a)) as u32;
+            }
+            *run_index = run_index.saturating_sub(1);
+        } else {
+            for (component, ((r_a, r_b, r_c, _), q)) in neighbourhoods.iter().zip(contexts).enumerate() {
+                let sample = decoder.decode_regular(q, predict(*r_a, *r_b, *r_c));
+                rows[row_length + x * component_count + component] = sample as u32;
+            }
+        }
+        x += 1;
+    }
+}
@@ -1,7 +1,6 @@
-use std::collections::{BTreeMap, HashMap};
-use std::slice::Iter;
+use std::collections::HashMap;
 
-use crate::{HeaderParameter, Jpeg, ScanHeader, SSSSTable};
+use crate::{HeaderParameter, Jpeg, Marker, ScanHeader, SSSSTable};
 
 pub(crate) struct ContextContext<'a> {
     pub(crate) component: usize,
@@ -11,7 +10,7 @@ pub(crate) struct ContextContext<'a> {
     pub(crate) component_count: usize,
     pub(crate) p_t: u8,
     pub(crate) p_: u8, // Sample precision
-    pub(crate) img: &'a Vec<u32>,
+    pub(crate) img: &'a [u32],
 }
 
 impl ContextContext<'_> {
@@ -30,61 +29,181 @@ impl ContextContext<'_> {
             + (self.y_position - 1) * self.width * self.component_count
             + self.component] as i32
     }
-    pub(crate) fn r_ix(&self) -> i32 {
-        1 << (self.p_ - self.p_t - 1) as i32
+    pub(crate) fn r_d(&self) -> i32 {
+        self.img[(self.x_position + 1) * self.component_count
+            + (self.y_position - 1) * self.width * self.component_count
+            + self.component] as i32
+    }
+}
+
+/// Reads the entropy coded data of a scan out of the encoded image as it's needed, a byte at a time
+/// for arithmetic coding and a bit at a time otherwise, so none of it is copied. The zero byte stuffed
+/// after each 0xFF is dropped, 10918-1, B.1.1.5, P. 33, or for JPEG-LS the zero bit stuffed at the top
+/// of the byte after it, T.87, A.1, P. 9. The data ends at a marker, or the end of the image.
+#[derive(Clone)]
+pub(crate) struct ScanReader<'a> {
+    encoded_image: &'a [u8],
+    index: usize,   // Of the next byte
+    byte: u8,       // The byte being read a bit at a time
+    bits_left: u32, // Bits of it not read yet
+    jpeg_ls: bool,
+}
+
+impl<'a> ScanReader<'a> {
+    pub(crate) fn new(encoded_image: &'a [u8], index: usize) -> Self {
+        Self {
+            encoded_image,
+            index,
+            byte: 0,
+            bits_left: 0,
+            jpeg_ls: false,
+        }
+    }
+
+    pub(crate) fn new_jpeg_ls(encoded_image: &'a [u8], index: usize) -> Self {
+        Self {
+            jpeg_ls: true,
+            ..Self::new(encoded_image, index)
+        }
+    }
+
+    /// Whether the next byte starts a marker, an 0xFF followed by anything but a stuffed zero byte,
+    /// or in JPEG-LS by a byte with its top bit set
+    fn at_marker(&self) -> bool {
+        self.encoded_image[self.index] == 0xFF
+            && self
+                .encoded_image
+                .get(self.index + 1)
+                .is_some_and(|next| if self.jpeg_ls { *next >= 0x80 } else { *next != 0 })
+    }
+
+    /// Reads the next byte of coded data, if the data hasn't ended
+    pub(crate) fn next_byte(&mut self) -> Option<u8> {
+        if self.index >= self.encoded_image.len() || self.at_marker() {
+            return None;
+        }
+        let byte = self.encoded_image[self.index];
+        let length = if byte == 0xFF && !self.jpeg_ls { 2 } else { 1 };
+        self.index = (self.index + length).min(self.encoded_image.len());
+        Some(byte)
+    }
+
+    /// Moves on to the next marker, or to the end of the image, looking at the bytes in between only to find it.
+    /// Any fill bytes, 0xFF, before the marker are skipped, B.1.1.2, P. 31.
+    fn skip_to_marker(&mut self) {
+        while let Some(offset) = self.encoded_image[self.index..].iter().position(|byte| *byte == 0xFF) {
+            self.index += offset;
+            if self.at_marker() {
+                while self.encoded_image.get(self.index + 1) == Some(&0xFF) {
+                    self.index += 1;
+                }
+                return;
+            }
+            self.index += 1;
+        }
+        self.index = self.encoded_image.len();
+    }
+
+    /// Whether the next marker is a restart marker, RSTm
+    fn at_restart_marker(&self) -> bool {
+        let restart_markers = Marker::RST0 as u16..=Marker::RST7 as u16;
+        self.encoded_image
+            .get(self.index..self.index + 2)
+            .is_some_and(|marker| restart_markers.contains(&u16::from_be_bytes([marker[0], marker[1]])))
+    }
+
+    /// Moves on to the next restart interval, skipping whatever's left of this one and the RSTm marker
+    /// that ends it, B.2.1, P. 34. Skipped data is only looked at for its 0xFF bytes.
+    pub(crate) fn next_interval(&mut self) {
+        self.skip_to_marker();
+        assert!(self.at_restart_marker(), "Missing restart interval");
+        self.index += 2;
+        self.byte = 0;
+        self.bits_left = 0;
+    }
+
+    /// Skips whatever's left of the scan, along with any restart intervals after it,
+    /// returning the index of the marker that ends it
+    pub(crate) fn end_of_scan(mut self) -> usize {
+        self.skip_to_marker();
+        while self.at_restart_marker() {
+            self.index += 2;
+            self.skip_to_marker();
+        }
+        self.index
+    }
+}
+
+/// The bits of the coded data, from the top bit of each byte down
+impl Iterator for ScanReader<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.bits_left == 0 {
+            let after_0xff = self.byte == 0xFF;
+            self.byte = self.next_byte()?;
+            self.bits_left = if self.jpeg_ls && after_0xff { 7 } else { 8 };
+        }
+        self.bits_left -= 1;
+        Some((self.byte >> self.bits_left) & 1)
     }
 }
 
 pub(crate) fn get_huffmaned_value(
     ssss_table: &SSSSTable,
-    image_bits: &mut Iter<u8>,
+    image_bits: &mut impl Iterator<Item = u8>,
 ) -> i32 {
-    let mut ssss: u8 = 0xFF;
+    match get_huffmaned_symbol(ssss_table, image_bits) {
+        16 => 32768,
+        ssss => receive_extend(ssss, image_bits),
+    }
+}
+
+/// Reads bits until they match one of the table's Huffman codes, returning the coded value
+pub(crate) fn get_huffmaned_symbol(
+    ssss_table: &SSSSTable,
+    image_bits: &mut impl Iterator<Item = u8>,
+) -> u8 {
     let mut guess: u32 = 1;
 
     for _ in 0..ssss_table.min_code_length - 1 {
-        guess = (guess << 1) | (*image_bits.next().unwrap() as u32);
+        guess = (guess << 1) | (image_bits.next().unwrap() as u32);
     }
 
     // TODO: seems like it should be min_code..max_code, or something like that
     for _ in 0..ssss_table.max_code_length {
-        guess = (guess << 1) | (*image_bits.next().unwrap() as u32);
-        if ssss_table.table.contains_key(&guess) {
-            ssss = ssss_table.table[&guess];
-            break;
+        guess = (guess << 1) | (image_bits.next().unwrap() as u32);
+        if let Some(symbol) = ssss_table.table.get(&guess) {
+            return *symbol;
         }
     }
 
-    match ssss {
-        0xFF => {
-            // if no code is matched return a zero, this was said to be the safest somewhere
-            // TODO: should if break or be error resistant? also goes for down below
-            // panic!("No matching Huffman code was found for a lossless tile jpeg.")
-            // warnings.warn('A Huffman coding error was found in a lossless jpeg in a dng; it may'
-            //               + ' have been resolved, there may be corrupted data')
-            panic!("bad huffmaned code!");
-        }
-        16 => 32768,
-        _ => {
-            let mut pixel_diff: u16 = 0;
-            if ssss > 0 {
-                let first_bit = *image_bits.next().unwrap();
-                // TODO: seems like the "(pixel_diff << 1) |" is unnecessary
-                pixel_diff = (pixel_diff << 1) | (first_bit as u16);
-                // step thru the remainder of the ssss number of bits to get the coded number
-                for _ in 0..ssss - 1 {
-                    pixel_diff = (pixel_diff << 1) | (*image_bits.next().unwrap() as u16);
-                }
-                // if the first read bit is 0 the number is negative and has to be calculated
-                if first_bit == 0 {
-                    -(((1 << ssss) - (pixel_diff + 1)) as i32)
-                } else {
-                    pixel_diff as i32
-                }
-            } else {
-                0
-            }
-        }
+    // if no code is matched return a zero, this was said to be the safest somewhere
+    // TODO: should if break or be error resistant? also goes for down below
+    // panic!("No matching Huffman code was found for a lossless tile jpeg.")
+    // warnings.warn('A Huffman coding error was found in a lossless jpeg in a dng; it may'
+    //               + ' have been resolved, there may be corrupted data')
+    panic!("bad huffmaned code!");
+}
+
+/// Reads the next `ssss` bits as an unsigned number, 10918-1, F.2.2.4, P. 110, (RECEIVE)
+pub(crate) fn receive(ssss: u8, image_bits: &mut impl Iterator<Item = u8>) -> i32 {
+    let mut value: i32 = 0;
+    for _ in 0..ssss {
+        value = (value << 1) | (image_bits.next().unwrap() as i32);
+    }
+    value
+}
+
+/// Reads the `ssss` additional bits that follow a Huffman code and turns them into a signed value,
+/// 10918-1, F.2.2.1, P. 104, (RECEIVE and EXTEND)
+pub(crate) fn receive_extend(ssss: u8, image_bits: &mut impl Iterator<Item = u8>) -> i32 {
+    let pixel_diff = receive(ssss, image_bits);
+    // if the first read bit is 0 the number is negative and has to be calculated
+    if ssss > 0 && pixel_diff < 1 << (ssss - 1) {
+        pixel_diff - (1 << ssss) + 1
+    } else {
+        pixel_diff
     }
 }
 
@@ -112,7 +231,11 @@ pub(crate) fn make_prediciton(
     predict(context, predictor)
 }
 
-fn predict(context: ContextContext, mut predictor: u8) -> u32 {
+pub(crate) fn predict(context: ContextContext, mut predictor: u8) -> u32 {
+    // no prediction, even at the edges, for the differential frames of hierarchical images, Table H.1, P. 133
+    if predictor == 0 {
+        return 0;
+    }
     if context.x_position == 0 {
         if context.y_position == 0 {
             predictor = 8;
@@ -140,13 +263,14 @@ pub(crate) fn parse_scan_header(image: &mut Jpeg) -> ScanHeader {
     let _l_s: u16 = image.bytes_to_int_two_consumed();
     let n_s: usize = image.byte_to_int_one_consumed() as usize;
     let mut head_params: HashMap<u8, HeaderParameter> = HashMap::new();
+    let mut component_order: Vec<u8> = Vec::with_capacity(n_s);
     for _ in 0..n_s {
         let c_s: u8 = image.byte_to_int_one_consumed();
+        component_order.push(c_s);
         let t_d_a: u8 = image.byte_to_int_one_consumed();
         head_params.insert(
             c_s,
             HeaderParameter {
-                c_s,
                 t_d: t_d_a >> 4,
                 t_a: t_d_a & 0xF,
             },
@@ -160,6 +284,7 @@ pub(crate) fn parse_scan_header(image: &mut Jpeg) -> ScanHeader {
 
     ScanHeader {
         head_params,
+        component_order,
         s_s,
         s_e,
         a_h,
@@ -169,7 +294,7 @@ pub(crate) fn parse_scan_header(image: &mut Jpeg) -> ScanHeader {
 
 /// TODO: this algerythom presumably doesn't work for all possible tables
 // fn make_ssss_table(code_lengths: [[u8; 16]; 16]) -> (HashMap<u32, u8>, usize, usize) {
-pub(crate) fn make_ssss_table(code_lengths: [[Option<u8>; 16]; 16]) -> (HashMap<u32, u8>, usize, usize) {
+pub(crate) fn make_ssss_table(code_lengths: [Vec<u8>; 16]) -> (HashMap<u32, u8>, usize, usize) {
     // https://www.youtube.com/watch?v=dM6us854Jk0
 
     // Codes start towards the top left of the tree
@@ -237,18 +362,13 @@ pub(crate) fn make_ssss_table(code_lengths: [[Option<u8>; 16]; 16]) -> (HashMap<
     // the code is preceided by a 1 so there can be leading zeros
     let mut code: u32 = 1;
     let mut table: HashMap<u32, u8> = HashMap::new();
-    for (index, row) in code_lengths.iter().enumerate() {
+    for (index, values) in code_lengths.into_iter().enumerate() {
         // the code lengths (number of bytes) are stored in a HashMap that was initized with 0xFF
         // and the codes only go up to 16,
         // so if the first cell has 0xFF then there are no codes with a length
         // equal to that row's index
         // so remove the rows that still have the initial value, 0xFF
         // since, as previously discussed, there aren't any codes of that length
-
-        // probably slower than the following but it's cleaner so... if row[0].is_some() {
-        // filter out the values that have 0xFF since those are initial values
-        // and don't have a valid code length
-        let values = row.iter().filter_map(|x| *x).collect::<Vec<u8>>();
         if !values.is_empty() {
             // for each code lengh start with the 0th code of that length
             let mut values_w_n_bits: usize = 0;
@@ -303,14 +423,89 @@ pub(crate) fn make_ssss_table(code_lengths: [[Option<u8>; 16]; 16]) -> (HashMap<
     (table, min_code_length, max_code_length)
 }
 
-pub(crate) fn is_jpeg(bytes: &[u8]) -> bool {
-    if u16::from_be_bytes([bytes[0], bytes[1]]) == 0xFFD8 as u16 {
-        true
-    } else {
-        false
+/// Copies SOI and the frame, Huffman table, quantization table, restart interval, hierarchical progression
+/// and first scan header segments
+/// into a new, small, buffer, skipping everything else, and stopping once the first
+/// scan header has been copied. See 10918-1, B.1.1.4, P. 32 for the segment layout.
+/// Returns None if the image ends before the first scan header, or a copied segment doesn't hold its fields.
+pub(crate) fn extract_header_segments(encoded_image: &[u8]) -> Option<Vec<u8>> {
+    let mut header: Vec<u8> = Vec::from([0xFF, 0xD8]);
+    let mut index: usize = 2;
+    while index + 1 < encoded_image.len() {
+        if encoded_image[index] != 0xFF || encoded_image[index + 1] == 0xFF {
+            // not a marker, or fill bytes preceding one
+            index += 1;
+            continue;
+        }
+        let marker = u16::from_be_bytes([encoded_image[index], encoded_image[index + 1]]);
+        match marker {
+            // markers without a length or contents
+            0xFF01 | 0xFFD0..=0xFFD9 => {
+                index += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let length = u16::from_be_bytes([*encoded_image.get(index + 2)?, *encoded_image.get(index + 3)?]) as usize;
+        let segment_end = index + 2 + length;
+        if length < 2 || segment_end > encoded_image.len() {
+            return None;
+        }
+        let is_frame_header = (0xFFC0..=0xFFCF).contains(&marker)
+            && marker != 0xFFC4
+            && marker != 0xFFC8
+            && marker != 0xFFCC
+            || marker == 0xFFF7;
+        if is_frame_header || [0xFFC4, 0xFFDB, 0xFFDD, 0xFFDE, 0xFFE0, 0xFFEE, 0xFFDA].contains(&marker) {
+            if !holds_its_fields(marker, &encoded_image[index + 4..segment_end]) {
+                return None;
+            }
+            header.extend_from_slice(&encoded_image[index..segment_end]);
+        }
+        if marker == 0xFFDA {
+            return Some(header);
+        }
+        index = segment_end;
+    }
+
+    None
+}
+
+/// Whether the contents of a header segment, after its length, are as long as its fields say,
+/// so parsing it doesn't read past its end: Lf = 8 + 3 × Nf for frame headers and DHP, B.2.2, P. 35,
+/// Ls = 6 + 2 × Ns, B.2.3, P. 35, Lr = 4, B.2.4.4, P. 43, and whole tables in DQT, B.2.4.1, P. 39,
+/// and DHT, B.2.4.2, P. 40. Application segments can hold anything.
+fn holds_its_fields(marker: u16, contents: &[u8]) -> bool {
+    match marker {
+        0xFFDA => contents.first().is_some_and(|n_s| contents.len() == 4 + 2 * *n_s as usize),
+        0xFFDD => contents.len() == 2,
+        0xFFDB => {
+            let mut index = 0;
+            while index < contents.len() {
+                let p_q = (contents[index] >> 4) as usize;
+                index += 1 + 64 * (1 + p_q);
+            }
+            index == contents.len()
+        },
+        0xFFC4 => {
+            let mut index = 0;
+            while index < contents.len() {
+                match contents.get(index + 1..index + 17) {
+                    Some(l_i) => index += 17 + l_i.iter().map(|l| *l as usize).sum::<usize>(),
+                    None => return false,
+                }
+            }
+            index == contents.len()
+        },
+        0xFFE0 | 0xFFEE => true,
+        _ => contents.get(5).is_some_and(|n_f| contents.len() == 6 + 3 * *n_f as usize),
     }
 }
 
+pub(crate) fn is_jpeg(bytes: &[u8]) -> bool {
+    u16::from_be_bytes([bytes[0], bytes[1]]) == 0xFFD8
+}
+
 pub(crate) fn number_of_used_bits(numb: &u32) -> usize {
     (32 - numb.leading_zeros()) as usize
 }
@@ -338,7 +533,7 @@ mod tests {
             max_code_length: 8,
         };
         let image_bits: Vec<u8> = Vec::from([0, 0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
-        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
         assert_eq!(pixel_diff, 0);
     }
 
@@ -362,7 +557,7 @@ mod tests {
             max_code_length: 8,
         };
         let image_bits: Vec<u8> = Vec::from([0, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
-        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
         assert_eq!(pixel_diff, 1);
     }
 
@@ -386,7 +581,7 @@ mod tests {
             max_code_length: 8,
         };
         let image_bits: Vec<u8> = Vec::from([0, 1, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
-        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
         assert_eq!(pixel_diff, -1);
     }
 
@@ -410,7 +605,7 @@ mod tests {
             max_code_length: 8,
         };
         let image_bits: Vec<u8> = Vec::from([1, 0, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
-        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
         assert_eq!(pixel_diff, 3);
     }
 
@@ -434,7 +629,7 @@ mod tests {
             max_code_length: 8,
         };
         let image_bits: Vec<u8> = Vec::from([1, 0, 0, 1, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
-        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
         assert_eq!(pixel_diff, -2);
     }
 
@@ -458,7 +653,7 @@ mod tests {
             max_code_length: 16,
         };
         let image_bits: Vec<u8> = Vec::from([1, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
-        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+        let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
         assert_eq!(pixel_diff, 32768);
     }
 
@@ -485,7 +680,7 @@ mod tests {
     //     let image_bits: Vec<u8> = Vec::from([
     //         1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 1, 0, 1,
     //     ]);
-    //     let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter());
+    //     let pixel_diff = get_huffmaned_value(&ssss_table, &mut image_bits.iter().copied());
     //     println!("{:?}", pixel_diff);
     //     assert_eq!(
     //         pixel_diff.unwrap_err().to_string(),
@@ -672,7 +867,8 @@ mod tests {
             (62, 5),
         ]);
 
-        let (tables, min_code_length, max_code_length) = make_ssss_table(code_lengths);
+        let (tables, min_code_length, max_code_length) =
+            make_ssss_table(code_lengths.map(|row| row.into_iter().flatten().collect()));
 
         assert_eq!(tables, expected);
         assert_eq!(min_code_length, 2);
@@ -764,21 +960,146 @@ mod tests {
 
         let expected = HashMap::from([(8, 0), (9, 1), (10, 2), (11, 3), (12, 4), (13, 5), (14, 6)]);
 
-        let (tables, min_code_length, max_code_length) = make_ssss_table(code_lengths);
+        let (tables, min_code_length, max_code_length) =
+            make_ssss_table(code_lengths.map(|row| row.into_iter().flatten().collect()));
 
         assert_eq!(tables, expected);
         assert_eq!(min_code_length, 3);
         assert_eq!(max_code_length, 3);
     }
 
+    #[test]
+    fn scan_reader_regular_number_then_marker() {
+        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00, 0x05, 0xFF, 0xDA]);
+        let expected_bits: Vec<u8> = Vec::from([
+            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
+            1, 1, 1, 0, 0, 0, 0, 0, 1, 0, 1,
+        ]);
+
+        let mut reader = ScanReader::new(&encoded_image, 0);
+        let actual_bits: Vec<u8> = reader.by_ref().collect();
+
+        assert_eq!(actual_bits, expected_bits);
+        assert_eq!(actual_bits.len(), 40);
+        let read_index = reader.end_of_scan();
+        assert_eq!(encoded_image[read_index], 0xFF);
+        assert_eq!(encoded_image[read_index + 1], 0xDA);
+    }
+
+    #[test]
+    fn scan_reader_padding_then_marker() {
+        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00, 0xFF, 0xDA]);
+        let expected_bits: Vec<u8> = Vec::from([
+            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
+            1, 1, 1,
+        ]);
+
+        let mut reader = ScanReader::new(&encoded_image, 0);
+        let actual_bits: Vec<u8> = reader.by_ref().collect();
+
+        assert_eq!(actual_bits, expected_bits);
+        assert_eq!(actual_bits.len(), 32);
+        let read_index = reader.end_of_scan();
+        assert_eq!(encoded_image[read_index], 0xFF);
+        assert_eq!(encoded_image[read_index + 1], 0xDA);
+    }
+
+    #[test]
+    fn scan_reader_regular_number_with_no_marker() {
+        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00, 0x05]);
+        let expected_bits: Vec<u8> = Vec::from([
+            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
+            1, 1, 1, 0, 0, 0, 0, 0, 1, 0, 1,
+        ]);
+
+        let mut reader = ScanReader::new(&encoded_image, 0);
+        let actual_bits: Vec<u8> = reader.by_ref().collect();
+
+        assert_eq!(actual_bits, expected_bits);
+        assert_eq!(actual_bits.len(), 40);
+        assert_eq!(reader.end_of_scan(), 6);
+    }
+
+    #[test]
+    fn scan_reader_padding_with_no_marker() {
+        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00]);
+        let expected_bits: Vec<u8> = Vec::from([
+            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
+            1, 1, 1,
+        ]);
+
+        let mut reader = ScanReader::new(&encoded_image, 0);
+        let actual_bits: Vec<u8> = reader.by_ref().collect();
+
+        assert_eq!(actual_bits, expected_bits);
+        assert_eq!(actual_bits.len(), 32);
+        assert_eq!(reader.end_of_scan(), 5);
+    }
+
+    #[test]
+    fn scan_reader_restart_intervals() {
+        // fill bytes before the second RSTm, and the rest of an interval left unread
+        let encoded_image: Vec<u8> = Vec::from([0xA5, 0xFF, 0xD0, 0xFF, 0x00, 0x3C, 0xFF, 0xFF, 0xD1, 0x81, 0xFF, 0xD9]);
+
+        let mut reader = ScanReader::new(&encoded_image, 0);
+        assert_eq!(reader.next_byte(), Some(0xA5));
+        assert_eq!(reader.next_byte(), None);
+        reader.next_interval();
+        assert_eq!(reader.next(), Some(1));
+        reader.next_interval();
+        assert_eq!(reader.next_byte(), Some(0x81));
+        assert_eq!(reader.end_of_scan(), 10);
+
+        let reader = ScanReader::new(&encoded_image, 0);
+        assert_eq!(reader.end_of_scan(), 10);
+    }
+
+    #[test]
+    fn scan_reader_jpeg_ls_bit_stuffing() {
+        // the bit stuffed at the top of the byte after 0xFF is dropped, and 0xFF 0x80 is a marker
+        let encoded_image: Vec<u8> = Vec::from([0xFF, 0x7F, 0xFF, 0x80]);
+
+        let mut reader = ScanReader::new_jpeg_ls(&encoded_image, 0);
+        let actual_bits: Vec<u8> = reader.by_ref().collect();
+
+        assert_eq!(actual_bits, vec![1; 15]);
+        assert_eq!(reader.end_of_scan(), 2);
+    }
+
+    #[test]
+    fn make_ssss_tables_more_than_16_codes_of_a_length() {
+        let mut code_lengths: [Vec<u8>; 16] = Default::default();
+        code_lengths[1] = Vec::from([0xAA]);
+        code_lengths[3] = (0..12).collect();
+
+        let (tables, min_code_length, max_code_length) = make_ssss_table(code_lengths);
+
+        let mut expected = HashMap::from([(4, 0xAA)]);
+        for value in 0..12 {
+            expected.insert(20 + value as u32, value);
+        }
+        assert_eq!(tables, expected);
+        assert_eq!(min_code_length, 2);
+        assert_eq!(max_code_length, 4);
+    }
+
+    #[test]
+    fn receive_extend_positive_and_negative() {
+        let image_bits: Vec<u8> = Vec::from([1, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(receive_extend(3, &mut image_bits.iter().copied()), 5);
+        assert_eq!(receive_extend(3, &mut image_bits[3..].iter().copied()), -5);
+        assert_eq!(receive_extend(15, &mut image_bits[1..].iter().copied()), -32767 + 0b010100000000000);
+        assert_eq!(receive_extend(0, &mut image_bits.iter().copied()), 0);
+    }
+
     use super::*;#[test]
     fn test_is_jpeg_passing() {
-        assert!(is_jpeg(&vec![0xFF, 0xD8]));
+        assert!(is_jpeg(&[0xFF, 0xD8]));
     }
 
     #[test]
     fn test_is_jpeg_failing() {
-        assert!(!is_jpeg(&vec![0xFF, 0x00]));
+        assert!(!is_jpeg(&[0xFF, 0x00]));
     }
 
     #[test]
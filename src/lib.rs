@@ -4,25 +4,262 @@ use std::fs;
 use std::path::PathBuf;
 
 // mod trials;
+mod arithmetic;
+mod color;
+mod dct;
+mod dct_encoder;
+mod encoder;
+mod hierarchical;
+mod jpeg_ls;
 mod jpeg_utils;
+mod lossless_encoder;
+mod transform;
+mod upsampling;
 
+pub use dct_encoder::DctEncoder;
+pub use lossless_encoder::LosslessEncoder;
+pub use transform::Transformer;
+
+#[allow(clippy::upper_case_acronyms)]
 enum Marker {
-    SOF0 = 0xFFC0, // Baseline DCT
-    SOF3 = 0xFFC3, // Lossless Huffman Encoding
-    DHT = 0xFFC4,  // Define Huffman table(s)
-    SOI = 0xFFD8,  // Start of image
-    EOI = 0xFFD9,  // End of image
-    SOS = 0xFFDA,  // Start of scan
-    DQT = 0xFFDB,  // Define quantization table(s)
-    APP = 0xFFE0,  //Reserved for application segments
-    APPn = 0xFFEF, //Reserved for application segments
+    SOF0 = 0xFFC0,  // Baseline DCT
+    SOF1 = 0xFFC1,  // Extended sequential DCT, Huffman coding
+    SOF2 = 0xFFC2,  // Progressive DCT, Huffman coding
+    SOF3 = 0xFFC3,  // Lossless Huffman Encoding
+    DHT = 0xFFC4,   // Define Huffman table(s)
+    SOF5 = 0xFFC5,  // Differential sequential DCT, Huffman coding
+    SOF6 = 0xFFC6,  // Differential progressive DCT, Huffman coding
+    SOF7 = 0xFFC7,  // Differential lossless, Huffman coding
+    SOF9 = 0xFFC9,  // Extended sequential DCT, arithmetic coding
+    SOF10 = 0xFFCA, // Progressive DCT, arithmetic coding
+    SOF11 = 0xFFCB, // Lossless, arithmetic coding
+    DAC = 0xFFCC,   // Define arithmetic coding conditioning(s)
+    SOF13 = 0xFFCD, // Differential sequential DCT, arithmetic coding
+    SOF14 = 0xFFCE, // Differential progressive DCT, arithmetic coding
+    SOF15 = 0xFFCF, // Differential lossless, arithmetic coding
+    TEM = 0xFF01,   // For temporary private use in arithmetic coding
+    RST0 = 0xFFD0,  // Restart with modulo 8 count 0
+    RST7 = 0xFFD7,  // Restart with modulo 8 count 7
+    SOI = 0xFFD8,   // Start of image
+    EOI = 0xFFD9,   // End of image
+    SOS = 0xFFDA,   // Start of scan
+    DQT = 0xFFDB,   // Define quantization table(s)
+    DRI = 0xFFDD,   // Define restart interval
+    DHP = 0xFFDE,   // Define hierarchical progression
+    EXP = 0xFFDF,   // Expand reference component(s)
+    APP = 0xFFE0,   //Reserved for application segments
+    APP14 = 0xFFEE, // Adobe's application segment
+    APPn = 0xFFEF,  //Reserved for application segments
+    COM = 0xFFFE,   // Comment
+    SOF55 = 0xFFF7, // JPEG-LS, ITU T.87
+    LSE = 0xFFF8,   // JPEG-LS preset parameters
+}
+
+/// The coding process of a frame, as identified by its SOF marker, 10918-1, B.1.1.3, P. 32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodingProcess {
+    BaselineDct,
+    ExtendedSequentialDct,
+    ProgressiveDct,
+    Lossless,
+    DifferentialSequentialDct,
+    DifferentialProgressiveDct,
+    DifferentialLossless,
+    ExtendedSequentialDctArithmetic,
+    ProgressiveDctArithmetic,
+    LosslessArithmetic,
+    DifferentialSequentialDctArithmetic,
+    DifferentialProgressiveDctArithmetic,
+    DifferentialLosslessArithmetic,
+    JpegLs,
+}
+
+impl CodingProcess {
+    fn from_marker(marker: u16) -> Option<Self> {
+        use CodingProcess::*;
+        match marker {
+            0xFFC0 => Some(BaselineDct),
+            0xFFC1 => Some(ExtendedSequentialDct),
+            0xFFC2 => Some(ProgressiveDct),
+            0xFFC3 => Some(Lossless),
+            0xFFC5 => Some(DifferentialSequentialDct),
+            0xFFC6 => Some(DifferentialProgressiveDct),
+            0xFFC7 => Some(DifferentialLossless),
+            0xFFC9 => Some(ExtendedSequentialDctArithmetic),
+            0xFFCA => Some(ProgressiveDctArithmetic),
+            0xFFCB => Some(LosslessArithmetic),
+            0xFFCD => Some(DifferentialSequentialDctArithmetic),
+            0xFFCE => Some(DifferentialProgressiveDctArithmetic),
+            0xFFCF => Some(DifferentialLosslessArithmetic),
+            0xFFF7 => Some(JpegLs),
+            _ => None,
+        }
+    }
+
+    fn is_dct(&self) -> bool {
+        !matches!(
+            self,
+            CodingProcess::Lossless
+                | CodingProcess::DifferentialLossless
+                | CodingProcess::LosslessArithmetic
+                | CodingProcess::DifferentialLosslessArithmetic
+                | CodingProcess::JpegLs
+        )
+    }
+
+    fn is_progressive(&self) -> bool {
+        matches!(
+            self,
+            CodingProcess::ProgressiveDct
+                | CodingProcess::DifferentialProgressiveDct
+                | CodingProcess::ProgressiveDctArithmetic
+                | CodingProcess::DifferentialProgressiveDctArithmetic
+        )
+    }
+
+    fn is_differential(&self) -> bool {
+        matches!(
+            self,
+            CodingProcess::DifferentialSequentialDct
+                | CodingProcess::DifferentialProgressiveDct
+                | CodingProcess::DifferentialLossless
+                | CodingProcess::DifferentialSequentialDctArithmetic
+                | CodingProcess::DifferentialProgressiveDctArithmetic
+                | CodingProcess::DifferentialLosslessArithmetic
+        )
+    }
+
+    fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            CodingProcess::ExtendedSequentialDctArithmetic
+                | CodingProcess::ProgressiveDctArithmetic
+                | CodingProcess::LosslessArithmetic
+                | CodingProcess::DifferentialSequentialDctArithmetic
+                | CodingProcess::DifferentialProgressiveDctArithmetic
+                | CodingProcess::DifferentialLosslessArithmetic
+        )
+    }
+}
+
+/// The color space of a frame's components, going by the JFIF or Adobe APP14 segment,
+/// or failing those the number of components and their identifiers, as libjpeg does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Grayscale,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    InvertedCmyk, // Adobe's CMYK, where 0 is full ink
+    Ycck,         // Adobe's YCbCr of inverted C, M and Y, and inverted K
+    Other,        // 2 components, or more than 4
+}
+
+/// How subsampled components are brought up to the size of the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upsampling {
+    Nearest, // each sample is repeated
+    Fancy,   // libjpeg's triangle filter, for components at half the width, height or both
+}
+
+/// Which IDCT to transform full size DCT blocks with, each as libjpeg's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdctMethod {
+    AccurateInteger, // islow, which libjpeg-turbo's output can be matched exactly with
+    FastInteger,     // ifast, the AAN algorithm with 8 bit multipliers, and less accurate
+    Float,           // float, the AAN algorithm in single precision
+}
+
+/// How color images are sampled by `DctEncoder`, with the chroma components at the full resolution
+/// or at half of it, with the luma component's sampling factors doubled to match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    Ratio444, // chroma at full resolution
+    Ratio422, // chroma at half the width
+    Ratio420, // chroma at half the width and half the height
+}
+
+/// One scan of a progressive image written by `DctEncoder`, with the same fields as its scan header,
+/// 10918-1, G.1.1, P. 119
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressiveScan {
+    pub component_order: Vec<u8>, // identifiers of the components in the scan: 1 for Y or gray, 2 for Cb, 3 for Cr
+    pub s_s: u8,                  // Start of spectral selection
+    pub s_e: u8,                  // End of spectral selection
+    pub a_h: u8,                  // Successive approximation bit position high, 0 for the first scan of a band
+    pub a_l: u8,                  // Successive approximation bit position low
+}
+
+/// How `Transformer` rearranges the blocks of a DCT image, as jpegtran's -flip, -transpose, -transverse and -rotate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    FlipHorizontal, // mirrored left to right
+    FlipVertical,   // mirrored top to bottom
+    Transpose,      // across the diagonal from the top left corner
+    Transverse,     // across the diagonal from the top right corner
+    Rotate90,       // clockwise
+    Rotate180,
+    Rotate270, // clockwise, or 90 counterclockwise
+}
+
+/// What can be learned about an image from its headers alone, see `Jpeg::probe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub width: u16,
+    pub height: u16,
+    pub precision: u8,
+    pub component_count: usize,
+    pub coding_process: CodingProcess,
+    pub huffman_tables: Vec<(u8, u8)>, // (Table class, destination identifier) of each Huffman table
+    pub restart_interval: u16,         // MCUs per restart interval; 0 if restart markers aren't used
+    pub predictor: u8,                 // Ss of the first scan; the predictor selector in lossless
+    pub point_transform: u8,           // Al of the first scan; Pt in lossless
+    pub hierarchical: bool,            // whether there's a DHP segment, in which case the size is the final image's
+    pub color_space: ColorSpace,       // the color space the components are coded in
+    pub quantization_tables: HashMap<u8, QuantiziationTable>, // by destination identifier; empty for lossless
+}
+
+/// The reconstructed output of one frame of a hierarchical image, 10918-1, J.1, P. 149
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HierarchicalLevel {
+    pub width: u16,
+    pub height: u16,
+    pub component_identifiers: Vec<u8>, // the frame's components, in the order their samples are interleaved
+    pub samples: Vec<u32>,
+}
+
+impl HierarchicalLevel {
+    /// The samples of one of the level's components
+    fn component_samples(&self, c_: u8) -> Vec<u32> {
+        let index = self.component_identifiers.iter().position(|c| *c == c_).unwrap();
+        self.samples
+            .iter()
+            .skip(index)
+            .step_by(self.component_identifiers.len())
+            .copied()
+            .collect()
+    }
 }
 
 /// Quantization Table, 10918-1, B.2.4.1, P. 39
-struct QuantiziationTable {
-    p_q: u8,        // Element precision,
-    t_q: u8,        // Destinaiton identifier
-    q_k: [u16; 64], // Table element
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantiziationTable {
+    pub p_q: u8,        // Element precision, 0 = 8 bit, 1 = 16 bit
+    pub t_q: u8,        // Destinaiton identifier
+    pub q_k: [u16; 64], // Table element, in natural (row major) order rather than the zig-zag order it's coded in
+}
+
+/// The quantized DCT coefficients of one component of a frame, as given by `Jpeg::read_coefficients`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentCoefficients {
+    pub component_identifier: u8,
+    pub sampling_factors: (u8, u8), // (H, V)
+    pub blocks_wide: usize,         // blocks needed to cover the component's lines
+    pub blocks_high: usize,         // blocks needed to cover the component's lines
+    pub padded_blocks_wide: usize,  // blocks per row of `blocks`, which is padded out to whole MCUs
+    pub padded_blocks_high: usize,  // rows of `blocks`
+    pub quantization_table: QuantiziationTable,
+    pub blocks: Vec<[i32; 64]>,     // row by row, each in natural (row major) order
 }
 
 struct Component {
@@ -33,7 +270,6 @@ struct Component {
 }
 
 struct HeaderParameter {
-    c_s: u8, // Scan component selector
     t_d: u8, // DC entropy coding table destination selector
     t_a: u8, // AC entropy coding table destination selector
 }
@@ -41,6 +277,7 @@ struct HeaderParameter {
 struct ScanHeader {
     // Scan Header, 10918-1, B.2.3, P. 35
     head_params: HashMap<u8, HeaderParameter>,
+    component_order: Vec<u8>, // Scan component selectors, in the order they're coded
     s_s: u8, // Start of Spectral selection; predictor selector in lossless
     s_e: u8, // End of Spectral or prediction selection; 0, not used, in lossless
     a_h: u8, // Successive aproximamtion bit position high, 0, not used, in lossless
@@ -54,6 +291,7 @@ struct FrameHeader {
     y_: u16, // Number of lines
     x_: u16, // Number of samples per line
     components: HashMap<u8, Component>,
+    component_order: Vec<u8>, // Component identifiers, in the order they're given
 }
 
 struct SSSSTable {
@@ -64,12 +302,37 @@ struct SSSSTable {
     max_code_length: usize, // number of bits of longest Huffman code
 }
 
+/// A rectangle of the output image, given by `Jpeg::decode_region`
+#[derive(Clone, Copy)]
+struct Region {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
 pub struct Jpeg {
     encoded_image: Vec<u8>,
     read_index: usize,
     frame_header: Option<FrameHeader>,
-    ssss_tables: HashMap<usize, SSSSTable>,
-    // quantization_tables: Option<HashMap<u8, QuantiziationTable>>,
+    ssss_tables: HashMap<(u8, u8), SSSSTable>, // keyed by table class and destination identifier
+    quantization_tables: HashMap<u8, QuantiziationTable>,
+    restart_interval: u16,
+    arithmetic_conditioning: HashMap<(u8, u8), u8>, // DAC Cs values, keyed by table class and destination identifier
+    coefficients: HashMap<u8, Vec<[i32; 64]>>, // each component's blocks, in natural order, for DCT frames
+    hierarchical_progression: Option<FrameHeader>, // from DHP, the final image of a hierarchical image
+    expansion: (u8, u8), // from EXP, whether the next frame's references are expanded horizontally and vertically
+    hierarchical_levels: Vec<HierarchicalLevel>,
+    jpeg_ls_presets: jpeg_ls::PresetParameters,
+    component_planes: HashMap<u8, Vec<u32>>, // samples of components coded in scans of their own, until all are in
+    jfif: bool, // whether there's a JFIF APP0 segment, so 3 components are YCbCr
+    adobe_transform: Option<u8>, // the transform flag of an Adobe APP14 segment
+    raw_output: bool, // whether to output the components as they were coded, without color conversion
+    upsampling: Upsampling,
+    idct_method: IdctMethod,
+    scale_denominator: u8, // DCT images are output at 1/1, 1/2, 1/4 or 1/8 of their size
+    region: Option<Region>, // the only part of the image that's output, if set
+    coefficients_only: bool, // whether DCT frames are left as coefficients, without being output
     raw_image: Vec<u32>
 }
 
@@ -83,165 +346,1180 @@ impl Jpeg {
         assert!(jpeg_utils::is_jpeg(&encoded_image[0..2]));
 
         Self {
-            encoded_image: encoded_image,
+            encoded_image,
             read_index: 2,
             frame_header: None,
             ssss_tables: HashMap::new(),
-            // quantization_tables: None,
+            quantization_tables: HashMap::new(),
+            restart_interval: 0,
+            arithmetic_conditioning: HashMap::new(),
+            coefficients: HashMap::new(),
+            hierarchical_progression: None,
+            expansion: (0, 0),
+            hierarchical_levels: Vec::new(),
+            jpeg_ls_presets: jpeg_ls::PresetParameters::default(),
+            component_planes: HashMap::new(),
+            jfif: false,
+            adobe_transform: None,
+            raw_output: false,
+            upsampling: Upsampling::Fancy,
+            idct_method: IdctMethod::AccurateInteger,
+            scale_denominator: 1,
+            region: None,
+            coefficients_only: false,
             raw_image: Vec::new(),
         }
     }
 
-    fn decode(&mut self) {
+    /// Reads only the headers, up to the first SOS, without any entropy decoding.
+    /// Returns None if they aren't those of a JPEG image, end before the first scan header,
+    /// or don't hold together, such as a lossless restart interval that isn't whole rows.
+    pub fn probe(encoded_image: &[u8]) -> Option<ImageInfo> {
+        if encoded_image.len() < 2 || !jpeg_utils::is_jpeg(&encoded_image[0..2]) {
+            return None;
+        }
+
+        // Only the segments that are needed get copied so the image data is never duplicated
+        let mut header = Self::from_encoded_vec(jpeg_utils::extract_header_segments(encoded_image)?);
+        let mut huffman_tables: Vec<(u8, u8)> = Vec::new();
+
+        use Marker::*;
+        let scan_header = loop {
+            match header.bytes_to_int_two_peeked() {
+                marker if marker == DHT as u16 => {
+                    header.found_marker();
+                    let l_h: usize = header.bytes_to_int_two_consumed() as usize;
+                    let segment_end = header.read_index + l_h - 2;
+                    while header.read_index < segment_end {
+                        let (t_c, t_h, _) = header.parse_huffman_info();
+                        huffman_tables.push((t_c, t_h));
+                    }
+                },
+                marker if marker == DQT as u16 => header.parse_quantization_tables(),
+                marker if marker == DRI as u16 => header.parse_restart_interval(),
+                marker if marker == DHP as u16 => header.parse_hierarchical_progression(),
+                marker if marker == APP as u16 || marker == APP14 as u16 => header.parse_application_segment(),
+                marker if marker == SOS as u16 => {
+                    header.found_marker();
+                    break jpeg_utils::parse_scan_header(&mut header);
+                },
+                marker if CodingProcess::from_marker(marker).is_some() => header.parse_frame_header(marker),
+                _ => header.skip_segment(),
+            }
+        };
+
+        if !header.headers_hold_together() {
+            return None;
+        }
+        let frame_header = header.frame_header.as_ref()?;
+        // the first frame of a hierarchical image is usually smaller than the final image
+        let (width, height) = match &header.hierarchical_progression {
+            Some(progression) => (progression.x_, progression.y_),
+            None => (frame_header.x_, frame_header.y_),
+        };
+        Some(ImageInfo {
+            width,
+            height,
+            precision: frame_header.p_,
+            component_count: frame_header.components.len(),
+            coding_process: CodingProcess::from_marker(frame_header.marker).unwrap(),
+            huffman_tables,
+            restart_interval: header.restart_interval,
+            predictor: scan_header.s_s,
+            point_transform: scan_header.a_l_p_t,
+            hierarchical: header.hierarchical_progression.is_some(),
+            color_space: header.color_space(),
+            quantization_tables: header.quantization_tables,
+        })
+    }
+
+    /// Decodes the whole image into `raw_image`, from its start, whatever has been decoded before
+    pub fn decode(&mut self) {
+        let mut raw_image: Vec<u32> = Vec::new();
+        self.decode_with(&mut |_, row| raw_image.extend_from_slice(row));
+        self.raw_image = raw_image;
+    }
+
+    /// Decodes only the `width` by `height` rectangle at (`x`, `y`) into `raw_image`, in output coordinates,
+    /// so after scaling. DCT images skip the restart intervals above and below the rectangle, only looking
+    /// for the 0xFF bytes of their markers in the coded data rather than unstuffing and decoding it,
+    /// stop after the last MCU row it needs and only transform the blocks under it,
+    /// while lossless images stop after its last row.
+    pub fn decode_region(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let info = Self::probe(&self.encoded_image).expect("The image's headers couldn't be read");
+        let scale = if info.coding_process.is_dct() { self.scale_denominator as usize } else { 1 };
+        assert!(width > 0 && height > 0, "A region must have at least one sample");
+        assert!(
+            x + width <= (info.width as usize).div_ceil(scale) && y + height <= (info.height as usize).div_ceil(scale),
+            "The region must be within the image"
+        );
+
+        self.region = Some(Region { x, y, width, height });
+        self.decode();
+        self.region = None;
+    }
+
+    /// Decodes the quantized DCT coefficients of each component of a sequential or progressive DCT image,
+    /// without transforming them into samples, along with the quantization table and geometry of each
+    pub fn read_coefficients(&mut self) -> Vec<ComponentCoefficients> {
+        let info = Self::probe(&self.encoded_image).expect("The image's headers couldn't be read");
+        assert!(info.coding_process.is_dct(), "Only DCT images have coefficients");
+        assert!(!info.hierarchical, "Coefficients can't be read from hierarchical images");
+
+        self.coefficients_only = true;
+        self.decode_with(&mut |_, _| {});
+        self.coefficients_only = false;
+
+        let frame_header = self.frame_header.as_ref().unwrap();
+        frame_header
+            .component_order
+            .iter()
+            .map(|c_| {
+                let component = &frame_header.components[c_];
+                let geometry = frame_header.component_geometry(component);
+                ComponentCoefficients {
+                    component_identifier: *c_,
+                    sampling_factors: (component.h_, component.v_),
+                    blocks_wide: geometry.blocks_wide,
+                    blocks_high: geometry.blocks_high,
+                    padded_blocks_wide: geometry.padded_blocks_wide,
+                    padded_blocks_high: geometry.padded_blocks_high,
+                    quantization_table: self.quantization_tables[&component.t_q].clone(),
+                    blocks: self.coefficients[c_].clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Rewrites a Huffman coded lossless image (SOF3) with the Huffman tables that code each of its scans
+    /// in the fewest bits, 10918-1, K.2, P. 144. The coded differences are carried over as they are,
+    /// without reconstructing the samples, so the image decodes to exactly the same samples.
+    /// The DHT segments are replaced by ones written before each scan, while every other segment is kept.
+    /// The whole image is read, whether or not it's been decoded, and it's left as it was for decoding.
+    pub fn transcode_with_optimal_huffman_tables(&mut self) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::with_capacity(self.encoded_image.len());
+        output.extend_from_slice(&(Marker::SOI as u16).to_be_bytes());
+
+        // from just past SOI, and with no restart interval until a DRI segment gives one
+        let read_index = std::mem::replace(&mut self.read_index, 2);
+        self.restart_interval = 0;
+
+        use Marker::*;
+        while self.read_index < self.encoded_image.len() {
+            let segment_start = self.read_index;
+            match self.bytes_to_int_two_peeked() {
+                marker if marker == DHT as u16 => self.make_ssss_tables(),
+                marker if marker == SOS as u16 => self.transcode_lossless_scan(&mut output),
+                marker if marker == EOI as u16 => {
+                    output.extend_from_slice(&marker.to_be_bytes());
+                    break;
+                },
+                marker if marker > 0xFF00 => {
+                    if CodingProcess::from_marker(marker).is_some() {
+                        assert_eq!(marker, SOF3 as u16, "Only Huffman coded lossless (SOF3) images can be transcoded");
+                        self.parse_frame_header(marker);
+                    } else if marker == DRI as u16 {
+                        self.parse_restart_interval();
+                    } else {
+                        self.skip_segment();
+                    }
+                    output.extend_from_slice(&self.encoded_image[segment_start..self.read_index]);
+                },
+                _ => self.read_index += 1,
+            }
+        }
+        self.read_index = read_index;
+        output
+    }
+
+    /// Decodes the image, handing the interleaved samples of each band of `rows_per_band` rows
+    /// to `on_band` (along with the index of the band's first row) as soon as they're reconstructed.
+    /// The last band may be shorter. The rows of lossless images, and JPEG-LS images whose components share a scan,
+    /// are reconstructed one by one as their coded data is read, with only the previous row kept around
+    /// for the predictor. DCT frames, hierarchical images and JPEG-LS components in scans of their own
+    /// are decoded whole before their rows are handed out.
+    pub fn decode_rows<F: FnMut(usize, &[u32])>(&mut self, rows_per_band: usize, mut on_band: F) {
+        assert!(rows_per_band > 0, "A band must have at least one row");
+
+        let mut band: Vec<u32> = Vec::new();
+        let mut band_start: usize = 0;
+        let mut rows_in_band: usize = 0;
+        self.decode_with(&mut |row_index, row| {
+            if rows_in_band == 0 {
+                band_start = row_index;
+            }
+            band.extend_from_slice(row);
+            rows_in_band += 1;
+            if rows_in_band == rows_per_band {
+                on_band(band_start, &band);
+                band.clear();
+                rows_in_band = 0;
+            }
+        });
+        if rows_in_band > 0 {
+            on_band(band_start, &band);
+        }
+    }
+
+    /// Whether to output DCT images in the color space they were coded in, such as YCbCr,
+    /// rather than converting them to RGB or CMYK, which is the default
+    pub fn set_raw_output(&mut self, raw_output: bool) {
+        self.raw_output = raw_output;
+    }
+
+    /// How subsampled components of DCT images are upsampled, libjpeg's fancy upsampling by default
+    pub fn set_upsampling(&mut self, upsampling: Upsampling) {
+        self.upsampling = upsampling;
+    }
+
+    /// Which IDCT DCT images are decoded with, the accurate integer one by default.
+    /// Scaled decoding always uses its own reduced size IDCTs, and differential frames the accurate one.
+    pub fn set_idct_method(&mut self, idct_method: IdctMethod) {
+        self.idct_method = idct_method;
+    }
+
+    /// Outputs DCT images at 1/`denominator` of their size, rounded up, which can be 1, 2, 4 or 8,
+    /// using IDCTs that go straight to 4x4, 2x2 or 1x1 samples rather than decoding the full size image
+    pub fn set_scale(&mut self, denominator: u8) {
+        assert!([1, 2, 4, 8].contains(&denominator), "Images can only be scaled by 1/1, 1/2, 1/4 or 1/8");
+        self.scale_denominator = denominator;
+    }
+
+    /// The decoded image, as interleaved samples. Those of DCT images are RGB, for YCbCr,
+    /// and CMYK, for YCCK or Adobe's inverted CMYK, unless raw output was asked for
+    pub fn raw_image(&self) -> &[u32] {
+        &self.raw_image
+    }
+
+    /// The output of each frame of a hierarchical image, in the order they were decoded,
+    /// the last of which is the final image; empty for images that aren't hierarchical
+    pub fn hierarchical_levels(&self) -> &[HierarchicalLevel] {
+        &self.hierarchical_levels
+    }
+
+    /// The quantization tables read so far, by destination identifier
+    pub fn quantization_tables(&self) -> &HashMap<u8, QuantiziationTable> {
+        &self.quantization_tables
+    }
+
+    fn decode_with(&mut self, on_row: &mut dyn FnMut(usize, &[u32])) {
+        self.start_over();
         let encoded_image_len = self.encoded_image.len();
 
         use Marker::*;
         while self.read_index < encoded_image_len {
             match self.bytes_to_int_two_peeked() {
-                marker if marker == SOF3 as u16 => {
+                marker
+                    if [SOF0, SOF1, SOF2, SOF3, SOF5, SOF6, SOF7, SOF9, SOF10, SOF11, SOF13, SOF14, SOF15, SOF55]
+                        .into_iter()
+                        .any(|sof| marker == sof as u16) =>
+                {
+                    self.finish_frame(on_row);
+                    self.coefficients.clear();
                     self.parse_frame_header(marker);
                 },
                 marker if marker == DHT as u16 => {
                     self.make_ssss_tables();
                 },
+                marker if marker == DQT as u16 => {
+                    self.parse_quantization_tables();
+                },
+                marker if marker == DRI as u16 => {
+                    self.parse_restart_interval();
+                },
+                marker if marker == DAC as u16 => {
+                    self.parse_arithmetic_conditioning();
+                },
+                marker if marker == DHP as u16 => {
+                    self.parse_hierarchical_progression();
+                },
+                marker if marker == LSE as u16 => {
+                    self.parse_jpeg_ls_presets();
+                },
+                marker if marker == EXP as u16 => {
+                    self.finish_frame(on_row);
+                    self.coefficients.clear();
+                    self.parse_expansion();
+                },
                 marker if marker == SOS as u16 => {
-                    self.read_scan();
-                    
+                    self.read_scan(on_row);
                 },
                 marker if marker == EOI as u16 => break,
-                marker if marker > 0xFF00 => panic!("Oops, that marker hasn't been implimented yet!"),
+                marker if marker == APP as u16 || marker == APP14 as u16 => {
+                    self.parse_application_segment();
+                },
+                marker if (APP as u16..=APPn as u16).contains(&marker) || marker == COM as u16 => {
+                    self.skip_segment();
+                },
+                marker if (RST0 as u16..=SOI as u16).contains(&marker) || marker == TEM as u16 => {
+                    panic!("A {marker:#06X} marker, which has no segment, can't be between segments")
+                },
+                // JPGn, reserved markers and any others are skipped by their length, as probe does,
+                // while 0xFF fill bytes are stepped over
+                marker if marker > 0xFF00 && marker != 0xFFFF => self.skip_segment(),
                 _ => self.read_index += 1,
             }
         }
+
+        self.finish_frame(on_row);
+        if self.hierarchical_progression.is_some() {
+            self.output_hierarchical_image(on_row);
+        }
+    }
+
+    /// Forgets whatever was read by an earlier decode, keeping only the settings, so that the image is read
+    /// again from just past SOI
+    fn start_over(&mut self) {
+        self.read_index = 2;
+        self.frame_header = None;
+        self.ssss_tables.clear();
+        self.quantization_tables.clear();
+        self.restart_interval = 0;
+        self.arithmetic_conditioning.clear();
+        self.coefficients.clear();
+        self.hierarchical_progression = None;
+        self.expansion = (0, 0);
+        self.hierarchical_levels.clear();
+        self.jpeg_ls_presets = jpeg_ls::PresetParameters::default();
+        self.component_planes.clear();
+        self.jfif = false;
+        self.adobe_transform = None;
+    }
+
+    /// Outputs a DCT frame once all its scans have been decoded. The frames of hierarchical images
+    /// are instead kept as levels, with those of differential frames added to their references.
+    /// The coefficients are kept, and have to be cleared before the next frame.
+    fn finish_frame(&mut self, on_row: &mut dyn FnMut(usize, &[u32])) {
+        if !self.is_dct_frame() || self.coefficients.is_empty() || self.coefficients_only {
+            return;
+        }
+        if self.hierarchical_progression.is_some() {
+            let mut frame_samples: Vec<u32> = Vec::new();
+            self.output_dct_image(&mut |_, row| frame_samples.extend_from_slice(row));
+            self.add_hierarchical_level(frame_samples);
+        } else if self.raw_output {
+            self.output_dct_image(on_row);
+        } else {
+            let color_space = self.color_space();
+            let p_ = self.frame_header.as_ref().unwrap().p_;
+            let mut line: Vec<u32> = Vec::new();
+            self.output_dct_image(&mut |row_index, row| {
+                line.clear();
+                line.extend_from_slice(row);
+                color::convert(&mut line, color_space, p_);
+                on_row(row_index, &line);
+            });
+        }
+    }
+
+    /// The color space of the frame's components, following libjpeg's choices, where 3 components are
+    /// YCbCr unless an Adobe segment or their identifiers, R, G and B, say otherwise,
+    /// and 4 components are only Adobe's inverted CMYK or YCCK with an Adobe segment.
+    /// Those of hierarchical images are the ones given by DHP.
+    fn color_space(&self) -> ColorSpace {
+        let frame_header = self.hierarchical_progression.as_ref().or(self.frame_header.as_ref()).unwrap();
+        match (frame_header.component_order.len(), self.adobe_transform) {
+            (1, _) => ColorSpace::Grayscale,
+            (3, _) if self.jfif => ColorSpace::YCbCr,
+            (3, Some(0)) => ColorSpace::Rgb,
+            (3, Some(_)) => ColorSpace::YCbCr,
+            (3, None) if frame_header.component_order == b"RGB" => ColorSpace::Rgb,
+            (3, None) => ColorSpace::YCbCr,
+            (4, Some(0)) => ColorSpace::InvertedCmyk,
+            (4, Some(_)) => ColorSpace::Ycck,
+            (4, None) => ColorSpace::Cmyk,
+            _ => ColorSpace::Other,
+        }
+    }
+
+    /// Hands a row of the image, of `component_count` interleaved components, to `on_row`, unless it's outside
+    /// the region being decoded, if there is one, in which case it's cropped to it and numbered from its top
+    fn output_row(&self, row_index: usize, row: &[u32], component_count: usize, on_row: &mut dyn FnMut(usize, &[u32])) {
+        match self.region {
+            None => on_row(row_index, row),
+            Some(region) if (region.y..region.y + region.height).contains(&row_index) => on_row(
+                row_index - region.y,
+                &row[region.x * component_count..(region.x + region.width) * component_count],
+            ),
+            Some(_) => {},
+        }
+    }
+
+    /// The rows of blocks of a component with vertical sampling factor `v_` that the region being decoded needs,
+    /// with one more either side for upsampling, or of MCUs with `v_` as 1. That's all of them without a region,
+    /// and for hierarchical images, whose frames are references for those that follow.
+    fn needed_block_rows(&self, v_: usize, v_max: usize) -> std::ops::RangeInclusive<usize> {
+        match self.region {
+            Some(region) if self.hierarchical_progression.is_none() => {
+                let scale = self.scale_denominator as usize;
+                let first_line = region.y * scale;
+                let last_line = (region.y + region.height) * scale - 1;
+                (first_line * v_ / v_max / 8).saturating_sub(1)..=last_line * v_ / v_max / 8 + 1
+            },
+            _ => 0..=usize::MAX,
+        }
+    }
+
+    fn coding_process(&self) -> Option<CodingProcess> {
+        self.frame_header
+            .as_ref()
+            .and_then(|frame_header| CodingProcess::from_marker(frame_header.marker))
+    }
+
+    fn is_dct_frame(&self) -> bool {
+        self.coding_process().is_some_and(|coding_process| coding_process.is_dct())
+    }
+
+    /// Whether the headers read so far make sense together, for the scan that follows them:
+    /// the restart intervals of lossless frames, other than JPEG-LS ones, must be whole rows, as libjpeg requires,
+    /// since their first rows are predicted like the first row of the image
+    fn headers_hold_together(&self) -> bool {
+        match (self.frame_header.as_ref(), self.coding_process()) {
+            (Some(frame_header), Some(coding_process)) if !coding_process.is_dct() && coding_process != CodingProcess::JpegLs => {
+                self.restart_interval.is_multiple_of(frame_header.x_)
+            },
+            _ => true,
+        }
     }
 
-    fn read_scan(&mut self) {
+    fn read_scan(&mut self, on_row: &mut dyn FnMut(usize, &[u32])) {
         self.found_marker();
         let scan_header = jpeg_utils::parse_scan_header(self);
-        self.decode_image(scan_header);
+        // reported as for any other headers that can't be read, as `probe` doesn't read them either
+        if !self.headers_hold_together() {
+            panic!("The image's headers couldn't be read");
+        }
+        if self.is_dct_frame() {
+            self.decode_dct_scan(scan_header);
+        } else if self.coding_process() == Some(CodingProcess::JpegLs) {
+            self.decode_jpeg_ls_scan(scan_header, on_row);
+        } else if self.hierarchical_progression.is_none()
+            && scan_header.component_order == self.frame_header.as_ref().unwrap().component_order
+        {
+            // there's no need to go on past the last row of the region being decoded
+            let height = self.frame_header.as_ref().unwrap().y_ as usize;
+            let row_count = self.region.map_or(height, |region| region.y + region.height);
+            self.decode_image(scan_header, row_count, on_row);
+        } else {
+            // the frames of hierarchical images are references for those that follow, so they're kept whole,
+            // as are the components of scans that don't have all of the frame's until the rest are in
+            let frame_header = self.frame_header.as_ref().unwrap();
+            let (width, height) = (frame_header.x_ as usize, frame_header.y_ as usize);
+            let component_order = scan_header.component_order.clone();
+            let mut samples: Vec<u32> = Vec::with_capacity(width * height * component_order.len());
+            self.decode_image(scan_header, height, &mut |_, row| samples.extend_from_slice(row));
+            if let Some(frame_samples) = self.add_component_planes(&component_order, &samples) {
+                if self.hierarchical_progression.is_some() {
+                    self.add_hierarchical_level(frame_samples);
+                } else {
+                    let component_count = self.frame_header.as_ref().unwrap().component_order.len();
+                    for (y, row) in frame_samples.chunks_exact(width * component_count).enumerate() {
+                        self.output_row(y, row, component_count, on_row);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keeps the samples of a scan that doesn't have all of the frame's components, interleaved in `component_order`,
+    /// until those of the rest of its components are in, when the frame's interleaved samples are returned
+    fn add_component_planes(&mut self, component_order: &[u8], samples: &[u32]) -> Option<Vec<u32>> {
+        let component_count = component_order.len();
+        for (index, c_s) in component_order.iter().enumerate() {
+            let plane = samples.iter().skip(index).step_by(component_count).copied().collect();
+            self.component_planes.insert(*c_s, plane);
+        }
+
+        let frame_header = self.frame_header.as_ref().unwrap();
+        if !frame_header.component_order.iter().all(|c_| self.component_planes.contains_key(c_)) {
+            return None;
+        }
+        let sample_count = frame_header.x_ as usize * frame_header.y_ as usize;
+        let frame_samples = (0..sample_count)
+            .flat_map(|index| frame_header.component_order.iter().map(move |c_| (index, c_)))
+            .map(|(index, c_)| self.component_planes[c_][index])
+            .collect();
+        self.component_planes.clear();
+        Some(frame_samples)
     }
 
     /// TODO: THIS SEEMS TO BE WEHRE I'VE LEFT OFF
     /// 10918-1, H.2, P. 136 & H.1, P. 132
-    fn decode_image(&mut self, scan_header: ScanHeader) {
-        let image_bits = self.get_image_data_without_stuffed_zero_bytes();
-        let mut image_bits = image_bits.iter();
+    /// Arithmetic coded differences are decoded as in H.1.4.3, P. 135
+    /// Only the first `row_count` rows are decoded, and each has the samples of the scan's components,
+    /// interleaved in the order they're in the scan
+    fn decode_image(&mut self, scan_header: ScanHeader, row_count: usize, on_row: &mut dyn FnMut(usize, &[u32])) {
+        let arithmetic = self.coding_process().is_some_and(|coding_process| coding_process.is_arithmetic());
+        let mut image_bits = jpeg_utils::ScanReader::new(&self.encoded_image, self.read_index);
+        let mut decoder = arithmetic.then(|| arithmetic::ArithmeticDecoder::new(image_bits.clone()));
+
+        // each component's table, in the order the components are interleaved
+        let table_selectors: Vec<u8> = scan_header
+            .component_order
+            .iter()
+            .map(|c_s| scan_header.head_params[c_s].t_d)
+            .collect();
+        let mut statistics: HashMap<u8, [u8; arithmetic::LOSSLESS_STATISTICS]> = HashMap::new();
 
         let frame_header = self.frame_header.as_ref().unwrap();
         let width = frame_header.x_ as usize;
         let height = frame_header.y_ as usize;
 
-        let component_count = frame_header.components.len();
-        self.raw_image = Vec::with_capacity(width * height * component_count);
+        let component_count = table_selectors.len();
+        let row_length = width * component_count;
+        // restart intervals are whole rows, checked with the headers, whose first rows are predicted like the image's first
+        let interval_rows = match self.restart_interval as usize {
+            0 => height,
+            restart_interval => restart_interval / width,
+        };
+
+        // The predictors only ever look one row up so,
+        // at most, the previous row and the one being reconstructed are kept
+        let mut rows: Vec<u32> = Vec::with_capacity(2 * row_length);
+        // and the differences coded for those samples, for the arithmetic coding contexts
+        let mut differences: Vec<i32> = Vec::with_capacity(2 * row_length);
+        // Samples are predicted and reconstructed with the point transform's low bits dropped,
+        // and only shifted back up for the output, H.1.2.1, P. 134
+        let p_t = scan_header.a_l_p_t;
+        assert!(p_t < frame_header.p_, "The point transform must leave at least one bit of each sample");
+
+        for row_index in 0..row_count {
+            if row_index % interval_rows == 0 {
+                // the predictions, and the arithmetic coding statistics, start afresh in each interval
+                if row_index > 0 {
+                    match decoder.as_mut() {
+                        Some(decoder) => decoder.restart(),
+                        None => image_bits.next_interval(),
+                    }
+                }
+                statistics.clear();
+                rows.clear();
+                differences.clear();
+            }
+            let rows_needed = rows.len() / row_length + 1;
+            while rows_needed * row_length > rows.len() {
+                let component = rows.len() % component_count;
+                let p_x = jpeg_utils::make_prediciton(
+                    &rows,
+                    component_count,
+                    width,
+                    frame_header.p_,
+                    p_t,
+                    scan_header.s_s,
+                );
+                let t_d = table_selectors[component];
+                let pixel_delta = match decoder.as_mut() {
+                    Some(decoder) => {
+                        let index = rows.len();
+                        // differences outside the image count as zero
+                        let d_a = if index % row_length >= component_count {
+                            differences[index - component_count]
+                        } else {
+                            0
+                        };
+                        let d_b = if index >= row_length { differences[index - row_length] } else { 0 };
+                        let conditioning = self
+                            .arithmetic_conditioning
+                            .get(&(0, t_d))
+                            .copied()
+                            .unwrap_or(arithmetic::DEFAULT_DC_CONDITIONING);
+                        arithmetic::decode_lossless_difference(
+                            decoder,
+                            statistics.entry(t_d).or_insert([0; arithmetic::LOSSLESS_STATISTICS]),
+                            d_a,
+                            d_b,
+                            conditioning,
+                        )
+                    },
+                    None => jpeg_utils::get_huffmaned_value(&self.ssss_tables[&(0, t_d)], &mut image_bits),
+                };
+                differences.push(pixel_delta);
+                rows.push(((p_x as i32 + pixel_delta) & ((1 << (frame_header.p_ - p_t)) - 1)) as u32);
+            }
+            let row = &rows[rows.len() - row_length..];
+            if p_t > 0 {
+                let row: Vec<u32> = row.iter().map(|sample| sample << p_t).collect();
+                self.output_row(row_index, &row, component_count, on_row);
+            } else {
+                self.output_row(row_index, row, component_count, on_row);
+            }
+            if rows.len() > row_length {
+                rows.drain(..row_length);
+                differences.drain(..row_length);
+            }
+        }
+        self.read_index = decoder.map_or(image_bits, |decoder| decoder.into_reader()).end_of_scan();
+    }
+
+    /// Writes a lossless scan with optimal Huffman tables for the differences it codes, which are decoded
+    /// as in H.2, P. 136, but not added to any predictions. Each MCU is a difference for each of the scan's
+    /// components, and the scan is restarted as it was.
+    fn transcode_lossless_scan(&mut self, output: &mut Vec<u8>) {
+        let scan_start = self.read_index;
+        self.found_marker();
+        let scan_header = jpeg_utils::parse_scan_header(self);
+        let scan_header_segment = self.encoded_image[scan_start..self.read_index].to_vec();
+        let mut image_bits = jpeg_utils::ScanReader::new(&self.encoded_image, self.read_index);
+
+        let frame_header = self.frame_header.as_ref().expect("No frame header found before the first scan");
+        assert!(
+            frame_header.components.values().all(|component| component.h_ == 1 && component.v_ == 1),
+            "Subsampled lossless components aren't supported"
+        );
+        let table_selectors: Vec<u8> = scan_header
+            .component_order
+            .iter()
+            .map(|c_s| scan_header.head_params[c_s].t_d)
+            .collect();
+        let component_count = table_selectors.len();
+        let mcu_count = frame_header.x_ as usize * frame_header.y_ as usize;
+        let mcus_per_interval = match self.restart_interval {
+            0 => mcu_count,
+            restart_interval => restart_interval as usize,
+        };
+
+        // the differences of each restart interval
+        let interval_count = mcu_count.div_ceil(mcus_per_interval);
+        let mut differences: Vec<Vec<i32>> = Vec::with_capacity(interval_count);
+        for interval in 0..interval_count {
+            if interval > 0 {
+                image_bits.next_interval();
+            }
+            let mcus = mcus_per_interval.min(mcu_count - interval * mcus_per_interval);
+            differences.push(
+                (0..mcus * component_count)
+                    .map(|index| {
+                        let ssss_table = &self.ssss_tables[&(0, table_selectors[index % component_count])];
+                        jpeg_utils::get_huffmaned_value(ssss_table, &mut image_bits)
+                    })
+                    .collect(),
+            );
+        }
+        self.read_index = image_bits.end_of_scan();
+
+        // the tables in the order the components use them, as they're ordered in the scan header
+        let mut codes: HashMap<u8, encoder::HuffmanCodes> = HashMap::new();
+        for t_d in &table_selectors {
+            if codes.contains_key(t_d) {
+                continue;
+            }
+            let mut frequencies: [u32; 256] = [0; 256];
+            for interval_differences in &differences {
+                for (index, difference) in interval_differences.iter().enumerate() {
+                    if table_selectors[index % component_count] == *t_d {
+                        frequencies[encoder::ssss(*difference) as usize] += 1;
+                    }
+                }
+            }
+            let code_lengths = encoder::optimal_code_lengths(&frequencies);
+            encoder::write_huffman_table(output, 0, *t_d, &code_lengths);
+            codes.insert(*t_d, encoder::huffman_codes(&code_lengths));
+        }
+        output.extend(scan_header_segment);
+
+        let mut writer = encoder::BitWriter::new();
+        for (interval, interval_differences) in differences.iter().enumerate() {
+            if interval > 0 {
+                writer.write_restart_marker(((interval - 1) % 8) as u8);
+            }
+            for (index, difference) in interval_differences.iter().enumerate() {
+                writer.write_difference(&codes[&table_selectors[index % component_count]], *difference);
+            }
+        }
+        output.extend(writer.finish());
+    }
+
+    /// Decodes a JPEG-LS scan, ITU T.87. Its Ss is NEAR and Se the interleave mode, ILV.
+    /// Scans that hold every component of the frame are output a line at a time,
+    /// while components coded in scans of their own are kept until they're all in.
+    fn decode_jpeg_ls_scan(&mut self, scan_header: ScanHeader, on_row: &mut dyn FnMut(usize, &[u32])) {
+        let mut image_bits = jpeg_utils::ScanReader::new_jpeg_ls(&self.encoded_image, self.read_index);
 
-        while width * height * component_count > self.raw_image.len() {
-            let component = self.raw_image.len() % component_count;
-            let p_x = jpeg_utils::make_prediciton(
-                &self.raw_image,
+        let frame_header = self.frame_header.as_ref().unwrap();
+        assert!(
+            frame_header.components.values().all(|component| component.h_ == 1 && component.v_ == 1),
+            "Subsampled JPEG-LS components aren't supported"
+        );
+        assert_eq!(self.restart_interval, 0, "Restart intervals in JPEG-LS scans aren't supported");
+        assert_eq!(scan_header.a_l_p_t, 0, "The JPEG-LS point transform isn't supported");
+        let parameters = jpeg_ls::Parameters::new(frame_header.p_, scan_header.s_s, &self.jpeg_ls_presets);
+        let size = (frame_header.x_ as usize, frame_header.y_ as usize);
+        let component_count = scan_header.component_order.len();
+
+        if scan_header.component_order == frame_header.component_order {
+            // there's no need to go on past the last line of the region being decoded
+            let lines = self.region.map_or(size.1, |region| region.y + region.height);
+            jpeg_ls::decode_scan(
+                &mut image_bits,
+                &parameters,
+                (size.0, lines),
                 component_count,
-                width,
-                frame_header.p_,
-                scan_header.a_h,
-                scan_header.s_s,
+                scan_header.s_e,
+                &mut |y, line| self.output_row(y, line, component_count, on_row),
             );
-            let pixel_delta = jpeg_utils::get_huffmaned_value(&self.ssss_tables[&component], &mut image_bits);
-            self.raw_image.push(((p_x as i32 + pixel_delta) & ((1 << frame_header.p_) - 1)) as u32);
+            self.read_index = image_bits.end_of_scan();
+            return;
+        }
+
+        let mut samples: Vec<u32> = Vec::with_capacity(size.0 * size.1 * component_count);
+        jpeg_ls::decode_scan(&mut image_bits, &parameters, size, component_count, scan_header.s_e, &mut |_, line| {
+            samples.extend_from_slice(line)
+        });
+        self.read_index = image_bits.end_of_scan();
+        if let Some(frame_samples) = self.add_component_planes(&scan_header.component_order, &samples) {
+            let component_count = self.frame_header.as_ref().unwrap().component_order.len();
+            for (y, row) in frame_samples.chunks_exact(size.0 * component_count).enumerate() {
+                self.output_row(y, row, component_count, on_row);
+            }
         }
     }
 
-    // ToDo: this is hacky
-    fn get_image_data_without_stuffed_zero_bytes(&mut self) -> Vec<u8> {
-        // See JPG document 10918-1 P33 B.1.1.5 Note 2
-        let mut image_data: Vec<u8> = Vec::with_capacity(self.encoded_image.len());
-        let mut this_byte: u8 = self.byte_to_int_one_consumed();
-        let mut next_byte: u8 = self.byte_to_int_one_consumed();
-        let mut i = 0;
-        loop {
-            if this_byte < 0xFF {
-                // if the current element is less then 0xFF the proceide as usual
-                image_data.push(this_byte);
-                i += 1;
-                this_byte = next_byte;
-                match self.encoded_image.get(self.read_index) {
-                    Some(n) => {
-                        next_byte = *n;
-                        self.read_index += 1;
-                    },
-                    None => break
+    /// Decodes a DCT scan into the coefficient buffers, which are kept across the scans of a frame.
+    /// Sequential scans are decoded as in 10918-1, F.2, P. 104, and progressive ones as in G.2, P. 124
+    fn decode_dct_scan(&mut self, scan_header: ScanHeader) {
+        let frame_header = self.frame_header.as_ref().unwrap();
+        let scan_components: Vec<&Component> = scan_header
+            .component_order
+            .iter()
+            .map(|c_s| &frame_header.components[c_s])
+            .collect();
+        let geometries: Vec<dct::ComponentGeometry> = scan_components
+            .iter()
+            .map(|component| frame_header.component_geometry(component))
+            .collect();
+
+        for (component, geometry) in scan_components.iter().zip(geometries.iter()) {
+            self.coefficients
+                .entry(component.c_)
+                .or_insert_with(|| vec![[0; 64]; geometry.padded_blocks_wide * geometry.padded_blocks_high]);
+        }
+
+        // A scan of a single component isn't interleaved and its MCUs are single blocks,
+        // which only cover the component itself, A.2.2, P. 25
+        let interleaved = scan_components.len() > 1;
+        let (mcus_wide, mcus_high) = if interleaved {
+            frame_header.mcus()
+        } else {
+            (geometries[0].blocks_wide, geometries[0].blocks_high)
+        };
+        let mcu_count = mcus_wide * mcus_high;
+        let mcus_per_interval = match self.restart_interval {
+            0 => mcu_count,
+            restart_interval => restart_interval as usize,
+        };
+        let v_max = frame_header.max_sampling_factors().1;
+        let needed_rows = if interleaved {
+            self.needed_block_rows(1, v_max)
+        } else {
+            self.needed_block_rows(scan_components[0].v_ as usize, v_max)
+        };
+
+        let coding_process = CodingProcess::from_marker(frame_header.marker).unwrap();
+        let progressive = coding_process.is_progressive();
+        let arithmetic = coding_process.is_arithmetic();
+        let s_s = scan_header.s_s as usize;
+        let s_e = scan_header.s_e as usize;
+        let a_l = scan_header.a_l_p_t;
+
+        let mut image_bits = jpeg_utils::ScanReader::new(&self.encoded_image, self.read_index);
+        let mut decoder = arithmetic.then(|| arithmetic::ArithmeticDecoder::new(image_bits.clone()));
+        let mut mcu: usize = 0;
+        while mcu < mcu_count {
+            // restart intervals wholly above the rows that are needed don't have to be decoded at all,
+            // since each starts afresh, and those below them never do
+            if mcu / mcus_wide > *needed_rows.end() {
+                break;
+            }
+            if mcu > 0 {
+                match decoder.as_mut() {
+                    Some(decoder) => decoder.restart(),
+                    None => image_bits.next_interval(),
                 }
-            } else if next_byte == 0 {
-                // given that the current element is 0xFF
-                // if the next element is zero then
-                // this element should be read and the next is a "zero byte"
-                // which was added to avoid confusion with markers and should be discarded
-                // ToDo: what if there are consecutive 0xFF?
-                image_data.push(this_byte);
-                i += 1;
-                match self.encoded_image.get(self.read_index) {
-                    Some(n) => {
-                        this_byte = *n;
-                        self.read_index += 1;
-                    },
-                    None => break
+            }
+            let last_mcu = (mcu + mcus_per_interval).min(mcu_count) - 1;
+            if last_mcu / mcus_wide < *needed_rows.start() {
+                mcu = last_mcu + 1;
+                continue;
+            }
+            // the DC predictions, the end of band run and the arithmetic coding statistics
+            // are reset at the start of the scan and of each restart interval
+            let mut predictions: Vec<i32> = vec![0; scan_components.len()];
+            let mut eob_run: u32 = 0;
+            let mut dc_contexts: Vec<usize> = vec![0; scan_components.len()];
+            let mut dc_statistics: HashMap<u8, [u8; arithmetic::DC_STATISTICS]> = HashMap::new();
+            let mut ac_statistics: HashMap<u8, [u8; arithmetic::AC_STATISTICS]> = HashMap::new();
+            for _ in 0..mcus_per_interval {
+                if mcu == mcu_count || mcu / mcus_wide > *needed_rows.end() {
+                    break;
                 }
-                match self.encoded_image.get(self.read_index) {
-                    Some(n) => {
-                        next_byte = *n;
-                        self.read_index += 1;
-                    },
-                    None => {}
+                let (mcu_x, mcu_y) = (mcu % mcus_wide, mcu / mcus_wide);
+                for (index, component) in scan_components.iter().enumerate() {
+                    let head_params = &scan_header.head_params[&component.c_];
+                    // refinement scans don't need both tables, so they might not have been defined
+                    let dc_table = self.ssss_tables.get(&(0, head_params.t_d));
+                    let ac_table = self.ssss_tables.get(&(1, head_params.t_a));
+                    let dc_conditioning = self
+                        .arithmetic_conditioning
+                        .get(&(0, head_params.t_d))
+                        .copied()
+                        .unwrap_or(arithmetic::DEFAULT_DC_CONDITIONING);
+                    let k_x = self
+                        .arithmetic_conditioning
+                        .get(&(1, head_params.t_a))
+                        .copied()
+                        .unwrap_or(arithmetic::DEFAULT_AC_CONDITIONING);
+                    let blocks = self.coefficients.get_mut(&component.c_).unwrap();
+                    let (h_, v_) = if interleaved {
+                        (component.h_ as usize, component.v_ as usize)
+                    } else {
+                        (1, 1)
+                    };
+                    for block_y in 0..v_ {
+                        for block_x in 0..h_ {
+                            let row = mcu_y * v_ + block_y;
+                            let column = mcu_x * h_ + block_x;
+                            let block = &mut blocks[row * geometries[index].padded_blocks_wide + column];
+                            if let Some(decoder) = decoder.as_mut() {
+                                // statistics are kept per table, so components sharing one share them too
+                                let dc_statistics = dc_statistics
+                                    .entry(head_params.t_d)
+                                    .or_insert([0; arithmetic::DC_STATISTICS]);
+                                let ac_statistics = ac_statistics
+                                    .entry(head_params.t_a)
+                                    .or_insert([0; arithmetic::AC_STATISTICS]);
+                                match (progressive, s_s, scan_header.a_h) {
+                                    (false, _, _) => {
+                                        predictions[index] += arithmetic::decode_dc_difference(
+                                            decoder,
+                                            dc_statistics,
+                                            &mut dc_contexts[index],
+                                            dc_conditioning,
+                                        );
+                                        block[0] = predictions[index];
+                                        arithmetic::decode_ac_coefficients(decoder, ac_statistics, (1, s_e), k_x, 0, block);
+                                    },
+                                    (true, 0, 0) => {
+                                        predictions[index] += arithmetic::decode_dc_difference(
+                                            decoder,
+                                            dc_statistics,
+                                            &mut dc_contexts[index],
+                                            dc_conditioning,
+                                        );
+                                        block[0] = predictions[index] << a_l;
+                                    },
+                                    (true, 0, _) => arithmetic::decode_dc_refine(decoder, a_l, block),
+                                    (true, _, 0) => {
+                                        arithmetic::decode_ac_coefficients(decoder, ac_statistics, (s_s, s_e), k_x, a_l, block)
+                                    },
+                                    (true, _, _) => {
+                                        arithmetic::decode_ac_refine(decoder, ac_statistics, (s_s, s_e), a_l, block)
+                                    },
+                                }
+                                continue;
+                            }
+                            let bits = &mut image_bits;
+                            match (progressive, s_s, scan_header.a_h) {
+                                (false, _, _) => dct::decode_block(
+                                    dc_table.expect("Missing DC Huffman table"),
+                                    ac_table.expect("Missing AC Huffman table"),
+                                    bits,
+                                    &mut predictions[index],
+                                    block,
+                                ),
+                                (true, 0, 0) => dct::decode_dc_first(
+                                    dc_table.expect("Missing DC Huffman table"),
+                                    bits,
+                                    &mut predictions[index],
+                                    a_l,
+                                    block,
+                                ),
+                                (true, 0, _) => dct::decode_dc_refine(bits, a_l, block),
+                                (true, _, 0) => dct::decode_ac_first(
+                                    ac_table.expect("Missing AC Huffman table"),
+                                    bits,
+                                    (s_s, s_e),
+                                    a_l,
+                                    &mut eob_run,
+                                    block,
+                                ),
+                                (true, _, _) => dct::decode_ac_refine(
+                                    ac_table.expect("Missing AC Huffman table"),
+                                    bits,
+                                    (s_s, s_e),
+                                    a_l,
+                                    &mut eob_run,
+                                    block,
+                                ),
+                            }
+                        }
+                    }
+                }
+                mcu += 1;
+            }
+        }
+        self.read_index = decoder.map_or(image_bits, |decoder| decoder.into_reader()).end_of_scan();
+    }
+
+    /// Adds the output of a frame of a hierarchical image to its levels. Differential frames hold differences
+    /// from the latest level of each of their components, expanded as given by the EXP segment before them,
+    /// 10918-1, J.2, P. 152. Differences from lossless frames are added modulo 2^P, as in H.2.1, P. 136,
+    /// while those from DCT frames are clamped to the sample range.
+    fn add_hierarchical_level(&mut self, mut samples: Vec<u32>) {
+        let frame_header = self.frame_header.as_ref().unwrap();
+        let coding_process = CodingProcess::from_marker(frame_header.marker).unwrap();
+        let frame_size = (frame_header.x_ as usize, frame_header.y_ as usize);
+        let component_count = frame_header.component_order.len();
+        let max_sample: i32 = (1 << frame_header.p_) - 1;
+
+        if coding_process.is_differential() {
+            for (index, c_) in frame_header.component_order.iter().enumerate() {
+                let reference_level = self
+                    .hierarchical_levels
+                    .iter()
+                    .rev()
+                    .find(|level| level.component_identifiers.contains(c_))
+                    .expect("A differential frame's components must be in an earlier frame");
+                let reference = hierarchical::expand(
+                    &reference_level.component_samples(*c_),
+                    (reference_level.width as usize, reference_level.height as usize),
+                    self.expansion,
+                    frame_size,
+                );
+                for (sample, reference_sample) in samples.iter_mut().skip(index).step_by(component_count).zip(reference) {
+                    let difference = *sample as i32;
+                    *sample = if coding_process.is_dct() {
+                        (reference_sample + difference).clamp(0, max_sample)
+                    } else {
+                        (reference_sample + difference) & max_sample
+                    } as u32;
+                }
+            }
+        }
+
+        self.expansion = (0, 0);
+        self.hierarchical_levels.push(HierarchicalLevel {
+            width: frame_header.x_,
+            height: frame_header.y_,
+            component_identifiers: frame_header.component_order.clone(),
+            samples,
+        });
+    }
+
+    /// Hands the final image of a hierarchical image to `on_row`, made of the latest level of each of the
+    /// components given by DHP, color converted as other DCT images are when the last frame is a DCT frame
+    fn output_hierarchical_image(&mut self, on_row: &mut dyn FnMut(usize, &[u32])) {
+        let convert = self.is_dct_frame() && !self.raw_output;
+        let color_space = self.color_space();
+        let progression = self.hierarchical_progression.as_ref().unwrap();
+        let planes: Vec<Vec<u32>> = progression
+            .component_order
+            .iter()
+            .map(|c_| {
+                let level = self
+                    .hierarchical_levels
+                    .iter()
+                    .rev()
+                    .find(|level| level.component_identifiers.contains(c_))
+                    .expect("Every component given by DHP must be in a frame");
+                assert!(
+                    level.width == progression.x_ && level.height == progression.y_,
+                    "The last frame of each component must be the size given by DHP"
+                );
+                level.component_samples(*c_)
+            })
+            .collect();
+
+        let width = progression.x_ as usize;
+        let mut row: Vec<u32> = Vec::with_capacity(width * planes.len());
+        for y in 0..progression.y_ as usize {
+            row.clear();
+            for x in 0..width {
+                for plane in planes.iter() {
+                    row.push(plane[y * width + x]);
+                }
+            }
+            if convert {
+                color::convert(&mut row, color_space, progression.p_);
+            }
+            self.output_row(y, &row, planes.len(), on_row);
+        }
+    }
+
+    /// Dequantizes and inverse transforms the coefficients of a DCT frame,
+    /// handing each row of interleaved component samples, of the region being decoded if there is one, to `on_row`.
+    /// Subsampled components are upsampled with libjpeg's fancy filter or by replicating samples.
+    fn output_dct_image(&mut self, on_row: &mut dyn FnMut(usize, &[u32])) {
+        let frame_header = self.frame_header.as_ref().unwrap();
+        let (h_max, v_max) = frame_header.max_sampling_factors();
+        let differential = CodingProcess::from_marker(frame_header.marker).unwrap().is_differential();
+        // the frames of hierarchical images are always full size, since they're references for those that follow
+        let scale = if self.hierarchical_progression.is_some() { 1 } else { self.scale_denominator as usize };
+        let min_size = 8 / scale;
+        let width = (frame_header.x_ as usize).div_ceil(scale);
+        let height = (frame_header.y_ as usize).div_ceil(scale);
+        let region = match self.region {
+            Some(region) if self.hierarchical_progression.is_none() => region,
+            _ => Region { x: 0, y: 0, width, height },
+        };
+
+        let mut planes: Vec<(Vec<i32>, usize, usize, usize)> = Vec::new();
+        for c_ in frame_header.component_order.iter() {
+            let component = &frame_header.components[c_];
+            let geometry = frame_header.component_geometry(component);
+            let quantization = &self.quantization_tables[&component.t_q].q_k;
+
+            let (h_, v_) = (component.h_ as usize, component.v_ as usize);
+            // as libjpeg does, subsampled components get larger blocks, up to 8 samples square,
+            // when that saves having to upsample them as much, or at all
+            let mut size = min_size;
+            while size < 8 && (h_max * min_size) % (h_ * size * 2) == 0 && (v_max * min_size) % (v_ * size * 2) == 0 {
+                size *= 2;
+            }
+
+            let stride = geometry.padded_blocks_wide * size;
+            let mut plane: Vec<i32> = vec![0; stride * geometry.padded_blocks_high * size];
+            let blocks = &self.coefficients[c_];
+            let ifast_multipliers = dct::ifast_multipliers(quantization, frame_header.p_);
+            let float_multipliers = dct::float_multipliers(quantization);
+            let expansion = (h_max * min_size / (h_ * size), v_max * min_size / (v_ * size));
+            // only the blocks under the region, and those either side of them for upsampling, are transformed
+            let needed_blocks = |first: usize, length: usize, expansion: usize, blocks: usize| {
+                ((first / expansion).saturating_sub(1) / size)..(((first + length - 1) / expansion + 1) / size + 1).min(blocks)
+            };
+            for block_row in needed_blocks(region.y, region.height, expansion.1, geometry.blocks_high) {
+                for block_column in needed_blocks(region.x, region.width, expansion.0, geometry.blocks_wide) {
+                    let block = &blocks[block_row * geometry.padded_blocks_wide + block_column];
+                    let samples = match (differential, size, self.idct_method) {
+                        (true, _, _) => dct::idct_islow_differential(block, quantization, frame_header.p_),
+                        (false, 8, IdctMethod::FastInteger) => dct::idct_ifast(block, &ifast_multipliers, frame_header.p_),
+                        (false, 8, IdctMethod::Float) => dct::idct_float(block, &float_multipliers, frame_header.p_),
+                        (false, _, _) => dct::idct_scaled(block, quantization, frame_header.p_, size),
+                    };
+                    for y in 0..size {
+                        let start = (block_row * size + y) * stride + block_column * size;
+                        plane[start..start + size].copy_from_slice(&samples[y * size..y * size + size]);
+                    }
                 }
+            }
+
+            let component_width = (frame_header.x_ as usize * h_ * size).div_ceil(h_max * 8);
+            let component_height = (frame_header.y_ as usize * v_ * size).div_ceil(v_max * 8);
+            // like libjpeg, only doubling is filtered, and not when widening lines of 2 samples or fewer,
+            // nor at 1/8 scale
+            if self.upsampling == Upsampling::Fancy
+                && min_size > 1
+                && matches!(expansion, (2, 1) | (2, 2) | (1, 2))
+                && (expansion.0 == 1 || component_width > 2)
+            {
+                let upsampled =
+                    upsampling::fancy_upsample(&plane, stride, (component_width, component_height), expansion);
+                planes.push((upsampled, component_width * expansion.0, 1, 1));
             } else {
-                // Hit the end of the section
-                break;
+                planes.push((plane, stride, expansion.0, expansion.1));
             }
         }
 
-        if this_byte == 0xFF && 0 < next_byte && next_byte < 0xFF {
-            self.read_index -= 2;
+        let mut row: Vec<u32> = Vec::with_capacity(region.width * planes.len());
+        for y in region.y..region.y + region.height {
+            row.clear();
+            for x in region.x..region.x + region.width {
+                for (plane, stride, h_expansion, v_expansion) in planes.iter() {
+                    row.push(plane[(y / v_expansion) * stride + x / h_expansion] as u32);
+                }
+            }
+            on_row(y - region.y, &row);
         }
-    
-        let mut bits: Vec<u8> = Vec::with_capacity(i * 8);
-
-        for i in image_data.iter() {
-            bits.push((i >> 7) & 1);
-            bits.push((i >> 6) & 1);
-            bits.push((i >> 5) & 1);
-            bits.push((i >> 4) & 1);
-            bits.push((i >> 3) & 1);
-            bits.push((i >> 2) & 1);
-            bits.push((i >> 1) & 1);
-            bits.push(i & 1);
+    }
+
+    /// Parses a DQT segment, which may hold several tables, 10918-1, B.2.4.1, P. 39
+    fn parse_quantization_tables(&mut self) {
+        self.found_marker();
+        let l_q: usize = self.bytes_to_int_two_consumed() as usize;
+        let segment_end = self.read_index + l_q - 2;
+
+        while self.read_index < segment_end {
+            let p_q_t_q: u8 = self.byte_to_int_one_consumed();
+            let p_q: u8 = p_q_t_q >> 4;
+            let t_q: u8 = p_q_t_q & 0xF;
+            // the elements are coded in zig-zag order but kept in natural order
+            let mut q_k: [u16; 64] = [0; 64];
+            for k in 0..64 {
+                q_k[dct::ZIGZAG[k]] = match p_q {
+                    0 => self.byte_to_int_one_consumed() as u16,
+                    1 => self.bytes_to_int_two_consumed(),
+                    _ => panic!("Quantization table element precision must be 0 or 1"),
+                };
+            }
+            self.quantization_tables.insert(t_q, QuantiziationTable { p_q, t_q, q_k });
         }
-    
-        bits
+    }
+
+    /// Reads the JFIF APP0 segment, JFIF 1.02, P. 5, and Adobe's APP14 segment, whose transform flag,
+    /// after "Adobe", the version and two flags, says whether the components are YCbCr or YCCK, or neither
+    fn parse_application_segment(&mut self) {
+        let marker = self.bytes_to_int_two_consumed();
+        let contents_length = self.segment_contents_length();
+        let contents = &self.encoded_image[self.read_index..self.read_index + contents_length];
+        if marker == Marker::APP as u16 && contents.starts_with(b"JFIF\0") {
+            self.jfif = true;
+        } else if marker == Marker::APP14 as u16 && contents.starts_with(b"Adobe") && contents.len() >= 12 {
+            self.adobe_transform = Some(contents[11]);
+        }
+        self.read_index += contents_length;
+    }
+
+    /// Skips over a segment that isn't needed for decoding, such as APPn and COM
+    fn skip_segment(&mut self) {
+        self.found_marker();
+        let contents_length = self.segment_contents_length();
+        self.read_index += contents_length;
+    }
+
+    /// Reads the length of a segment, 10918-1, B.1.1.4, P. 33, which counts its own two bytes, and returns
+    /// that of the rest of the segment, checking it's all there
+    fn segment_contents_length(&mut self) -> usize {
+        assert!(self.read_index + 2 <= self.encoded_image.len(), "The image ends before a segment's length");
+        let length: usize = self.bytes_to_int_two_consumed() as usize;
+        assert!(length >= 2, "A segment's length must count its own two bytes");
+        assert!(
+            length - 2 <= self.encoded_image.len() - self.read_index,
+            "A segment runs past the end of the image"
+        );
+        length - 2
     }
 
     fn make_ssss_tables(&mut self) {
         self.found_marker();
+        let l_h: usize = self.bytes_to_int_two_consumed() as usize;
+        let segment_end = self.read_index + l_h - 2;
 
-        // since I'm returning stuff, should this go in the utils file and then just pass in &mut self
-        let (t_c, t_h, code_lengths) = self.parse_huffman_info();
+        // A single DHT segment may hold several tables
+        while self.read_index < segment_end {
+            // since I'm returning stuff, should this go in the utils file and then just pass in &mut self
+            let (t_c, t_h, code_lengths) = self.parse_huffman_info();
 
-        let (table, min_code_length, max_code_length) = jpeg_utils::make_ssss_table(code_lengths);
+            let (table, min_code_length, max_code_length) = jpeg_utils::make_ssss_table(code_lengths);
 
-        let ssss_table = SSSSTable {
-            t_c,
-            t_h,
-            table,
-            min_code_length,
-            max_code_length,
-        };
+            let ssss_table = SSSSTable {
+                t_c,
+                t_h,
+                table,
+                min_code_length,
+                max_code_length,
+            };
 
-        self.ssss_tables.insert(ssss_table.t_h as usize, ssss_table);
+            self.ssss_tables.insert((ssss_table.t_c, ssss_table.t_h), ssss_table);
+        }
     }
 
-    fn parse_huffman_info(&mut self) -> (u8, u8, [[Option<u8>; 16]; 16]) {
-        let _l_h: u16 = self.bytes_to_int_two_consumed();
+    /// Parses one table of a DHT segment, 10918-1, B.2.4.2, P. 40
+    /// Returns the table class, the destination identifier,
+    /// and the values (HUFFVAL) grouped by code length.
+    fn parse_huffman_info(&mut self) -> (u8, u8, [Vec<u8>; 16]) {
         let t_c_h: u8 = self.byte_to_int_one_consumed();
         let t_c: u8 = t_c_h >> 4;
         let t_h: u8 = t_c_h & 0xF;
-        let mut code_lengths: [[Option<u8>; 16]; 16] = [[None; 16]; 16];
+        let mut code_lengths: [Vec<u8>; 16] = Default::default();
         let mut lengths: BTreeMap<u8, u8> = BTreeMap::new();
         for code_length_index in 0..16 {
             let l_i: u8 = self.byte_to_int_one_consumed();
@@ -250,15 +1528,65 @@ impl Jpeg {
             }
         }
         for (code_length_index, l_i) in lengths.iter() {
-            for i in 0..*l_i {
-                code_lengths[*code_length_index as usize][i as usize] =
-                    Some(self.byte_to_int_one_consumed());
+            for _ in 0..*l_i {
+                code_lengths[*code_length_index as usize].push(self.byte_to_int_one_consumed());
             }
         }
     
         (t_c, t_h, code_lengths)
     }
 
+    /// Parses a DAC segment, which may hold several conditioning table values, 10918-1, B.2.4.3, P. 42
+    fn parse_arithmetic_conditioning(&mut self) {
+        self.found_marker();
+        let l_a: usize = self.bytes_to_int_two_consumed() as usize;
+        let segment_end = self.read_index + l_a - 2;
+
+        while self.read_index < segment_end {
+            let t_c_b: u8 = self.byte_to_int_one_consumed();
+            let c_s: u8 = self.byte_to_int_one_consumed();
+            self.arithmetic_conditioning.insert((t_c_b >> 4, t_c_b & 0xF), c_s);
+        }
+    }
+
+    /// Parses a DHP segment, which is laid out like a frame header, and gives the size and components
+    /// of the final image of a hierarchical image, 10918-1, B.3.2, P. 46
+    fn parse_hierarchical_progression(&mut self) {
+        let marker = self.bytes_to_int_two_peeked();
+        self.parse_frame_header(marker);
+        self.hierarchical_progression = self.frame_header.take();
+    }
+
+    /// Parses an EXP segment, which applies to the references of the next frame, 10918-1, B.3.3, P. 46
+    fn parse_expansion(&mut self) {
+        self.found_marker();
+        let _l_e: u16 = self.bytes_to_int_two_consumed();
+        let e_h_v: u8 = self.byte_to_int_one_consumed();
+        self.expansion = (e_h_v >> 4, e_h_v & 0xF);
+    }
+
+    /// Parses an LSE segment, T.87, C.2.4.1, P. 32. Only preset coding parameters, ID 1, are supported.
+    fn parse_jpeg_ls_presets(&mut self) {
+        self.found_marker();
+        let _l_l: u16 = self.bytes_to_int_two_consumed();
+        let id: u8 = self.byte_to_int_one_consumed();
+        assert_eq!(id, 1, "Only JPEG-LS preset coding parameters are supported");
+        self.jpeg_ls_presets = jpeg_ls::PresetParameters {
+            maxval: self.bytes_to_int_two_consumed(),
+            t1: self.bytes_to_int_two_consumed(),
+            t2: self.bytes_to_int_two_consumed(),
+            t3: self.bytes_to_int_two_consumed(),
+            reset: self.bytes_to_int_two_consumed(),
+        };
+    }
+
+    fn parse_restart_interval(&mut self) {
+        // Define Restart Interval, 10918-1, B.2.4.4, P. 43
+        self.found_marker();
+        let _l_r: u16 = self.bytes_to_int_two_consumed();
+        self.restart_interval = self.bytes_to_int_two_consumed();
+    }
+
     fn parse_frame_header(&mut self, marker: u16) {
         // See JPG document 10918-1 P33 B.1.1.5 Note 2
         self.found_marker();
@@ -268,8 +1596,10 @@ impl Jpeg {
         let x_: u16 = self.bytes_to_int_two_consumed();
         let n_f: usize = self.byte_to_int_one_consumed() as usize;
         let mut components: HashMap<u8, Component> = HashMap::new();
-        for _ in 0..n_f as usize {
+        let mut component_order: Vec<u8> = Vec::with_capacity(n_f);
+        for _ in 0..n_f {
             let c_: u8 = self.byte_to_int_one_consumed();
+            component_order.push(c_);
             let h_v: u8 = self.byte_to_int_one_consumed();
             let t_q: u8 = self.byte_to_int_one_consumed();
             components.insert(
@@ -289,6 +1619,7 @@ impl Jpeg {
             y_,
             x_,
             components,
+            component_order,
         })
     }
 
@@ -344,124 +1675,968 @@ mod tests {
     // }
 
     #[test]
-    fn get_image_data_without_stuffed_zero_bytes_good_reguar_number_then_marker() {
-        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00, 0x05, 0xFF, 0xDA]);
-        let expected_bits: Vec<u8> = Vec::from([
-            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
-            1, 1, 1, 0, 0, 0, 0, 0, 1, 0, 1,
-        ]);
+    fn test_open() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
 
-        let mut image = Jpeg{
-            encoded_image,
-            read_index: 0,
-            frame_header: None,
-            ssss_tables: HashMap::new(),
-            raw_image: Vec::new(),
-        };
+        let image = Jpeg::open(path);
+        
+        assert!(image.encoded_image.len() == 107760);
+        assert_eq!(image.read_index, 2);
+        // assert!(image.raw_image == None);
+    }
+
+    #[test]
+    fn test_from_encoded_vec() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
+        let encoded_image = fs::read(path).expect("Unable to read file");
 
-        let actual_bits = image.get_image_data_without_stuffed_zero_bytes();
+        let image = Jpeg::from_encoded_vec(encoded_image);
+        
+        assert!(image.encoded_image.len() == 107760);
+        assert_eq!(image.read_index, 2);
+        // assert!(image.raw_image == None);
+    }
+
+
+    #[test]
+    fn decode_rows_matches_decode() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
+
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+
+        let mut streamed_image = Jpeg::open(path);
+        let mut streamed: Vec<u32> = Vec::new();
+        let mut row_indices: Vec<usize> = Vec::new();
+        streamed_image.decode_rows(1, |row_index, row| {
+            assert_eq!(row.len(), 320 * 3);
+            row_indices.push(row_index);
+            streamed.extend_from_slice(row);
+        });
+
+        assert_eq!(image.raw_image().len(), 320 * 240 * 3);
+        assert_eq!(streamed, image.raw_image());
+        assert_eq!(row_indices, (0..240).collect::<Vec<usize>>());
+        assert!(streamed_image.raw_image().is_empty());
+    }
+
+    #[test]
+    fn decode_rows_bands() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
+
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+
+        let mut streamed_image = Jpeg::open(path);
+        let mut streamed: Vec<u32> = Vec::new();
+        let mut bands: Vec<(usize, usize)> = Vec::new();
+        streamed_image.decode_rows(100, |first_row, band| {
+            bands.push((first_row, band.len() / (320 * 3)));
+            streamed.extend_from_slice(band);
+        });
+
+        assert_eq!(bands, vec![(0, 100), (100, 100), (200, 40)]);
+        assert_eq!(streamed, image.raw_image());
+    }
+
+    #[test]
+    fn probe_lossless() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
+        let encoded_image = fs::read(path).expect("Unable to read file");
 
-        assert_eq!(actual_bits, expected_bits);
-        assert_eq!(actual_bits.len(), 40);
-        assert_eq!(image.encoded_image[image.read_index], 0xFF);
-        assert_eq!(image.encoded_image[image.read_index + 1], 0xDA);
+        let info = Jpeg::probe(&encoded_image).unwrap();
+
+        assert_eq!(info.width, 320);
+        assert_eq!(info.height, 240);
+        assert_eq!(info.precision, 8);
+        assert_eq!(info.component_count, 3);
+        assert_eq!(info.coding_process, CodingProcess::Lossless);
+        assert_eq!(info.huffman_tables, vec![(0, 0), (0, 1), (0, 2)]);
+        assert_eq!(info.restart_interval, 0);
+        assert_eq!(info.predictor, 5);
+        assert_eq!(info.point_transform, 0);
     }
 
     #[test]
-    fn get_image_data_without_stuffed_zero_bytes_good_padding_then_marker() {
-        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00, 0xFF, 0xDA]);
-        let expected_bits: Vec<u8> = Vec::from([
-            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
-            1, 1, 1,
+    fn probe_skips_other_segments() {
+        let encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xE1, 0x00, 0x04, 0xFF, 0xC3, // APP1 holding something that looks like a marker
+            0xFF, 0xFF, // fill byte
+            0xFF, 0xC1, 0x00, 0x0B, 0x0C, 0x00, 0x10, 0x00, 0x20, 0x01, 0x01, 0x11, 0x00, // SOF1
+            0xFF, 0xC4, 0x00, 0x26, // DHT with two tables
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x05, 0x11, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // and the second
+            0xFF, 0xDD, 0x00, 0x04, 0x00, 0x02, // DRI
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, // SOS
+            0x12, 0x34, 0xFF, 0xD9,
         ]);
 
-        let mut image = Jpeg{
-            encoded_image,
-            read_index: 0,
-            frame_header: None,
-            ssss_tables: HashMap::new(),
-            raw_image: Vec::new(),
-        };
+        let info = Jpeg::probe(&encoded_image).unwrap();
+
+        assert_eq!(info.width, 32);
+        assert_eq!(info.height, 16);
+        assert_eq!(info.precision, 12);
+        assert_eq!(info.component_count, 1);
+        assert_eq!(info.coding_process, CodingProcess::ExtendedSequentialDct);
+        assert_eq!(info.huffman_tables, vec![(0, 0), (1, 1)]);
+        assert_eq!(info.restart_interval, 2);
+        assert_eq!(info.predictor, 0);
+        assert_eq!(jpeg_utils::extract_header_segments(&encoded_image).unwrap().len(), 2 + 13 + 40 + 6 + 10);
+    }
 
-        let actual_bits = image.get_image_data_without_stuffed_zero_bytes();
+    #[test]
+    fn probe_truncated_headers() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
+        let encoded_image = fs::read(path).expect("Unable to read file");
+        let scan_header_end = jpeg_utils::extract_header_segments(&encoded_image).unwrap().len();
 
-        assert_eq!(actual_bits, expected_bits);
-        assert_eq!(actual_bits.len(), 32);
-        assert_eq!(image.encoded_image[image.read_index], 0xFF);
-        assert_eq!(image.encoded_image[image.read_index + 1], 0xDA);
+        // cut anywhere before the end of the first scan header, even mid-length
+        for length in 0..scan_header_end {
+            assert!(Jpeg::probe(&encoded_image[..length]).is_none(), "{length} bytes");
+        }
+        assert!(Jpeg::probe(&encoded_image[..scan_header_end]).is_some());
     }
 
     #[test]
-    fn get_image_data_without_stuffed_zero_bytes_good_reguar_number_with_no_marker() {
-        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00, 0x05]);
-        let expected_bits: Vec<u8> = Vec::from([
-            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
-            1, 1, 1, 0, 0, 0, 0, 0, 1, 0, 1,
+    fn probe_without_a_scan() {
+        let encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xF0, 0x00, 0x03, 0x00, // JPG0, which isn't known, skipped by its length
+            0xFF, 0xC3, 0x00, 0x0B, 0x08, 0x00, 0x02, 0x00, 0x02, 0x01, 0x01, 0x11, 0x00, // SOF3
+            0xFF, 0xD9, // EOI
         ]);
+        assert!(Jpeg::probe(&encoded_image).is_none());
 
-        let mut image = Jpeg{
-            encoded_image,
-            read_index: 0,
-            frame_header: None,
-            ssss_tables: HashMap::new(),
-            raw_image: Vec::new(),
-        };
+        // a scan header with no frame header before it
+        let encoded_image: Vec<u8> = Vec::from([0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00]);
+        assert!(Jpeg::probe(&encoded_image).is_none());
 
-        let actual_bits = image.get_image_data_without_stuffed_zero_bytes();
+        // a frame header shorter than its component count says
+        let encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, 0xFF, 0xC3, 0x00, 0x0B, 0x08, 0x00, 0x02, 0x00, 0x02, 0x02, 0x01, 0x11, 0x00, 0xFF, 0xDA, 0x00,
+            0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00,
+        ]);
+        assert!(Jpeg::probe(&encoded_image).is_none());
+        assert!(Jpeg::probe(b"GIF89a").is_none());
+    }
+
+    #[test]
+    fn probe_skips_unknown_markers() {
+        let encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xF0, 0x00, 0x04, 0xFF, 0xC3, // JPG0, holding something that looks like a marker
+            0xFF, 0xC3, 0x00, 0x0B, 0x08, 0x00, 0x02, 0x00, 0x03, 0x01, 0x01, 0x11, 0x00, // SOF3
+            0xFF, 0x4F, 0x00, 0x02, // a reserved marker, with nothing in it
+            0xFF, 0xC4, 0x00, 0x14, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, // DHT, a single code, 0, for differences of 0
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, // SOS
+            0x03, // 6 zero differences, padded with 1s
+            0xFF, 0xFD, 0x00, 0x02, // JPG13, after the scan
+            0xFF, 0xD9, // EOI
+        ]);
+
+        let info = Jpeg::probe(&encoded_image).unwrap();
 
-        assert_eq!(actual_bits, expected_bits);
-        assert_eq!(actual_bits.len(), 40);
-        assert_eq!(image.read_index, 6);
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.coding_process, CodingProcess::Lossless);
+        assert_eq!(info.predictor, 1);
+
+        let mut image = Jpeg::from_encoded_vec(encoded_image);
+        image.decode();
+        assert_eq!(image.raw_image(), [128; 6]);
     }
 
     #[test]
-    fn get_image_data_without_stuffed_zero_bytes_good_padding_with_no_marker() {
-        let encoded_image: Vec<u8> = Vec::from([0x00, 0xFE, 0x00, 0xFF, 0x00]);
-        let expected_bits: Vec<u8> = Vec::from([
-            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1,
-            1, 1, 1,
+    #[should_panic(expected = "A 0xFF01 marker, which has no segment, can't be between segments")]
+    fn decode_temporary_marker_between_segments() {
+        let encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xC3, 0x00, 0x0B, 0x08, 0x00, 0x02, 0x00, 0x03, 0x01, 0x01, 0x11, 0x00, // SOF3
+            0xFF, 0x01, // TEM
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, // SOS
+            0x03, 0xFF, 0xD9,
         ]);
+        Jpeg::from_encoded_vec(encoded_image).decode();
+    }
 
-        let mut image = Jpeg{
-            encoded_image,
-            read_index: 0,
-            frame_header: None,
-            ssss_tables: HashMap::new(),
-            raw_image: Vec::new(),
+    /// A 3x2 lossless image with a restart interval of `restart_interval` pixels
+    fn lossless_restarted_every(restart_interval: u8) -> Vec<u8> {
+        Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xC3, 0x00, 0x0B, 0x08, 0x00, 0x02, 0x00, 0x03, 0x01, 0x01, 0x11, 0x00, // SOF3
+            0xFF, 0xC4, 0x00, 0x14, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, // DHT, a single code, 0, for differences of 0
+            0xFF, 0xDD, 0x00, 0x04, 0x00, restart_interval, // DRI
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, // SOS
+            0x1F, 0xFF, 0xD0, 0x1F, // 3 zero differences in each interval, padded with 1s
+            0xFF, 0xD9, // EOI
+        ])
+    }
+
+    #[test]
+    fn decode_lossless_restarted_every_row() {
+        let mut image = Jpeg::from_encoded_vec(lossless_restarted_every(3));
+        image.decode();
+
+        assert_eq!(image.raw_image(), [128; 6]);
+    }
+
+    #[test]
+    fn probe_lossless_restart_interval_within_a_row() {
+        assert!(Jpeg::probe(&lossless_restarted_every(3)).is_some());
+        assert!(Jpeg::probe(&lossless_restarted_every(2)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "The image's headers couldn't be read")]
+    fn decode_lossless_restart_interval_within_a_row() {
+        let mut image = Jpeg::from_encoded_vec(lossless_restarted_every(2));
+        image.decode();
+    }
+
+    #[test]
+    #[should_panic(expected = "A segment's length must count its own two bytes")]
+    fn decode_application_segment_too_short() {
+        let mut image = Jpeg::from_encoded_vec(Vec::from([0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x01, 0xFF, 0xD9]));
+        image.decode();
+    }
+
+    #[test]
+    #[should_panic(expected = "A segment runs past the end of the image")]
+    fn decode_application_segment_past_the_end() {
+        let mut image = Jpeg::from_encoded_vec(Vec::from([0xFF, 0xD8, 0xFF, 0xEE, 0x00, 0x10, b'A', b'd', b'o']));
+        image.decode();
+    }
+
+    #[test]
+    #[should_panic(expected = "A segment's length must count its own two bytes")]
+    fn decode_comment_too_short() {
+        let mut image = Jpeg::from_encoded_vec(Vec::from([0xFF, 0xD8, 0xFF, 0xFE, 0x00, 0x00, 0xFF, 0xD9]));
+        image.decode();
+    }
+
+    #[test]
+    fn decode_baseline_420_with_restarts() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420.jpg");
+        let mut image = Jpeg::open(path);
+        image.set_raw_output(true);
+        image.set_upsampling(Upsampling::Nearest);
+        image.decode();
+
+        // libjpeg's output for the same file, as YCbCr, upsampled by replication
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420.ycc");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.restart_interval, 3);
+        assert_eq!(image.quantization_tables.len(), 2);
+        assert_eq!(image.raw_image().len(), 43 * 29 * 3);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_baseline_420_rgb() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420.jpg");
+        let mut image = Jpeg::open(path);
+        image.set_upsampling(Upsampling::Nearest);
+        image.decode();
+
+        // libjpeg's output for the same file, as RGB, upsampled by replication
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420.rgb");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert!(image.jfif);
+        assert_eq!(image.color_space(), ColorSpace::YCbCr);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_fancy_upsampling() {
+        // 37x23, so the subsampled components have odd sizes, compared to libjpeg's fancy upsampled YCbCr
+        for subsampling in ["420", "422", "440"] {
+            let mut path = env::current_dir().unwrap();
+            path.push(format!("tests/common/fancy_{}.jpg", subsampling));
+            let mut image = Jpeg::open(path);
+            image.set_raw_output(true);
+            image.decode();
+
+            let mut path = env::current_dir().unwrap();
+            path.push(format!("tests/common/fancy_{}.ycc", subsampling));
+            let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+            assert_eq!(image.raw_image(), expected, "{}", subsampling);
+        }
+    }
+
+    #[test]
+    fn decode_idct_methods() {
+        for (idct_method, name) in [(IdctMethod::FastInteger, "ifast"), (IdctMethod::Float, "float")] {
+            let mut path = env::current_dir().unwrap();
+            path.push("tests/common/fancy_420.jpg");
+            let mut image = Jpeg::open(path);
+            image.set_raw_output(true);
+            image.set_idct_method(idct_method);
+            image.decode();
+
+            // libjpeg-turbo's output with the same IDCT
+            let mut path = env::current_dir().unwrap();
+            path.push(format!("tests/common/fancy_420_{}.ycc", name));
+            let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+            assert_eq!(image.raw_image(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn decode_scaled() {
+        // compared to libjpeg's scaled output, with the chroma of 4:2:0 getting larger blocks rather than upsampling
+        for subsampling in ["420", "422"] {
+            for denominator in [2, 4, 8] {
+                let mut path = env::current_dir().unwrap();
+                path.push(format!("tests/common/fancy_{}.jpg", subsampling));
+                let mut image = Jpeg::open(path);
+                image.set_raw_output(true);
+                image.set_scale(denominator);
+                image.decode();
+
+                let mut path = env::current_dir().unwrap();
+                path.push(format!("tests/common/fancy_{}_scale_{}.ycc", subsampling, denominator));
+                let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+                let (width, height) = (37_usize.div_ceil(denominator as usize), 23_usize.div_ceil(denominator as usize));
+                assert_eq!(image.raw_image().len(), width * height * 3);
+                assert_eq!(image.raw_image(), expected, "{} at 1/{}", subsampling, denominator);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_ycck() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/ycck.jpg");
+        let encoded_image = fs::read(path).expect("Unable to read file");
+        assert_eq!(Jpeg::probe(&encoded_image).unwrap().color_space, ColorSpace::Ycck);
+        let mut image = Jpeg::from_encoded_vec(encoded_image);
+        image.set_upsampling(Upsampling::Nearest);
+        image.decode();
+
+        // libjpeg's output, which is Adobe's inverted CMYK, inverted
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/ycck.cmyk");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_adobe_cmyk() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/adobe_cmyk.jpg");
+        let mut raw = Jpeg::open(path.clone());
+        raw.set_raw_output(true);
+        raw.decode();
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/adobe_cmyk.cmyk");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.color_space(), ColorSpace::InvertedCmyk);
+        assert_eq!(image.raw_image(), expected);
+        assert!(raw.raw_image().iter().zip(expected).all(|(r, e)| r + e == 255));
+    }
+
+    #[test]
+    fn decode_baseline_gray() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_gray.jpg");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_gray.raw");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_extended_sequential_12_bit() {
+        let mut encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xDB, 0x00, 0x83, 0x10, // DQT, 16 bit elements, all 2
+        ]);
+        encoded_image.extend([0x00, 0x02].repeat(64));
+        encoded_image.extend([
+            0xFF, 0xC1, 0x00, 0x0B, 0x0C, 0x00, 0x08, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00, // SOF1, 12 bit, 16x8
+            0xFF, 0xC4, 0x00, 0x29, // DHT
+            0x02, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x09, 0x0B, // DC table 2; SSSS 0, 9 and 11 as 00, 01 and 10
+            0x13, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x0A, // AC table 3; EOB and RS 0/10 as 00 and 01
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x23, 0x00, 0x3F, 0x00, // SOS
+            // DC +1500, AC -600, EOB, then DC -1500, EOB
+            0xAE, 0xE2, 0xD3, 0x92, 0x23, 0x3F,
+            0xFF, 0xD9, // EOI
+        ]);
+        let mut image = Jpeg::from_encoded_vec(encoded_image);
+        image.decode();
+
+        let blocks = &image.coefficients[&1];
+        assert_eq!(blocks[0][0], 1500);
+        assert_eq!(blocks[0][1], -600);
+        assert_eq!(blocks[0][2..], [0; 62]);
+        assert_eq!(blocks[1], [0; 64]);
+
+        let first_block = dct::idct_islow(&blocks[0], &[2; 64], 12);
+        for y in 0..8 {
+            let row = &image.raw_image()[y * 16..y * 16 + 16];
+            // well outside what 8 bits could hold, and brightening left to right
+            assert!(row[0] > 2048 && row[7] > 2600 && row[7] < 4096);
+            assert!(row.windows(2).take(7).all(|pair| pair[0] < pair[1]));
+            for x in 0..8 {
+                assert_eq!(row[x], first_block[y * 8 + x] as u32);
+            }
+            // DC of 0 is just the level shift
+            assert_eq!(row[8..], [2048; 8]);
+        }
+    }
+
+    #[test]
+    fn decode_12_bit_as_libjpeg_turbo() {
+        // Encoded and decoded by cjpeg and djpeg built with BITS_IN_JSAMPLE=12 from the libjpeg-turbo 2.1
+        // sources vendored by mozjpeg-sys 2.2.3, from 37x23 images of 12 bit samples:
+        //   cjpeg -revert -quality 85 -sample 2x2 -outfile dct_12_bit_420.jpg rgb.ppm
+        //   cjpeg -revert -quality 90 -progressive -outfile dct_12_bit_gray_progressive.jpg gray.pgm
+        //   djpeg -outfile <name>.ppm <name>.jpg
+        // The expected samples are djpeg's PPM and PGM samples, big endian, with their headers cut off.
+        for (name, expected_name, marker) in [
+            ("dct_12_bit_420.jpg", "dct_12_bit_420.rgb", Marker::SOF1),
+            ("dct_12_bit_gray_progressive.jpg", "dct_12_bit_gray_progressive.gray", Marker::SOF2),
+        ] {
+            let mut path = env::current_dir().unwrap();
+            path.push("tests/common");
+            let mut image = Jpeg::open(path.join(name));
+            image.decode();
+
+            let expected: Vec<u32> = fs::read(path.join(expected_name))
+                .unwrap()
+                .chunks(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]) as u32)
+                .collect();
+
+            let frame_header = image.frame_header.as_ref().unwrap();
+            assert_eq!(frame_header.marker, marker as u16);
+            assert_eq!(frame_header.p_, 12);
+            assert_eq!(image.raw_image().len(), expected.len(), "{name}");
+            assert_eq!(image.raw_image(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn decode_progressive_420_with_restarts() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/progressive_420.jpg");
+        let mut image = Jpeg::open(path);
+        image.set_raw_output(true);
+        image.set_upsampling(Upsampling::Nearest);
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/progressive_420.ycc");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert!(image.restart_interval > 0);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_progressive_gray() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/progressive_gray.jpg");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/progressive_gray.raw");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_arithmetic_420_with_restarts() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_420.jpg");
+        let mut image = Jpeg::open(path);
+        image.set_raw_output(true);
+        image.set_upsampling(Upsampling::Nearest);
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_420.ycc");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        // coded with L = 1, U = 4 and Kx = 12 rather than the defaults
+        assert_eq!(image.arithmetic_conditioning[&(0, 0)], 0x41);
+        assert_eq!(image.arithmetic_conditioning[&(1, 1)], 12);
+        assert_eq!(image.restart_interval, 3);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_arithmetic_progressive_420() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_progressive_420.jpg");
+        let mut image = Jpeg::open(path);
+        image.set_raw_output(true);
+        image.set_upsampling(Upsampling::Nearest);
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_progressive_420.ycc");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_arithmetic_lossless() {
+        // 17x11, 3 components, predictor 6, with the second and third components sharing a table.
+        // No encoder at hand writes SOF11, so it's written by tests/common/arithmetic_lossless.py,
+        // a QM-coder whose output is transliterated from libjpeg's jcarith.c: python3 arithmetic_lossless.py
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_lossless.jpg");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_lossless.raw");
+        let expected: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+
+        assert_eq!(image.arithmetic_conditioning[&(0, 0)], 0x31);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_hierarchical() {
+        // A 16x16 image coded as an 8x8 DCT frame, expanded both ways for a differential DCT frame,
+        // whose blocks only have DC terms, followed by a differential lossless frame of the same size
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/hierarchical.jpg");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        let levels = image.hierarchical_levels();
+        assert_eq!(levels.len(), 3);
+        assert_eq!((levels[0].width, levels[0].height), (8, 8));
+        assert_eq!(levels[0].samples, vec![100; 64]);
+        assert_eq!((levels[1].width, levels[1].height), (16, 16));
+        assert_eq!(levels[1].samples[0..16], [[108; 8], [92; 8]].concat());
+        assert_eq!(levels[1].samples[16 * 15..], [[116; 8], [100; 8]].concat());
+
+        let expected: Vec<u32> = (0..16).flat_map(|y| (0..16).map(move |x| x * 11 + y * 4)).collect();
+        assert_eq!(levels[2].samples, expected);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn decode_hierarchical_ycbcr() {
+        // An 8x8 frame of Y 100, Cb 90 and Cr 160 expanded for a 16x16 differential DCT frame, coded in a scan
+        // for each component, with a constant difference in each quarter, written by tests/common/hierarchical_components.py
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/hierarchical_ycbcr.jpg");
+        let quarters = |image: &Jpeg, pixels: [[u32; 3]; 4]| {
+            let pixel_at = |x: usize, y: usize| pixels[y / 8 * 2 + x / 8];
+            let expected: Vec<u32> = (0..16).flat_map(|y| (0..16).flat_map(move |x| pixel_at(x, y))).collect();
+            assert_eq!(image.raw_image(), expected);
         };
 
-        let actual_bits = image.get_image_data_without_stuffed_zero_bytes();
+        let mut raw = Jpeg::open(path.clone());
+        raw.set_raw_output(true);
+        raw.decode();
+        quarters(&raw, [[108, 80, 160], [92, 90, 164], [116, 90, 156], [100, 100, 160]]);
 
-        assert_eq!(actual_bits, expected_bits);
-        assert_eq!(actual_bits.len(), 32);
-        assert_eq!(image.read_index, 5);
+        // each worked out as in T.871, 7, P. 4, with libjpeg's fixed point arithmetic, so for the first quarter,
+        // R is 108 + 1.402 * 32, G is 108 - 0.34414 * -48 - 0.71414 * 32 and B is 108 + 1.772 * -48
+        let mut image = Jpeg::open(path);
+        image.decode();
+        quarters(&image, [[153, 102, 23], [142, 79, 25], [155, 109, 49], [145, 87, 50]]);
     }
 
     #[test]
-    fn test_open() {
+    fn decode_hierarchical_lossless_components() {
+        // The same 8x8 frame expanded for a 16x16 differential lossless frame coded in a scan for each component,
+        // each with its own Huffman table, which add x to Y, y to Cb and take x from Cr, written by
+        // tests/common/hierarchical_components.py. The last frame isn't a DCT frame, so there's no color conversion.
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/hierarchical_lossless_components.jpg");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        let levels = image.hierarchical_levels();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[1].component_identifiers, [1, 2, 3]);
+        let expected: Vec<u32> = (0..16).flat_map(|y| (0..16).flat_map(move |x| [100 + x, 90 + y, 160 - x])).collect();
+        assert_eq!(levels[1].samples, expected);
+        assert_eq!(image.raw_image(), expected);
+    }
+
+    #[test]
+    fn probe_hierarchical() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/hierarchical.jpg");
+        let encoded_image = fs::read(path).expect("Unable to read file");
+
+        let info = Jpeg::probe(&encoded_image).unwrap();
+
+        assert!(info.hierarchical);
+        assert_eq!((info.width, info.height), (16, 16));
+        assert_eq!(info.coding_process, CodingProcess::ExtendedSequentialDct);
+    }
+
+    #[test]
+    fn decode_jpeg_ls_example() {
+        // the 4x4 example of ITU T.87, H.3, P. 64, which has both regular and run mode samples
+        let encoded_image: Vec<u8> = Vec::from([
+            0xFF, 0xD8, // SOI
+            0xFF, 0xF7, 0x00, 0x0B, 0x08, 0x00, 0x04, 0x00, 0x04, 0x01, 0x01, 0x11, 0x00, // SOF55
+            0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, // SOS, NEAR 0, ILV 0
+            0xC0, 0x00, 0x00, 0x6C, 0x80, 0x20, 0x8E, 0x01, 0xC0, 0x00, 0x00, 0x57, 0x40, 0x00, 0x00, 0x6E,
+            0xE6, 0x00, 0x00, 0x01, 0xBC, 0x18, 0x00, 0x00, 0x05, 0xD8, 0x00, 0x00, 0x91, 0x60,
+            0xFF, 0xD9, // EOI
+        ]);
+        let mut image = Jpeg::from_encoded_vec(encoded_image);
+        image.decode();
+
+        assert_eq!(
+            image.raw_image(),
+            [0, 0, 90, 74, 68, 50, 43, 205, 64, 145, 145, 145, 100, 145, 145, 145]
+        );
+    }
+
+    /// The image the JPEG-LS fixtures were coded from, all written by tests/common/jpeg_ls.py
+    fn jpeg_ls_reference() -> Vec<u32> {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/jpeg_ls.raw");
+        fs::read(path).unwrap().iter().map(|s| *s as u32).collect()
+    }
+
+    #[test]
+    fn decode_jpeg_ls_sample_interleaved() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/jpeg_ls_sample_interleaved.jls");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        assert_eq!(image.raw_image(), jpeg_ls_reference());
+    }
+
+    #[test]
+    fn decode_jpeg_ls_non_interleaved_with_presets() {
+        // one scan per component, with T1, T2, T3 and RESET set by an LSE segment
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/jpeg_ls_non_interleaved.jls");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        assert_eq!(image.jpeg_ls_presets.reset, 32);
+        assert_eq!(image.raw_image(), jpeg_ls_reference());
+    }
+
+    #[test]
+    fn decode_jpeg_ls_line_interleaved_near_lossless() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/jpeg_ls_line_interleaved_near_3.jls");
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        // the samples the encoder, tests/common/jpeg_ls.py, reconstructed as it coded them
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/jpeg_ls_line_interleaved_near_3.raw");
+        let reconstructed: Vec<u32> = fs::read(path).unwrap().iter().map(|s| *s as u32).collect();
+        let decoded = image.raw_image();
+        assert_eq!(decoded, reconstructed);
+
+        let expected = jpeg_ls_reference();
+        assert_eq!(decoded.len(), expected.len());
+        assert!(decoded.iter().zip(expected).all(|(d, e)| d.abs_diff(e) <= 3));
+    }
+
+    fn crop(
+        samples: &[u32],
+        image_width: usize,
+        component_count: usize,
+        (x, y, width, height): (usize, usize, usize, usize),
+    ) -> Vec<u32> {
+        samples
+            .chunks(image_width * component_count)
+            .skip(y)
+            .take(height)
+            .flat_map(|row| row[x * component_count..(x + width) * component_count].iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn decode_region_skips_restart_intervals() {
+        // 40x96 4:2:0, with a restart interval for each of its 6 MCU rows
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420_tall.jpg");
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+        let mut region = Jpeg::open(path);
+        region.decode_region(7, 81, 20, 9);
+
+        assert_eq!(region.raw_image(), crop(image.raw_image(), 40, 3, (7, 81, 20, 9)));
+        // the first 4 MCU rows were skipped, each being 2 rows of blocks of luminance 5 blocks wide
+        let luminance = &region.coefficients[&1];
+        assert!(luminance[..4 * 2 * 5].iter().all(|block| *block == [0; 64]));
+        assert!(luminance[4 * 2 * 5..].iter().any(|block| *block != [0; 64]));
+    }
+
+    #[test]
+    fn decode_region_only_looks_for_markers_in_skipped_intervals() {
+        // The same image with the coded data of the first 4 restart intervals swapped for stuffed 0xFF bytes,
+        // all 1 bits, which aren't a Huffman code, so it can only be decoded by not decoding them
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420_tall.jpg");
+        let encoded_image = fs::read(path).unwrap();
+        let scan_start = encoded_image.windows(2).position(|bytes| bytes == [0xFF, 0xDA]).unwrap() + 2 + 12;
+        let rst3 = scan_start + encoded_image[scan_start..].windows(2).position(|bytes| bytes == [0xFF, 0xD3]).unwrap();
+        let mut corrupted = encoded_image[..scan_start].to_vec();
+        for m in 0..4 {
+            corrupted.extend([0xFF, 0x00].repeat(16));
+            if m < 3 {
+                corrupted.extend([0xFF, 0xD0 + m]);
+            }
+        }
+        corrupted.extend_from_slice(&encoded_image[rst3..]);
+
+        let mut image = Jpeg::from_encoded_vec(encoded_image);
+        image.decode();
+        let mut region = Jpeg::from_encoded_vec(corrupted);
+        region.decode_region(7, 81, 20, 9);
+
+        assert_eq!(region.raw_image(), crop(image.raw_image(), 40, 3, (7, 81, 20, 9)));
+    }
+
+    #[test]
+    fn decode_region_after_decode() {
+        for name in ["F-18.ljpg", "baseline_420.jpg"] {
+            let mut path = env::current_dir().unwrap();
+            path.push("tests/common");
+            path.push(name);
+            let info = Jpeg::probe(&fs::read(&path).unwrap()).unwrap();
+            let mut image = Jpeg::open(path);
+            image.decode();
+            let decoded = image.raw_image().to_vec();
+
+            image.decode_region(0, 0, 4, 4);
+            assert_eq!(image.raw_image(), crop(&decoded, info.width as usize, info.component_count, (0, 0, 4, 4)));
+            image.decode();
+            assert_eq!(image.raw_image(), decoded, "{name}");
+        }
+    }
+
+    #[test]
+    fn decode_region_progressive_and_scaled() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/progressive_420.jpg");
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+        let mut region = Jpeg::open(path);
+        region.decode_region(3, 10, 30, 12);
+        assert_eq!(region.raw_image(), crop(image.raw_image(), 43, 3, (3, 10, 30, 12)));
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/fancy_420.jpg");
+        let mut image = Jpeg::open(path.clone());
+        image.set_scale(2);
+        image.decode();
+        let mut region = Jpeg::open(path);
+        region.set_scale(2);
+        region.decode_region(5, 4, 9, 7);
+        assert_eq!(region.raw_image(), crop(image.raw_image(), 19, 3, (5, 4, 9, 7)));
+    }
+
+    #[test]
+    fn decode_region_lossless() {
         let mut path = env::current_dir().unwrap();
         path.push("tests/common/F-18.ljpg");
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+        let mut region = Jpeg::open(path);
+        region.decode_region(100, 50, 64, 32);
+        assert_eq!(region.raw_image(), crop(image.raw_image(), 320, 3, (100, 50, 64, 32)));
 
-        let image = Jpeg::open(path);
-        
-        assert!(image.encoded_image.len() == 107760);
-        assert_eq!(image.read_index, 2);
-        // assert!(image.raw_image == None);
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/jpeg_ls_non_interleaved.jls");
+        let mut region = Jpeg::open(path);
+        region.decode_region(20, 0, 3, 13);
+        assert_eq!(region.raw_image(), crop(&jpeg_ls_reference(), 23, 3, (20, 0, 3, 13)));
     }
 
     #[test]
-    fn test_from_encoded_vec() {
+    fn read_coefficients_sequential_and_progressive() {
+        // the same image, coded sequentially and progressively, checked against libjpeg's coefficients
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/fancy_420.jpg");
+        let sequential = Jpeg::open(path).read_coefficients();
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/fancy_420_progressive.jpg");
+        let progressive = Jpeg::open(path).read_coefficients();
+
+        assert_eq!(sequential, progressive);
+        assert_eq!(sequential.len(), 3);
+        let luminance = &sequential[0];
+        assert_eq!(luminance.sampling_factors, (2, 2));
+        assert_eq!((luminance.blocks_wide, luminance.blocks_high), (5, 3));
+        assert_eq!((luminance.padded_blocks_wide, luminance.padded_blocks_high), (6, 4));
+        assert_eq!(luminance.quantization_table.q_k[0], 5);
+        assert_eq!(luminance.blocks[0][..10], [-92, -51, 14, 5, -8, 1, 2, -2, -32, -18]);
+        assert_eq!(luminance.blocks[1][..10], [-88, 5, 23, 3, 1, -2, 0, -1, -37, -5]);
+        let blue = &sequential[1];
+        assert_eq!((blue.blocks_wide, blue.blocks_high), (3, 2));
+        assert_eq!(blue.blocks[1][..10], [19, 2, -5, -2, 0, 0, 0, 0, 34, -8]);
+    }
+
+    #[test]
+    fn transcode_f_18_with_optimal_huffman_tables() {
+        // F-18 was written with optimal tables for each component, so it comes back as it was
         let mut path = env::current_dir().unwrap();
         path.push("tests/common/F-18.ljpg");
-        let encoded_image = fs::read(path).expect("Unable to read file");
+        let encoded = fs::read(&path).unwrap();
 
-        let image = Jpeg::from_encoded_vec(encoded_image);
-        
-        assert!(image.encoded_image.len() == 107760);
-        assert_eq!(image.read_index, 2);
-        // assert!(image.raw_image == None);
+        assert_eq!(Jpeg::from_encoded_vec(encoded.clone()).transcode_with_optimal_huffman_tables(), encoded);
+    }
+
+    #[test]
+    fn transcode_as_if_encoded_with_optimal_huffman_tables() {
+        let samples: Vec<u32> = (0..19 * 9 * 3).map(|i| ((i * 37) % 1024 + (i % 3) * 700) as u32 % 4096).collect();
+        let mut encoder = LosslessEncoder::new(samples.clone(), 19, 9, 3, 12);
+        encoder.set_predictor(5);
+        let default_tables = encoder.encode();
+        encoder.set_optimize_huffman_table(true);
+
+        let transcoded = Jpeg::from_encoded_vec(default_tables.clone()).transcode_with_optimal_huffman_tables();
+        assert_eq!(transcoded, encoder.encode());
+
+        // restart intervals are kept, and their markers rewritten
+        encoder.set_optimize_huffman_table(false);
+        encoder.set_restart_interval_in_rows(2);
+        let encoded = encoder.encode();
+        let transcoded = Jpeg::from_encoded_vec(encoded.clone()).transcode_with_optimal_huffman_tables();
+        assert!(transcoded.len() < encoded.len());
+        let mut image = Jpeg::from_encoded_vec(transcoded);
+        image.decode();
+        assert_eq!(image.raw_image(), samples);
+    }
+
+    #[test]
+    fn transcode_after_and_before_decoding() {
+        let samples: Vec<u32> = (0..11 * 7).map(|i| (i * 29 % 256) as u32).collect();
+        let mut encoder = LosslessEncoder::new(samples.clone(), 11, 7, 1, 8);
+        encoder.set_restart_interval_in_rows(3);
+        let encoded = encoder.encode();
+        let transcoded = Jpeg::from_encoded_vec(encoded.clone()).transcode_with_optimal_huffman_tables();
+        assert!(transcoded.len() < encoded.len());
+
+        let mut image = Jpeg::from_encoded_vec(encoded.clone());
+        image.decode();
+        assert_eq!(image.transcode_with_optimal_huffman_tables(), transcoded);
+        assert_eq!(image.transcode_with_optimal_huffman_tables(), transcoded);
+
+        let mut image = Jpeg::from_encoded_vec(encoded);
+        assert_eq!(image.transcode_with_optimal_huffman_tables(), transcoded);
+        image.decode();
+        assert_eq!(image.raw_image(), samples);
+    }
+
+    #[test]
+    fn decode_rows_baseline() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420.jpg");
+
+        let mut image = Jpeg::open(path.clone());
+        image.decode();
+
+        let mut streamed: Vec<u32> = Vec::new();
+        Jpeg::open(path).decode_rows(8, |_, band| streamed.extend_from_slice(band));
+
+        assert_eq!(streamed, image.raw_image());
     }
 
+    #[test]
+    fn parse_quantization_tables_16_bit() {
+        let mut encoded_image: Vec<u8> = Vec::from([0xFF, 0xDB, 0x00, 0x83, 0x12]);
+        for k in 0..64u16 {
+            encoded_image.extend((k * 1000).to_be_bytes());
+        }
+        let mut image = Jpeg::from_encoded_vec(Vec::from([0xFF, 0xD8]));
+        image.encoded_image = encoded_image;
+        image.read_index = 0;
+
+        image.parse_quantization_tables();
+
+        let table = &image.quantization_tables()[&2];
+        assert_eq!(image.read_index, 133);
+        assert_eq!(table.p_q, 1);
+        assert_eq!(table.t_q, 2);
+        for (k, natural) in dct::ZIGZAG.iter().enumerate() {
+            assert_eq!(table.q_k[*natural], k as u16 * 1000);
+        }
+    }
+
+    #[test]
+    fn probe_quantization_tables() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/baseline_420.jpg");
+        let encoded_image = fs::read(path.clone()).expect("Unable to read file");
+
+        let info = Jpeg::probe(&encoded_image).unwrap();
+        let mut image = Jpeg::open(path);
+        image.decode();
+
+        assert_eq!(info.coding_process, CodingProcess::BaselineDct);
+        assert_eq!(info.quantization_tables.len(), 2);
+        assert_eq!(&info.quantization_tables, image.quantization_tables());
+        // libjpeg's quality 75 luminance table, Annex K, Table K.1, P. 143, scaled by 1/2
+        assert_eq!(info.quantization_tables[&0].q_k[0..8], [8, 6, 5, 8, 12, 20, 26, 31]);
+        assert_eq!(info.quantization_tables[&0].q_k[8], 6);
+    }
+
+    #[test]
+    fn parse_quantization_tables_two_in_one_segment() {
+        let mut encoded_image: Vec<u8> = Vec::from([0xFF, 0xDB, 0x00, 0x84, 0x00]);
+        encoded_image.extend(1..=64);
+        encoded_image.push(0x01);
+        encoded_image.extend([2; 64]);
+        let mut image = Jpeg::from_encoded_vec(Vec::from([0xFF, 0xD8]));
+        image.encoded_image = encoded_image;
+        image.read_index = 0;
+
+        image.parse_quantization_tables();
+
+        assert_eq!(image.read_index, 134);
+        assert_eq!(image.quantization_tables[&0].p_q, 0);
+        assert_eq!(image.quantization_tables[&0].q_k[0], 1);
+        assert_eq!(image.quantization_tables[&0].q_k[63], 64);
+        assert_eq!(image.quantization_tables[&0].q_k[8], 3);
+        assert_eq!(image.quantization_tables[&1].t_q, 1);
+        assert_eq!(image.quantization_tables[&1].q_k, [2; 64]);
+    }
 
     #[test]
     fn parse_frame_header_good() {
@@ -498,7 +2673,23 @@ mod tests {
             read_index: 0,
             ssss_tables: HashMap::new(),
             frame_header: None,
-            // quantization_tables: None,
+            quantization_tables: HashMap::new(),
+            restart_interval: 0,
+            arithmetic_conditioning: HashMap::new(),
+            coefficients: HashMap::new(),
+            hierarchical_progression: None,
+            expansion: (0, 0),
+            hierarchical_levels: Vec::new(),
+            jpeg_ls_presets: jpeg_ls::PresetParameters::default(),
+            component_planes: HashMap::new(),
+            jfif: false,
+            adobe_transform: None,
+            raw_output: false,
+            upsampling: Upsampling::Fancy,
+            idct_method: IdctMethod::AccurateInteger,
+            scale_denominator: 1,
+            region: None,
+            coefficients_only: false,
             raw_image: Vec::new(),
         };
 
@@ -513,7 +2704,23 @@ mod tests {
             read_index: 0,
             ssss_tables: HashMap::new(),
             frame_header: None,
-            // quantization_tables: None,
+            quantization_tables: HashMap::new(),
+            restart_interval: 0,
+            arithmetic_conditioning: HashMap::new(),
+            coefficients: HashMap::new(),
+            hierarchical_progression: None,
+            expansion: (0, 0),
+            hierarchical_levels: Vec::new(),
+            jpeg_ls_presets: jpeg_ls::PresetParameters::default(),
+            component_planes: HashMap::new(),
+            jfif: false,
+            adobe_transform: None,
+            raw_output: false,
+            upsampling: Upsampling::Fancy,
+            idct_method: IdctMethod::AccurateInteger,
+            scale_denominator: 1,
+            region: None,
+            coefficients_only: false,
             raw_image: Vec::new(),
         };
 
@@ -528,7 +2735,23 @@ mod tests {
             read_index: 0,
             ssss_tables: HashMap::new(),
             frame_header: None,
-            // quantization_tables: None,
+            quantization_tables: HashMap::new(),
+            restart_interval: 0,
+            arithmetic_conditioning: HashMap::new(),
+            coefficients: HashMap::new(),
+            hierarchical_progression: None,
+            expansion: (0, 0),
+            hierarchical_levels: Vec::new(),
+            jpeg_ls_presets: jpeg_ls::PresetParameters::default(),
+            component_planes: HashMap::new(),
+            jfif: false,
+            adobe_transform: None,
+            raw_output: false,
+            upsampling: Upsampling::Fancy,
+            idct_method: IdctMethod::AccurateInteger,
+            scale_denominator: 1,
+            region: None,
+            coefficients_only: false,
             raw_image: Vec::new(),
         };
 
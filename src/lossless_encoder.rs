@@ -0,0 +1,564 @@
+use crate::arithmetic::{self, ArithmeticEncoder};
+use crate::encoder::{self, BitWriter};
+use crate::jpeg_utils::{self, ContextContext};
+use crate::Marker;
+
+/// Encodes samples as a lossless image, Huffman coded (SOF3) or arithmetic coded (SOF11), 10918-1, H, P. 132
+pub struct LosslessEncoder {
+    samples: Vec<u32>, // interleaved, row by row
+    width: u16,
+    height: u16,
+    component_count: usize,
+    precision: u8,
+    predictor: u8,
+    point_transform: u8, // Pt, the low bits of each sample that are dropped
+    optimize_huffman_table: bool, // whether the Huffman table is built for the image rather than the default one
+    restart_interval: u16,        // MCUs, which are pixels, in each restart interval, or 0 if the scan isn't restarted
+    arithmetic_coding: bool,
+    arithmetic_conditioning: u8, // the DAC Cs value of the table, U over L
+}
+
+impl LosslessEncoder {
+    /// An encoder for `width` by `height` pixels of `component_count` interleaved samples,
+    /// each of `precision` bits, using predictor 1 until another is chosen
+    pub fn new(samples: Vec<u32>, width: u16, height: u16, component_count: usize, precision: u8) -> Self {
+        assert!((2..=16).contains(&precision), "Lossless samples are 2 to 16 bits");
+        assert!((1..=4).contains(&component_count), "A scan can have 1 to 4 components");
+        assert!(width > 0 && height > 0, "An image must have at least one pixel");
+        assert_eq!(
+            samples.len(),
+            width as usize * height as usize * component_count,
+            "There must be a sample of each component for every pixel"
+        );
+        assert!(samples.iter().all(|sample| *sample >> precision == 0), "Samples must fit in the precision");
+
+        Self {
+            samples,
+            width,
+            height,
+            component_count,
+            precision,
+            predictor: 1,
+            point_transform: 0,
+            optimize_huffman_table: false,
+            restart_interval: 0,
+            arithmetic_coding: false,
+            arithmetic_conditioning: arithmetic::DEFAULT_DC_CONDITIONING,
+        }
+    }
+
+    /// Which of the predictors, 1 to 7, of Table H.1, P. 133, samples are predicted with
+    pub fn set_predictor(&mut self, predictor: u8) {
+        assert!((1..=7).contains(&predictor), "Lossless predictors are 1 to 7");
+        self.predictor = predictor;
+    }
+
+    /// Drops the low `point_transform` bits of each sample before it's predicted and coded, H.1.2.1, P. 134,
+    /// for a near-lossless image that decodes to samples less than 2^Pt from the originals, and that codes in fewer bits
+    pub fn set_point_transform(&mut self, point_transform: u8) {
+        assert!(point_transform < self.precision, "The point transform must leave at least one bit of each sample");
+        self.point_transform = point_transform;
+    }
+
+    /// Whether to code the image with the Huffman table that makes it smallest, 10918-1, K.2, P. 144,
+    /// which takes a pass over the image to count its SSSS categories, rather than a default one
+    pub fn set_optimize_huffman_table(&mut self, optimize_huffman_table: bool) {
+        self.optimize_huffman_table = optimize_huffman_table;
+    }
+
+    /// Whether to code the differences with the QM-coder, H.1.4.3, P. 135, for an SOF11 image,
+    /// rather than Huffman code them
+    pub fn set_arithmetic_coding(&mut self, arithmetic_coding: bool) {
+        self.arithmetic_coding = arithmetic_coding;
+    }
+
+    /// The conditioning of the arithmetic coding statistics, written in a DAC segment, 10918-1, B.2.4.3, P. 40:
+    /// differences of magnitude below 2^(L - 1) count as zero in the contexts of the next ones, and those above
+    /// 2^(U - 1) as large, F.1.4.4.1.2, P. 97. The defaults are L = 0 and U = 1.
+    pub fn set_arithmetic_conditioning(&mut self, l: u8, u: u8) {
+        assert!(l <= u && u <= 15, "The conditioning bounds must have 0 <= L <= U <= 15");
+        self.arithmetic_conditioning = (u << 4) | l;
+    }
+
+    /// Restarts the scan every `mcus` pixels, with a DRI segment and RSTm markers, 10918-1, B.2.1, P. 34,
+    /// so that its intervals can be decoded independently; 0 doesn't restart it. Each interval must be
+    /// whole rows, as libjpeg requires, since their first rows are predicted like the first row of the image.
+    pub fn set_restart_interval(&mut self, mcus: u16) {
+        assert_eq!(mcus % self.width, 0, "Lossless restart intervals must be whole rows");
+        self.restart_interval = mcus;
+    }
+
+    /// Restarts the scan every `rows` rows; 0 doesn't restart it
+    pub fn set_restart_interval_in_rows(&mut self, rows: u16) {
+        let mcus = rows as usize * self.width as usize;
+        assert!(mcus <= u16::MAX as usize, "A restart interval is at most 65535 MCUs");
+        self.restart_interval = mcus as u16;
+    }
+
+    /// Tries each predictor on `sample_rows` rows spread evenly over the image, or on all of them if `None`,
+    /// and picks the one that codes them in the fewest bits, with the Huffman table the image would be coded with,
+    /// or with the QM-coder if it's arithmetic coded, in which case the sampled rows are coded as one interval.
+    /// Returns the number of bits of entropy coded data each predictor, 1 to 7, gave.
+    pub fn select_predictor(&mut self, sample_rows: Option<usize>) -> [usize; 7] {
+        let height = self.height as usize;
+        let step = match sample_rows {
+            Some(sample_rows) => {
+                assert!(sample_rows > 0, "At least one row must be sampled");
+                height.div_ceil(sample_rows.min(height))
+            },
+            None => 1,
+        };
+
+        let sizes: [usize; 7] = core::array::from_fn(|i| {
+            let differences = self.differences(i as u8 + 1, (0..height).step_by(step));
+            if self.arithmetic_coding {
+                return self.arithmetic_coded_differences(&differences, differences.len()).len() * 8;
+            }
+            let codes = encoder::huffman_codes(&self.code_lengths(&differences));
+            differences
+                .into_iter()
+                .map(|difference| {
+                    let ssss = encoder::ssss(difference);
+                    let additional_bits = if ssss < 16 { ssss } else { 0 };
+                    (codes[&ssss].1 + additional_bits) as usize
+                })
+                .sum()
+        });
+        // the first of any that tie
+        let best = (0..7).min_by_key(|i| sizes[*i]).unwrap();
+        self.predictor = best as u8 + 1;
+        sizes
+    }
+
+    /// Writes the image, with one Huffman or arithmetic coding table shared by all of the components,
+    /// in a single interleaved scan
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        output.extend_from_slice(&(Marker::SOI as u16).to_be_bytes());
+
+        // Frame header, 10918-1, B.2.2, P. 35
+        let mut frame_header: Vec<u8> = vec![self.precision];
+        frame_header.extend_from_slice(&self.height.to_be_bytes());
+        frame_header.extend_from_slice(&self.width.to_be_bytes());
+        frame_header.push(self.component_count as u8);
+        for c_ in 1..=self.component_count as u8 {
+            frame_header.extend_from_slice(&[c_, 0x11, 0]);
+        }
+        let marker = if self.arithmetic_coding { Marker::SOF11 } else { Marker::SOF3 };
+        encoder::write_segment(&mut output, marker, &frame_header);
+
+        let differences = self.differences(self.predictor, 0..self.height as usize);
+        let interval_length = match self.restart_interval {
+            0 => differences.len(),
+            restart_interval => restart_interval as usize * self.component_count,
+        };
+        let data = if self.arithmetic_coding {
+            // Arithmetic coding conditioning, 10918-1, B.2.4.3, P. 40
+            encoder::write_segment(&mut output, Marker::DAC, &[0x00, self.arithmetic_conditioning]);
+            self.arithmetic_coded_differences(&differences, interval_length)
+        } else {
+            let code_lengths = self.code_lengths(&differences);
+            encoder::write_huffman_table(&mut output, 0, 0, &code_lengths);
+            let codes = encoder::huffman_codes(&code_lengths);
+            let mut writer = BitWriter::new();
+            for (interval, interval_differences) in differences.chunks(interval_length).enumerate() {
+                if interval > 0 {
+                    writer.write_restart_marker(((interval - 1) % 8) as u8);
+                }
+                interval_differences.iter().for_each(|difference| writer.write_difference(&codes, *difference));
+            }
+            writer.finish()
+        };
+        if self.restart_interval > 0 {
+            // Restart interval definition, 10918-1, B.2.4.4, P. 43
+            encoder::write_segment(&mut output, Marker::DRI, &self.restart_interval.to_be_bytes());
+        }
+
+        // Scan header, 10918-1, B.2.3, P. 37
+        let mut scan_header: Vec<u8> = vec![self.component_count as u8];
+        for c_s in 1..=self.component_count as u8 {
+            scan_header.extend_from_slice(&[c_s, 0x00]);
+        }
+        scan_header.extend_from_slice(&[self.predictor, 0, self.point_transform]);
+        encoder::write_segment(&mut output, Marker::SOS, &scan_header);
+        output.extend(data);
+        output.extend_from_slice(&(Marker::EOI as u16).to_be_bytes());
+        output
+    }
+
+    /// The arithmetic coded differences, H.1.4.3, P. 135, in intervals of `interval_length`, each conditioned on
+    /// the differences to the left and above, which count as zero outside of the interval's rows.
+    /// The statistics start afresh in each interval.
+    fn arithmetic_coded_differences(&self, differences: &[i32], interval_length: usize) -> Vec<u8> {
+        let row_length = self.width as usize * self.component_count;
+        let mut encoder = ArithmeticEncoder::new();
+        for (interval, interval_differences) in differences.chunks(interval_length).enumerate() {
+            if interval > 0 {
+                encoder.write_restart_marker(((interval - 1) % 8) as u8);
+            }
+            let mut statistics = [0; arithmetic::LOSSLESS_STATISTICS];
+            for (index, difference) in interval_differences.iter().enumerate() {
+                let d_a = if index % row_length >= self.component_count {
+                    interval_differences[index - self.component_count]
+                } else {
+                    0
+                };
+                let d_b = if index >= row_length { interval_differences[index - row_length] } else { 0 };
+                arithmetic::encode_lossless_difference(
+                    &mut encoder,
+                    &mut statistics,
+                    d_a,
+                    d_b,
+                    self.arithmetic_conditioning,
+                    *difference,
+                );
+            }
+        }
+        encoder.finish()
+    }
+
+    /// The Huffman table the differences are coded with
+    fn code_lengths(&self, differences: &[i32]) -> [Vec<u8>; 16] {
+        if self.optimize_huffman_table {
+            let mut frequencies: [u32; 256] = [0; 256];
+            differences.iter().for_each(|difference| frequencies[encoder::ssss(*difference) as usize] += 1);
+            encoder::optimal_code_lengths(&frequencies)
+        } else {
+            encoder::DEFAULT_LOSSLESS_CODE_LENGTHS.map(|row| row.to_vec())
+        }
+    }
+
+    /// The difference between each sample of the given rows and its prediction, 10918-1, H.1.2, P. 133,
+    /// after the point transform. The first row of each restart interval is predicted like the first row of the image.
+    fn differences(&self, predictor: u8, rows: impl Iterator<Item = usize>) -> Vec<i32> {
+        let samples: Vec<u32> = self.samples.iter().map(|sample| sample >> self.point_transform).collect();
+        let row_length = self.width as usize * self.component_count;
+        let interval_rows = match self.restart_interval {
+            0 => self.height as usize,
+            restart_interval => (restart_interval / self.width) as usize,
+        };
+        let mut differences: Vec<i32> = Vec::new();
+        for y in rows {
+            // the samples from the start of the row's restart interval
+            let interval_samples = &samples[(y - y % interval_rows) * row_length..];
+            for index in (y % interval_rows) * row_length..(y % interval_rows + 1) * row_length {
+                let context = ContextContext {
+                    component: index % self.component_count,
+                    x_position: (index / self.component_count) % self.width as usize,
+                    y_position: (index / self.component_count) / self.width as usize,
+                    width: self.width as usize,
+                    component_count: self.component_count,
+                    p_t: self.point_transform,
+                    p_: self.precision,
+                    img: interval_samples,
+                };
+                let prediction = jpeg_utils::predict(context, predictor);
+                differences.push(difference_modulo_65536(interval_samples[index] as i32 - prediction as i32));
+            }
+        }
+        differences
+    }
+}
+
+/// Differences are taken modulo 2^16, from -32767 to 32768, 10918-1, H.1.2.1, P. 134,
+/// as the decoder adds them to the prediction modulo 2^P
+fn difference_modulo_65536(difference: i32) -> i32 {
+    match difference.rem_euclid(65536) {
+        difference if difference > 32768 => difference - 65536,
+        difference => difference,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::Jpeg;
+
+    fn random_samples(length: usize, precision: u8, seed: u64) -> Vec<u32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..length).map(|_| rng.gen_range(0..1u32 << precision)).collect()
+    }
+
+    fn decode(encoded: Vec<u8>) -> Vec<u32> {
+        let mut jpeg = Jpeg::from_encoded_vec(encoded);
+        jpeg.decode();
+        jpeg.raw_image().to_vec()
+    }
+
+    #[test]
+    fn difference_modulo_65536_range() {
+        assert_eq!(difference_modulo_65536(0), 0);
+        assert_eq!(difference_modulo_65536(-32767), -32767);
+        assert_eq!(difference_modulo_65536(32768), 32768);
+        assert_eq!(difference_modulo_65536(-32768), 32768);
+        assert_eq!(difference_modulo_65536(65535), -1);
+        assert_eq!(difference_modulo_65536(-65535), 1);
+    }
+
+    #[test]
+    fn round_trip_every_predictor_precision_and_component_count() {
+        let (width, height) = (13, 7);
+        for precision in [2, 8, 12, 16] {
+            for component_count in 1..=4 {
+                let samples = random_samples(width * height * component_count, precision, precision as u64);
+                for predictor in 1..=7 {
+                    let mut encoder =
+                        LosslessEncoder::new(samples.clone(), width as u16, height as u16, component_count, precision);
+                    encoder.set_predictor(predictor);
+                    let encoded = encoder.encode();
+
+                    let info = Jpeg::probe(&encoded).unwrap();
+                    assert_eq!(info.coding_process, crate::CodingProcess::Lossless);
+                    assert_eq!(info.predictor, predictor);
+                    assert_eq!(decode(encoded), samples, "P = {precision}, Nf = {component_count}, predictor {predictor}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_16_bit_extremes() {
+        // alternating 0 and 65535 gives differences of +-65535, which are coded as -+1, and 32768
+        let samples: Vec<u32> = (0..64).map(|i| [0, 65535, 32768, 0][i % 4]).collect();
+        for predictor in 1..=7 {
+            let mut encoder = LosslessEncoder::new(samples.clone(), 8, 8, 1, 16);
+            encoder.set_predictor(predictor);
+
+            assert_eq!(decode(encoder.encode()), samples);
+        }
+    }
+
+    /// Gradients, a different one for each component, with a little noise
+    fn gradient_samples(width: usize, height: usize, component_count: usize, precision: u8) -> Vec<u32> {
+        let mut seed: u32 = 12345;
+        let mut samples: Vec<u32> = Vec::with_capacity(width * height * component_count);
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..component_count {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345) & 0x7FFF_FFFF;
+                    let gradient = ((x * (c + 3) + y * (7 - 2 * c)) << (precision - 8)) as u32;
+                    samples.push(gradient + (seed >> 16) % 17);
+                }
+            }
+        }
+        samples
+    }
+
+    /// The samples jpeg-decoder, which `image` decodes JPEG images with, decodes from an image
+    fn decode_with_jpeg_decoder(encoded: &[u8]) -> Vec<u32> {
+        match image::load_from_memory_with_format(encoded, image::ImageFormat::Jpeg).unwrap() {
+            image::DynamicImage::ImageLuma16(image) => image.into_raw().into_iter().map(|sample| sample as u32).collect(),
+            image::DynamicImage::ImageRgb8(image) => image.into_raw().into_iter().map(|sample| sample as u32).collect(),
+            image => panic!("Unexpected {:?} image", image.color()),
+        }
+    }
+
+    #[test]
+    fn decoded_by_jpeg_decoder() {
+        let samples = gradient_samples(23, 17, 1, 12);
+        let mut encoder = LosslessEncoder::new(samples.clone(), 23, 17, 1, 12);
+        encoder.set_predictor(7);
+        assert_eq!(decode_with_jpeg_decoder(&encoder.encode()), samples);
+
+        let samples = gradient_samples(19, 14, 3, 8);
+        let mut encoder = LosslessEncoder::new(samples.clone(), 19, 14, 3, 8);
+        encoder.set_predictor(4);
+        encoder.set_optimize_huffman_table(true);
+        assert_eq!(decode_with_jpeg_decoder(&encoder.encode()), samples);
+    }
+
+    #[test]
+    fn optimized_huffman_table_is_smaller() {
+        let samples: Vec<u32> = (0..32 * 16).map(|i| 2048 + (i % 32) * 3 + (i / 32) * 5).collect();
+        let mut encoder = LosslessEncoder::new(samples.clone(), 32, 16, 1, 12);
+        let default_size = encoder.encode().len();
+        encoder.set_optimize_huffman_table(true);
+        let encoded = encoder.encode();
+
+        assert!(encoded.len() < default_size);
+        assert_eq!(decode(encoded), samples);
+    }
+
+    #[test]
+    fn select_predictor_for_a_gradient() {
+        // a horizontal gradient is predicted exactly by the sample above, a diagonal one by Ra + Rb - Rc
+        let mut encoder = LosslessEncoder::new((0..24 * 20).map(|i| (i % 24) * 9).collect(), 24, 20, 1, 8);
+        let sizes = encoder.select_predictor(None);
+
+        assert_eq!(encoder.predictor, 2);
+        assert!(sizes[1] < sizes[0]);
+
+        let mut encoder = LosslessEncoder::new(
+            (0..24 * 20).map(|i| (i % 24) * 5 + (i / 24) * 7).collect(),
+            24,
+            20,
+            1,
+            8,
+        );
+        let sampled_sizes = encoder.select_predictor(Some(5));
+        assert_eq!(encoder.predictor, 4);
+        assert!(sampled_sizes[3] < encoder.select_predictor(None)[3]);
+        assert_eq!(decode(encoder.encode()), encoder.samples);
+    }
+
+    #[test]
+    fn select_predictor_arithmetic_coded() {
+        // the sizes are those of the entropy coded data, between the scan header and EOI
+        let samples = gradient_samples(21, 13, 3, 8);
+        let mut encoder = LosslessEncoder::new(samples.clone(), 21, 13, 3, 8);
+        encoder.set_arithmetic_coding(true);
+        let sizes = encoder.select_predictor(None);
+        let best = encoder.predictor;
+        for predictor in 1..=7 {
+            encoder.set_predictor(predictor);
+            let encoded = encoder.encode();
+            let scan_start = encoded.windows(2).position(|bytes| bytes == [0xFF, 0xDA]).unwrap() + 2 + 12;
+            assert_eq!(sizes[predictor as usize - 1], (encoded.len() - 2 - scan_start) * 8, "predictor {predictor}");
+        }
+        assert_eq!(sizes.iter().min(), Some(&sizes[best as usize - 1]));
+
+        // which differ from those of Huffman coding them
+        encoder.set_arithmetic_coding(false);
+        assert_ne!(encoder.select_predictor(None), sizes);
+        encoder.set_predictor(best);
+        encoder.set_arithmetic_coding(true);
+        assert_eq!(decode(encoder.encode()), samples);
+    }
+
+    #[test]
+    fn round_trip_with_restart_intervals() {
+        let (width, height) = (13, 7);
+        let samples = random_samples(width * height * 3, 12, 46);
+        for rows in [1, 2, 3, 7, 8] {
+            for predictor in 1..=7 {
+                let mut encoder = LosslessEncoder::new(samples.clone(), width as u16, height as u16, 3, 12);
+                encoder.set_predictor(predictor);
+                encoder.set_restart_interval_in_rows(rows);
+                encoder.set_optimize_huffman_table(predictor % 2 == 0);
+                let encoded = encoder.encode();
+
+                assert_eq!(Jpeg::probe(&encoded).unwrap().restart_interval, rows * width as u16);
+                let restart_markers = encoded.windows(2).filter(|bytes| bytes[0] == 0xFF && bytes[1] & 0xF8 == 0xD0);
+                assert_eq!(restart_markers.count(), (height - 1) / rows as usize);
+                assert_eq!(decode(encoded), samples, "restarted every {rows} rows, predictor {predictor}");
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Lossless restart intervals must be whole rows")]
+    fn restart_interval_within_a_row() {
+        LosslessEncoder::new(vec![0; 8 * 8], 8, 8, 1, 8).set_restart_interval(12);
+    }
+
+    #[test]
+    fn point_transform_bounds_the_error() {
+        let (width, height) = (17, 9);
+        for precision in [8, 12, 16] {
+            let samples = random_samples(width * height * 3, precision, precision as u64);
+            let mut encoder = LosslessEncoder::new(samples.clone(), width as u16, height as u16, 3, precision);
+            encoder.set_predictor(4);
+            encoder.set_optimize_huffman_table(true);
+            let mut previous_size = encoder.encode().len();
+            for point_transform in [1, 2, 5] {
+                encoder.set_point_transform(point_transform);
+                let encoded = encoder.encode();
+
+                assert_eq!(Jpeg::probe(&encoded).unwrap().point_transform, point_transform);
+                assert!(encoded.len() < previous_size);
+                previous_size = encoded.len();
+                let expected: Vec<u32> = samples.iter().map(|sample| sample >> point_transform << point_transform).collect();
+                assert_eq!(decode(encoded), expected, "P = {precision}, Pt = {point_transform}");
+            }
+        }
+    }
+
+    #[test]
+    fn point_transform_with_restarts_and_every_predictor() {
+        // the first sample of each interval is predicted as 2^(P - Pt - 1)
+        let samples = random_samples(11 * 6, 12, 49);
+        for predictor in 1..=7 {
+            let mut encoder = LosslessEncoder::new(samples.clone(), 11, 6, 1, 12);
+            encoder.set_predictor(predictor);
+            encoder.set_point_transform(3);
+            encoder.set_restart_interval_in_rows(2);
+
+            let expected: Vec<u32> = samples.iter().map(|sample| sample & !0b111).collect();
+            assert_eq!(decode(encoder.encode()), expected, "predictor {predictor}");
+        }
+    }
+
+    #[test]
+    fn round_trip_arithmetic_coded() {
+        let (width, height) = (13, 7);
+        for precision in [2, 8, 16] {
+            for component_count in [1, 3] {
+                let samples = random_samples(width * height * component_count, precision, precision as u64 + 50);
+                for predictor in 1..=7 {
+                    let mut encoder =
+                        LosslessEncoder::new(samples.clone(), width as u16, height as u16, component_count, precision);
+                    encoder.set_predictor(predictor);
+                    encoder.set_arithmetic_coding(true);
+                    encoder.set_arithmetic_conditioning(predictor % 3, 2 + predictor % 2);
+                    encoder.set_restart_interval_in_rows(predictor as u16 % 4);
+                    let encoded = encoder.encode();
+
+                    assert_eq!(Jpeg::probe(&encoded).unwrap().coding_process, crate::CodingProcess::LosslessArithmetic);
+                    assert_eq!(decode(encoded), samples, "P = {precision}, Nf = {component_count}, predictor {predictor}");
+                }
+            }
+        }
+
+        // with differences of +-1 and 32768, as well as the point transform
+        let samples: Vec<u32> = (0..64).map(|i| [0, 65535, 32768, 0][i % 4]).collect();
+        let mut encoder = LosslessEncoder::new(samples.clone(), 8, 8, 1, 16);
+        encoder.set_arithmetic_coding(true);
+        assert_eq!(decode(encoder.encode()), samples);
+        encoder.set_point_transform(4);
+        assert_eq!(decode(encoder.encode()), samples.iter().map(|sample| sample & !0xF).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn encode_arithmetic_coded_as_reference() {
+        // the samples of arithmetic_lossless.jpg, coded with a single table, L = 1 and U = 3,
+        // by tests/common/arithmetic_lossless.py, a QM-coder transliterated from libjpeg's jcarith.c
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_lossless.raw");
+        let samples: Vec<u32> = std::fs::read(path).unwrap().into_iter().map(|sample| sample as u32).collect();
+        let mut encoder = LosslessEncoder::new(samples, 17, 11, 3, 8);
+        encoder.set_predictor(6);
+        encoder.set_arithmetic_coding(true);
+        encoder.set_arithmetic_conditioning(1, 3);
+
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/arithmetic_lossless_one_table.jpg");
+        assert_eq!(encoder.encode(), std::fs::read(path).unwrap());
+    }
+
+    #[test]
+    fn round_trip_f_18() {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common/F-18.ljpg");
+        let info = Jpeg::probe(&std::fs::read(&path).unwrap()).unwrap();
+        let mut original = Jpeg::open(path);
+        original.decode();
+
+        let mut encoder = LosslessEncoder::new(
+            original.raw_image().to_vec(),
+            info.width,
+            info.height,
+            info.component_count,
+            info.precision,
+        );
+        encoder.set_predictor(info.predictor);
+
+        assert_eq!(decode(encoder.encode()), original.raw_image());
+        encoder.set_optimize_huffman_table(true);
+        assert_eq!(decode(encoder.encode()), original.raw_image());
+    }
+}
@@ -0,0 +1,380 @@
+use crate::dct_encoder::{DctEncoder, EncoderComponent};
+use crate::{dct, encoder, Jpeg, Marker, Region, Transform};
+
+/// Flips, transposes or rotates a DCT image, or crops it at MCU boundaries, by rearranging its quantized
+/// coefficients rather than decoding and re-encoding it, so that nothing more is lost, as libjpeg's jpegtran
+/// (transupp.c) does. The result is a sequential image with optimal Huffman tables.
+pub struct Transformer {
+    encoded_image: Vec<u8>,
+    transform: Option<Transform>,
+    trim: bool,           // whether the partial MCUs at the edges that would be mirrored are dropped
+    crop: Option<Region>, // of the transformed image
+}
+
+impl Transformer {
+    /// A transformer of a sequential or progressive DCT image, that leaves it as it is until told otherwise
+    pub fn new(encoded_image: Vec<u8>) -> Self {
+        Self {
+            encoded_image,
+            transform: None,
+            trim: false,
+            crop: None,
+        }
+    }
+
+    /// How the image is rearranged
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = Some(transform);
+    }
+
+    /// Whether to drop the partial MCUs at the right or bottom edge, when the transform would mirror them.
+    /// Blocks can only be mirrored within whole MCUs, so otherwise those are left where they were,
+    /// only transposed if the image is, as jpegtran does without -trim.
+    pub fn set_trim(&mut self, trim: bool) {
+        self.trim = trim;
+    }
+
+    /// Keeps only `width` by `height` pixels of the transformed image, from `x`, `y`,
+    /// which have to be on a boundary of its MCUs
+    pub fn set_crop(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        assert!(width > 0 && height > 0, "A crop must have at least one pixel");
+        self.crop = Some(Region { x, y, width, height });
+    }
+
+    /// Writes the transformed image, with the same quantization tables, and with the APPn and COM segments
+    /// before the image's first scan copied as they are, as jpegtran -copy all does. That includes
+    /// the JFIF or Adobe APP14 segment giving the color space, but also any Exif orientation, which isn't updated.
+    pub fn encode(&self) -> Vec<u8> {
+        let info = Jpeg::probe(&self.encoded_image).expect("The image's headers couldn't be read");
+        let mut jpeg = Jpeg::from_encoded_vec(self.encoded_image.clone());
+        let components = jpeg.read_coefficients();
+
+        // as a transpose followed by mirroring either way
+        let (transpose, mirror_x, mirror_y) = match self.transform {
+            None => (false, false, false),
+            Some(Transform::FlipHorizontal) => (false, true, false),
+            Some(Transform::FlipVertical) => (false, false, true),
+            Some(Transform::Transpose) => (true, false, false),
+            Some(Transform::Transverse) => (true, true, true),
+            Some(Transform::Rotate90) => (true, true, false),
+            Some(Transform::Rotate180) => (false, true, true),
+            Some(Transform::Rotate270) => (true, false, true),
+        };
+
+        let h_max = components.iter().map(|component| component.sampling_factors.0).max().unwrap() as usize;
+        let v_max = components.iter().map(|component| component.sampling_factors.1).max().unwrap() as usize;
+        let ((width, height), (mcu_width, mcu_height)) = if transpose {
+            ((info.height as usize, info.width as usize), (v_max * 8, h_max * 8))
+        } else {
+            ((info.width as usize, info.height as usize), (h_max * 8, v_max * 8))
+        };
+        let (full_mcus_wide, full_mcus_high) = (width / mcu_width, height / mcu_height);
+        let width = if self.trim && mirror_x && full_mcus_wide > 0 { full_mcus_wide * mcu_width } else { width };
+        let height = if self.trim && mirror_y && full_mcus_high > 0 { full_mcus_high * mcu_height } else { height };
+
+        let crop = self.crop.unwrap_or(Region { x: 0, y: 0, width, height });
+        assert!(
+            crop.x.is_multiple_of(mcu_width) && crop.y.is_multiple_of(mcu_height),
+            "A crop has to start on an MCU boundary of the transformed image"
+        );
+        assert!(crop.x + crop.width <= width && crop.y + crop.height <= height, "The crop must be within the image");
+
+        let transformed: Vec<EncoderComponent> = components
+            .iter()
+            .enumerate()
+            .map(|(index, component)| {
+                let (h_, v_) = match component.sampling_factors {
+                    (h_, v_) if transpose => (v_ as usize, h_ as usize),
+                    (h_, v_) => (h_ as usize, v_ as usize),
+                };
+                let blocks_wide = (crop.width * h_).div_ceil(mcu_width);
+                let blocks_high = (crop.height * v_).div_ceil(mcu_height);
+                // the blocks that can be mirrored, of the transformed image before it's cropped
+                let (full_blocks_wide, full_blocks_high) = (full_mcus_wide * h_, full_mcus_high * v_);
+                let (x_offset, y_offset) = (crop.x / mcu_width * h_, crop.y / mcu_height * v_);
+
+                let mut blocks: Vec<[i32; 64]> = Vec::with_capacity(blocks_wide * blocks_high);
+                for y in y_offset..y_offset + blocks_high {
+                    for x in x_offset..x_offset + blocks_wide {
+                        let mirrored_x = mirror_x && x < full_blocks_wide;
+                        let mirrored_y = mirror_y && y < full_blocks_high;
+                        let x = if mirrored_x { full_blocks_wide - 1 - x } else { x };
+                        let y = if mirrored_y { full_blocks_high - 1 - y } else { y };
+                        let (source_x, source_y) = if transpose { (y, x) } else { (x, y) };
+
+                        let mut block = component.blocks[source_y * component.padded_blocks_wide + source_x];
+                        if transpose {
+                            block = transposed(&block);
+                        }
+                        // mirroring a block negates its odd horizontal or vertical frequencies
+                        for (k, coefficient) in block.iter_mut().enumerate() {
+                            if (mirrored_x && k % 2 == 1) != (mirrored_y && (k / 8) % 2 == 1) {
+                                *coefficient = -*coefficient;
+                            }
+                        }
+                        blocks.push(block);
+                    }
+                }
+
+                EncoderComponent {
+                    c_: component.component_identifier,
+                    h_: h_ as u8,
+                    v_: v_ as u8,
+                    t_q: component.quantization_table.t_q,
+                    t_h: index.min(1) as u8,
+                    blocks_wide,
+                    blocks_high,
+                    blocks,
+                }
+            })
+            .collect();
+
+        let mut output: Vec<u8> = Vec::new();
+        output.extend_from_slice(&(Marker::SOI as u16).to_be_bytes());
+        for segment in application_segments(&self.encoded_image) {
+            output.extend_from_slice(segment);
+        }
+
+        // Quantization tables, 10918-1, B.2.4.1, P. 39, which are transposed along with the blocks
+        let mut written_tables: Vec<u8> = Vec::new();
+        for component in &components {
+            let table = &component.quantization_table;
+            if written_tables.contains(&table.t_q) {
+                continue;
+            }
+            written_tables.push(table.t_q);
+            let q_k = if transpose { transposed(&table.q_k) } else { table.q_k };
+            let mut parameters: Vec<u8> = vec![(table.p_q << 4) | table.t_q];
+            for k in dct::ZIGZAG {
+                match table.p_q {
+                    0 => parameters.push(q_k[k] as u8),
+                    _ => parameters.extend_from_slice(&q_k[k].to_be_bytes()),
+                }
+            }
+            encoder::write_segment(&mut output, Marker::DQT, &parameters);
+        }
+
+        let mut encoder = DctEncoder::for_coefficients(crop.width as u16, crop.height as u16, components.len());
+        encoder.set_optimize_huffman_tables(true);
+        let sixteen_bit_tables = components.iter().any(|component| component.quantization_table.p_q == 1);
+        encoder.write_frame(&mut output, &transformed, info.precision, sixteen_bit_tables);
+        output.extend_from_slice(&(Marker::EOI as u16).to_be_bytes());
+        output
+    }
+}
+
+/// The APPn and COM segments, markers included, before the first scan of an image whose headers have been probed,
+/// so whose segments are all there, in the order they're in
+fn application_segments(encoded_image: &[u8]) -> Vec<&[u8]> {
+    let mut segments: Vec<&[u8]> = Vec::new();
+    let mut index = 2;
+    while index + 4 <= encoded_image.len() {
+        let marker = u16::from_be_bytes([encoded_image[index], encoded_image[index + 1]]);
+        if marker == Marker::SOS as u16 || marker == Marker::EOI as u16 {
+            break;
+        }
+        let length = u16::from_be_bytes([encoded_image[index + 2], encoded_image[index + 3]]) as usize;
+        if (Marker::APP as u16..=Marker::APPn as u16).contains(&marker) || marker == Marker::COM as u16 {
+            segments.push(&encoded_image[index..index + 2 + length]);
+        }
+        index += 2 + length;
+    }
+    segments
+}
+
+/// A block, or a quantization table, in natural order, transposed across its main diagonal
+fn transposed<T: Copy>(block: &[T; 64]) -> [T; 64] {
+    core::array::from_fn(|k| block[(k % 8) * 8 + k / 8])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::CodingProcess;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let mut path = env::current_dir().unwrap();
+        path.push("tests/common");
+        path.push(name);
+        fs::read(path).unwrap()
+    }
+
+    /// The width, height and samples of an image, as they were coded
+    fn decode(encoded: Vec<u8>) -> (usize, usize, Vec<u32>) {
+        let info = Jpeg::probe(&encoded).unwrap();
+        let mut jpeg = Jpeg::from_encoded_vec(encoded);
+        jpeg.set_raw_output(true);
+        jpeg.decode();
+        (info.width as usize, info.height as usize, jpeg.raw_image().to_vec())
+    }
+
+    fn transform(encoded: &[u8], transform: Option<Transform>, trim: bool) -> Vec<u8> {
+        let mut transformer = Transformer::new(encoded.to_vec());
+        if let Some(transform) = transform {
+            transformer.set_transform(transform);
+        }
+        transformer.set_trim(trim);
+        transformer.encode()
+    }
+
+    #[test]
+    fn transforms_move_the_pixels() {
+        // without subsampling, each block decodes to the same pixels wherever it is
+        for (name, component_count) in [("dct_444_q75.jpg", 3), ("dct_gray_q50.jpg", 1)] {
+            let encoded = fixture(name);
+            let (width, height, samples) = decode(encoded.clone());
+            for (transform, transpose, mirror_x, mirror_y) in [
+                (Transform::FlipHorizontal, false, true, false),
+                (Transform::FlipVertical, false, false, true),
+                (Transform::Transpose, true, false, false),
+                (Transform::Transverse, true, true, true),
+                (Transform::Rotate90, true, true, false),
+                (Transform::Rotate180, false, true, true),
+                (Transform::Rotate270, true, false, true),
+            ] {
+                for trim in [false, true] {
+                    let (output_width, output_height, output) =
+                        decode(self::transform(&encoded, Some(transform), trim));
+                    let (transformed_width, transformed_height) =
+                        if transpose { (height, width) } else { (width, height) };
+                    let (full_width, full_height) = (transformed_width / 8 * 8, transformed_height / 8 * 8);
+                    assert_eq!(output_width, if trim && mirror_x { full_width } else { transformed_width });
+                    assert_eq!(output_height, if trim && mirror_y { full_height } else { transformed_height });
+
+                    for y in 0..output_height {
+                        for x in 0..output_width {
+                            // the partial blocks past the last whole MCU aren't mirrored, and as the IDCT
+                            // rounds between its passes over columns and rows, transposed blocks can be 1 out
+                            let a = if mirror_x && x < full_width { full_width - 1 - x } else { x };
+                            let b = if mirror_y && y < full_height { full_height - 1 - y } else { y };
+                            let (source_x, source_y) = if transpose { (b, a) } else { (a, b) };
+                            for c in 0..component_count {
+                                let sample = output[(y * output_width + x) * component_count + c] as i32;
+                                let expected = samples[(source_y * width + source_x) * component_count + c] as i32;
+                                assert!(
+                                    (sample - expected).abs() <= if transpose { 1 } else { 0 },
+                                    "{name} {transform:?}, trimmed: {trim}, at {x}, {y}"
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transforms_compose() {
+        // a 4:2:0 image trimmed to whole MCUs, so that nothing is left unmirrored
+        let rotated = transform(&fixture("dct_420_q75.jpg"), Some(Transform::Rotate90), true);
+        let coefficients = |encoded: &[u8]| Jpeg::from_encoded_vec(encoded.to_vec()).read_coefficients();
+        let info = Jpeg::probe(&rotated).unwrap();
+        assert_eq!((info.width, info.height), (16, 37));
+        assert_eq!(coefficients(&rotated)[0].sampling_factors, (2, 2));
+
+        let mut four_times = rotated.clone();
+        for _ in 0..4 {
+            four_times = transform(&four_times, Some(Transform::Rotate90), false);
+        }
+        assert_eq!(four_times, transform(&rotated, None, false));
+
+        let twice = transform(&transform(&rotated, Some(Transform::Rotate90), true), Some(Transform::Rotate90), true);
+        assert_eq!(coefficients(&twice), coefficients(&transform(&rotated, Some(Transform::Rotate180), true)));
+
+        let transposed = transform(&rotated, Some(Transform::Transpose), false);
+        assert_eq!(
+            coefficients(&transform(&transposed, Some(Transform::Rotate180), true)),
+            coefficients(&transform(&rotated, Some(Transform::Transverse), true))
+        );
+    }
+
+    #[test]
+    fn crop_keeps_the_blocks() {
+        let encoded = fixture("dct_444_q75.jpg");
+        let mut transformer = Transformer::new(encoded.clone());
+        transformer.set_crop(8, 16, 21, 7);
+        let (width, height, cropped) = decode(transformer.encode());
+
+        assert_eq!((width, height), (21, 7));
+        let mut jpeg = Jpeg::from_encoded_vec(encoded);
+        jpeg.set_raw_output(true);
+        jpeg.decode_region(8, 16, 21, 7);
+        assert_eq!(cropped, jpeg.raw_image());
+
+        // with subsampled chroma, a crop starts on a 16 pixel boundary
+        let encoded = fixture("dct_420_q75.jpg");
+        let mut transformer = Transformer::new(encoded.clone());
+        transformer.set_transform(Transform::FlipVertical);
+        transformer.set_crop(16, 0, 21, 16);
+        let cropped = Jpeg::from_encoded_vec(transformer.encode()).read_coefficients();
+        let flipped = transform(&encoded, Some(Transform::FlipVertical), false);
+        let flipped = Jpeg::from_encoded_vec(flipped).read_coefficients();
+        for (cropped, flipped) in cropped.iter().zip(flipped.iter()) {
+            let x_offset = 16 * cropped.sampling_factors.0 as usize / 16;
+            for y in 0..cropped.blocks_high {
+                for x in 0..cropped.blocks_wide {
+                    assert_eq!(
+                        cropped.blocks[y * cropped.padded_blocks_wide + x],
+                        flipped.blocks[y * flipped.padded_blocks_wide + x + x_offset]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn application_segments_are_copied() {
+        // the JFIF APP0 segment of the image, followed by an APP1 and a COM segment
+        let encoded = fixture("dct_444_q75.jpg");
+        let jfif = &encoded[2..20];
+        let app1: &[u8] = &[0xFF, 0xE1, 0x00, 0x0A, b'E', b'x', b'i', b'f', 0, 0, b'M', b'M'];
+        let com: &[u8] = &[0xFF, 0xFE, 0x00, 0x07, b'h', b'e', b'l', b'l', b'o'];
+        let mut with_segments = encoded[..20].to_vec();
+        with_segments.extend_from_slice(app1);
+        with_segments.extend_from_slice(com);
+        with_segments.extend_from_slice(&encoded[20..]);
+
+        let rotated = transform(&with_segments, Some(Transform::Rotate180), false);
+        assert!(rotated[2..].starts_with(&[jfif, app1, com].concat()));
+        assert_eq!(decode(rotated), decode(transform(&encoded, Some(Transform::Rotate180), false)));
+    }
+
+    #[test]
+    fn sixteen_bit_tables_stay_extended_sequential() {
+        // the image as an extended sequential one, SOF1, with the same tables in 16 bits, its two DQT segments
+        // of a table each being the two segments after the JFIF one
+        let encoded = fixture("dct_444_q75.jpg");
+        let mut extended = encoded[..20].to_vec();
+        let mut index = 20;
+        for _ in 0..2 {
+            let (t_q, q_k) = (encoded[index + 4], &encoded[index + 5..index + 69]);
+            extended.extend_from_slice(&[0xFF, 0xDB, 0x00, 0x83, 0x10 | t_q]);
+            extended.extend(q_k.iter().flat_map(|q| [0, *q]));
+            index += 69;
+        }
+        assert_eq!(&encoded[index..index + 2], &[0xFF, 0xC0]);
+        extended.extend_from_slice(&[0xFF, 0xC1]);
+        extended.extend_from_slice(&encoded[index + 2..]);
+        assert_eq!(decode(extended.clone()), decode(encoded.clone()));
+
+        let rotated = transform(&extended, Some(Transform::Rotate90), false);
+        let info = Jpeg::probe(&rotated).unwrap();
+        assert_eq!(info.coding_process, CodingProcess::ExtendedSequentialDct);
+        assert!(info.quantization_tables.values().all(|table| table.p_q == 1));
+        assert_eq!(decode(rotated), decode(transform(&encoded, Some(Transform::Rotate90), false)));
+
+        // while the image with 8 bit tables stays baseline
+        let info = Jpeg::probe(&transform(&encoded, Some(Transform::Rotate90), false)).unwrap();
+        assert_eq!(info.coding_process, CodingProcess::BaselineDct);
+    }
+
+    #[test]
+    #[should_panic(expected = "A crop has to start on an MCU boundary of the transformed image")]
+    fn crop_within_an_mcu() {
+        let mut transformer = Transformer::new(fixture("dct_420_q75.jpg"));
+        transformer.set_crop(8, 0, 8, 8);
+        transformer.encode();
+    }
+}
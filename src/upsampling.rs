@@ -0,0 +1,66 @@
+/// Upsamples a subsampled component, of `width` by `height` samples laid out `stride` apart, by two horizontally,
+/// vertically or both, with libjpeg's "fancy" triangle filter (jdsample.c), so the results match it exactly.
+/// Each output sample is 3/4 of its nearest input sample and 1/4 of the next nearest, with the edge samples
+/// repeated, and libjpeg's alternating rounding. The result is `width * h_expansion` samples wide.
+pub(crate) fn fancy_upsample(
+    plane: &[i32],
+    stride: usize,
+    (width, height): (usize, usize),
+    (h_expansion, v_expansion): (usize, usize),
+) -> Vec<i32> {
+    let sample = |x: isize, y: isize| -> i32 {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        plane[y * stride + x]
+    };
+
+    let mut upsampled: Vec<i32> = Vec::with_capacity(width * h_expansion * height * v_expansion);
+    for output_y in 0..height * v_expansion {
+        let y = (output_y / v_expansion) as isize;
+        // the row above for the upper output row, and the row below for the lower one
+        let neighbour_y = if v_expansion == 1 { y } else if output_y % 2 == 0 { y - 1 } else { y + 1 };
+        let column_sum = |x: isize| -> i32 {
+            if v_expansion == 1 {
+                sample(x, y)
+            } else {
+                3 * sample(x, y) + sample(x, neighbour_y)
+            }
+        };
+
+        for output_x in 0..width * h_expansion {
+            let x = (output_x / h_expansion) as isize;
+            upsampled.push(match (h_expansion, v_expansion) {
+                (2, 1) if output_x % 2 == 0 => (3 * column_sum(x) + column_sum(x - 1) + 1) >> 2,
+                (2, 1) => (3 * column_sum(x) + column_sum(x + 1) + 2) >> 2,
+                (2, 2) if output_x % 2 == 0 => (3 * column_sum(x) + column_sum(x - 1) + 8) >> 4,
+                (2, 2) => (3 * column_sum(x) + column_sum(x + 1) + 7) >> 4,
+                (1, 2) => (column_sum(x) + 1 + (output_y % 2) as i32) >> 2,
+                _ => panic!("Fancy upsampling is only for doubling the width, the height or both"),
+            });
+        }
+    }
+    upsampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fancy_upsample_edges() {
+        let plane: [i32; 6] = [0, 100, 200, 40, 40, 40];
+
+        assert_eq!(
+            fancy_upsample(&plane, 3, (3, 1), (2, 1)),
+            [0, 25, 75, 125, 175, 200]
+        );
+        assert_eq!(
+            fancy_upsample(&plane, 3, (1, 2), (1, 2)),
+            [0, 10, 30, 40]
+        );
+        assert_eq!(
+            fancy_upsample(&plane, 3, (2, 2), (2, 2)),
+            [0, 25, 75, 100, 10, 29, 66, 85, 30, 36, 49, 55, 40, 40, 40, 40]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+#!/usr/bin/env python3
+"""Writes arithmetic_lossless.jpg, arithmetic_lossless.raw and arithmetic_lossless_one_table.jpg,
+lossless arithmetic coded (SOF11) images of the same 17x11 three component image, predictor 6.
+
+No encoder at hand writes SOF11, so the coded data comes from this QM-coder. Its byte output is a
+transliteration of libjpeg's jcarith.c (arith_encode, emit_byte and finish_pass) and the states are
+libjpeg's jpeg_aritab, 10918-1, Table D.2, P. 58. The lossless model, the contexts from the differences
+above and to the left and the conditioning categories, follows 10918-1, H.1.4.3, P. 135 and F.1.4.4.1,
+P. 95, without looking at the decoder in src/arithmetic.rs.
+
+Run from this directory: python3 arithmetic_lossless.py
+"""
+import math
+import os
+
+# Qe, Next_Index_LPS, Next_Index_MPS and Switch_MPS, with libjpeg's extra state 113 for fixed probabilities
+STATES = [
+    (0x5A1D, 1, 1, 1), (0x2586, 14, 2, 0), (0x1114, 16, 3, 0), (0x080B, 18, 4, 0),
+    (0x03D8, 20, 5, 0), (0x01DA, 23, 6, 0), (0x00E5, 25, 7, 0), (0x006F, 28, 8, 0),
+    (0x0036, 30, 9, 0), (0x001A, 33, 10, 0), (0x000D, 35, 11, 0), (0x0006, 9, 12, 0),
+    (0x0003, 10, 13, 0), (0x0001, 12, 13, 0), (0x5A7F, 15, 15, 1), (0x3F25, 36, 16, 0),
+    (0x2CF2, 38, 17, 0), (0x207C, 39, 18, 0), (0x17B9, 40, 19, 0), (0x1182, 42, 20, 0),
+    (0x0CEF, 43, 21, 0), (0x09A1, 45, 22, 0), (0x072F, 46, 23, 0), (0x055C, 48, 24, 0),
+    (0x0406, 49, 25, 0), (0x0303, 51, 26, 0), (0x0240, 52, 27, 0), (0x01B1, 54, 28, 0),
+    (0x0144, 56, 29, 0), (0x00F5, 57, 30, 0), (0x00B7, 59, 31, 0), (0x008A, 60, 32, 0),
+    (0x0068, 62, 33, 0), (0x004E, 63, 34, 0), (0x003B, 32, 35, 0), (0x002C, 33, 9, 0),
+    (0x5AE1, 37, 37, 1), (0x484C, 64, 38, 0), (0x3A0D, 65, 39, 0), (0x2EF1, 67, 40, 0),
+    (0x261F, 68, 41, 0), (0x1F33, 69, 42, 0), (0x19A8, 70, 43, 0), (0x1518, 72, 44, 0),
+    (0x1177, 73, 45, 0), (0x0E74, 74, 46, 0), (0x0BFB, 75, 47, 0), (0x09F8, 77, 48, 0),
+    (0x0861, 78, 49, 0), (0x0706, 79, 50, 0), (0x05CD, 48, 51, 0), (0x04DE, 50, 52, 0),
+    (0x040F, 50, 53, 0), (0x0363, 51, 54, 0), (0x02D4, 52, 55, 0), (0x025C, 53, 56, 0),
+    (0x01F8, 54, 57, 0), (0x01A4, 55, 58, 0), (0x0160, 56, 59, 0), (0x0125, 57, 60, 0),
+    (0x00F6, 58, 61, 0), (0x00CB, 59, 62, 0), (0x00AB, 61, 63, 0), (0x008F, 61, 32, 0),
+    (0x5B12, 65, 65, 1), (0x4D04, 80, 66, 0), (0x412C, 81, 67, 0), (0x37D8, 82, 68, 0),
+    (0x2FE8, 83, 69, 0), (0x293C, 84, 70, 0), (0x2379, 86, 71, 0), (0x1EDF, 87, 72, 0),
+    (0x1AA9, 87, 73, 0), (0x174E, 72, 74, 0), (0x1424, 72, 75, 0), (0x119C, 74, 76, 0),
+    (0x0F6B, 74, 77, 0), (0x0D51, 75, 78, 0), (0x0BB6, 77, 79, 0), (0x0A40, 77, 48, 0),
+    (0x5832, 80, 81, 1), (0x4D1C, 88, 82, 0), (0x438E, 89, 83, 0), (0x3BDD, 90, 84, 0),
+    (0x34EE, 91, 85, 0), (0x2EAE, 92, 86, 0), (0x299A, 93, 87, 0), (0x2516, 86, 71, 0),
+    (0x5570, 88, 89, 1), (0x4CA9, 95, 90, 0), (0x44D9, 96, 91, 0), (0x3E22, 97, 92, 0),
+    (0x3824, 99, 93, 0), (0x32B4, 99, 94, 0), (0x2E17, 93, 86, 0), (0x56A8, 95, 96, 1),
+    (0x4F46, 101, 97, 0), (0x47E5, 102, 98, 0), (0x41CF, 103, 99, 0), (0x3C3D, 104, 100, 0),
+    (0x375E, 99, 93, 0), (0x5231, 105, 102, 0), (0x4C0F, 106, 103, 0), (0x4639, 107, 104, 0),
+    (0x415E, 103, 99, 0), (0x5627, 105, 106, 1), (0x50E7, 108, 107, 0), (0x4B85, 109, 103, 0),
+    (0x5597, 110, 109, 0), (0x504F, 111, 107, 0), (0x5A10, 110, 111, 1), (0x5522, 112, 109, 0),
+    (0x59EB, 112, 111, 1), (0x5A1D, 113, 113, 0),
+]
+
+
+class Encoder:
+    def __init__(self):
+        self.c = 0
+        self.a = 0x10000
+        self.sc = 0  # stacked 0xFF bytes
+        self.zc = 0  # pending 0x00 bytes
+        self.ct = 11
+        self.buffer = -1
+        self.out = bytearray()
+
+    def emit(self, byte):
+        self.out.append(byte)
+        if byte == 0xFF:
+            self.out.append(0)
+
+    def emit_zeros(self):
+        while self.zc:
+            self.out.append(0)
+            self.zc -= 1
+
+    def emit_stacked(self):
+        self.emit_zeros()
+        while self.sc:
+            self.emit(0xFF)
+            self.sc -= 1
+
+    def byte_out(self, temp):
+        if temp > 0xFF:
+            # a carry
+            if self.buffer >= 0:
+                self.emit_zeros()
+                self.emit(self.buffer + 1)
+            self.zc += self.sc
+            self.sc = 0
+            self.buffer = temp & 0xFF
+        elif temp == 0xFF:
+            self.sc += 1
+        else:
+            if self.buffer == 0:
+                self.zc += 1
+            elif self.buffer >= 0:
+                self.emit_zeros()
+                self.emit(self.buffer)
+            if self.sc:
+                self.emit_stacked()
+            self.buffer = temp & 0xFF
+
+    def encode(self, stats, index, value):
+        state = stats[index]
+        q_e, next_lps, next_mps, switch_mps = STATES[state & 0x7F]
+        self.a -= q_e
+        if value != state >> 7:
+            if self.a >= q_e:
+                self.c += self.a
+                self.a = q_e
+            stats[index] = (state & 0x80) ^ ((switch_mps << 7) | next_lps)
+        else:
+            if self.a >= 0x8000:
+                return
+            if self.a < q_e:
+                self.c += self.a
+                self.a = q_e
+            stats[index] = (state & 0x80) ^ next_mps
+        while True:
+            self.a <<= 1
+            self.c <<= 1
+            self.ct -= 1
+            if self.ct == 0:
+                self.byte_out(self.c >> 19)
+                self.c &= 0x7FFFF
+                self.ct += 8
+            if self.a >= 0x8000:
+                break
+
+    def finish(self):
+        temp = (self.a - 1 + self.c) & 0xFFFF0000
+        self.c = temp + 0x8000 if temp < self.c else temp
+        self.c <<= self.ct
+        if self.c & 0xF8000000:
+            if self.buffer >= 0:
+                self.emit_zeros()
+                self.emit(self.buffer + 1)
+            self.zc += self.sc
+            self.sc = 0
+        else:
+            if self.buffer == 0:
+                self.zc += 1
+            elif self.buffer >= 0:
+                self.emit_zeros()
+                self.emit(self.buffer)
+            if self.sc:
+                self.emit_stacked()
+        # trailing zeros are dropped
+        if self.c & 0x7FFF800:
+            self.emit_zeros()
+            self.emit((self.c >> 19) & 0xFF)
+            if self.c & 0x7F800:
+                self.emit((self.c >> 11) & 0xFF)
+        return bytes(self.out)
+
+
+def category(difference, conditioning):
+    """The conditioning category of a difference, F.1.4.4.1.2, P. 96, with the bounds compared to the
+    magnitude category of the difference as libjpeg does"""
+    lower = (1 << (conditioning & 0xF)) >> 1
+    upper = (1 << (conditioning >> 4)) >> 1
+    if difference == 0:
+        return 0
+    magnitude = abs(difference) - 1
+    m = 0 if magnitude == 0 else 1 << (magnitude.bit_length() - 1)
+    sign = 1 if difference < 0 else 0
+    if m < lower:
+        return 0
+    if m > upper:
+        return 3 + sign
+    return 1 + sign
+
+
+def encode_difference(encoder, stats, s0, x1, value):
+    """F.1.4.4.1.1, P. 95, with the statistics of H.1.4.3.1, P. 135"""
+    if value == 0:
+        encoder.encode(stats, s0, 0)
+        return
+    encoder.encode(stats, s0, 1)
+    sign = 1 if value < 0 else 0
+    encoder.encode(stats, s0 + 1, sign)
+    bin = s0 + 2 + sign
+    magnitude = abs(value) - 1
+    if magnitude == 0:
+        encoder.encode(stats, bin, 0)
+        return
+    encoder.encode(stats, bin, 1)
+    m = 1
+    bin = x1
+    while m << 1 <= magnitude:
+        encoder.encode(stats, bin, 1)
+        m <<= 1
+        bin += 1
+    encoder.encode(stats, bin, 0)
+    bin += 14
+    bit = m >> 1
+    while bit:
+        encoder.encode(stats, bin, 1 if magnitude & bit else 0)
+        bit >>= 1
+
+
+def encode_lossless(image, width, height, components, precision, predictor, tables, conditioning):
+    encoder = Encoder()
+    statistics = {}
+    differences = [[[0] * components for _ in range(width)] for _ in range(height)]
+    for y in range(height):
+        for x in range(width):
+            for c in range(components):
+                if x == 0 and y == 0:
+                    prediction = 1 << (precision - 1)
+                elif y == 0:
+                    prediction = image[y][x - 1][c]
+                elif x == 0:
+                    prediction = image[y - 1][x][c]
+                else:
+                    r_a, r_b, r_c = image[y][x - 1][c], image[y - 1][x][c], image[y - 1][x - 1][c]
+                    prediction = [0, r_a, r_b, r_c, r_a + r_b - r_c, r_a + ((r_b - r_c) >> 1),
+                                  r_b + ((r_a - r_c) >> 1), (r_a + r_b) // 2][predictor]
+                difference = (image[y][x][c] - prediction) & 0xFFFF
+                if difference >= 0x8000:
+                    difference -= 0x10000
+                differences[y][x][c] = difference
+                d_a = differences[y][x - 1][c] if x > 0 else 0
+                d_b = differences[y - 1][x][c] if y > 0 else 0
+                table = tables[c]
+                stats = statistics.setdefault(table, [0] * 158)
+                category_b = category(d_b, conditioning[table])
+                s0 = 4 * (5 * category(d_a, conditioning[table]) + category_b)
+                encode_difference(encoder, stats, s0, 129 if category_b > 2 else 100, difference)
+    return encoder.finish()
+
+
+def write_image(path, image, width, height, precision, predictor, tables, conditioning):
+    components = len(tables)
+    out = bytearray(b"\xff\xd8")
+    out += bytes([0xFF, 0xCB, 0, 8 + 3 * components, precision, 0, height, 0, width, components])
+    for c in range(components):
+        out += bytes([c + 1, 0x11, 0])
+    out += bytes([0xFF, 0xCC, 0, 2 + 2 * len(conditioning)])
+    for table, value in sorted(conditioning.items()):
+        out += bytes([table, value])
+    out += bytes([0xFF, 0xDA, 0, 6 + 2 * components, components])
+    for c in range(components):
+        out += bytes([c + 1, tables[c] << 4])
+    out += bytes([predictor, 0, 0])
+    out += encode_lossless(image, width, height, components, precision, predictor, tables, conditioning)
+    out += b"\xff\xd9"
+    with open(path, "wb") as file:
+        file.write(out)
+
+
+def main():
+    width, height = 17, 11
+    image = [[[0] * 3 for _ in range(width)] for _ in range(height)]
+    seed = 12345
+    for y in range(height):
+        for x in range(width):
+            seed = (seed * 1103515245 + 12345) & 0x7FFFFFFF
+            noise = (seed >> 16) % 23
+            image[y][x][0] = min(255, x * 13 + y * 5 + (noise if (x // 4 + y // 3) % 2 else 0))
+            image[y][x][1] = int(128 + 100 * math.sin(x / 3.0 + y / 5.0))
+            image[y][x][2] = (255 if (x // 5 + y // 4) % 2 else 0) if x < 12 else (seed >> 8) & 0xFF
+
+    here = os.path.dirname(os.path.abspath(__file__))
+    with open(os.path.join(here, "arithmetic_lossless.raw"), "wb") as file:
+        file.write(bytes(sample for row in image for pixel in row for sample in pixel))
+    # the second and third components share a table, L = 0 and U = 1, while the first's has L = 1 and U = 3
+    write_image(os.path.join(here, "arithmetic_lossless.jpg"), image, width, height, 8, 6, [0, 1, 1], {0: 0x31, 1: 0x10})
+    write_image(os.path.join(here, "arithmetic_lossless_one_table.jpg"), image, width, height, 8, 6, [0, 0, 0], {0: 0x31})
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,161 @@
+#!/usr/bin/env python3
+"""Writes hierarchical_ycbcr.jpg and hierarchical_lossless_components.jpg, hierarchical images of three
+components, 1, 2 and 3, so YCbCr, whose first frame is the same 8x8 extended sequential frame of
+Y 100, Cb 90 and Cr 160 everywhere, expanded both ways for a 16x16 differential frame.
+
+In hierarchical_ycbcr.jpg that's a differential DCT frame coded in a scan for each component, whose
+blocks only have DC terms, so each 8x8 quarter of each component is a constant.
+In hierarchical_lossless_components.jpg it's a differential lossless frame coded in a scan for each
+component, each with a Huffman table of its own, with no prediction, 10918-1, H.1.2.1, P. 134, adding
+x to Y, y to Cb and taking x from Cr.
+
+No encoder at hand writes hierarchical images, so the frames are put together here, following
+10918-1, Annex J, P. 149, without looking at the decoder.
+
+Run from this directory: python3 hierarchical_components.py
+"""
+import os
+
+
+def huffman_codes(bits, values):
+    """The codes of a table given as in DHT, 10918-1, C.2, P. 51"""
+    codes = {}
+    code = 0
+    k = 0
+    for length in range(1, 17):
+        for _ in range(bits[length - 1]):
+            codes[values[k]] = (code, length)
+            code += 1
+            k += 1
+        code <<= 1
+    return codes
+
+
+class BitWriter:
+    def __init__(self):
+        self.bits = []
+
+    def put(self, value, length):
+        for i in range(length - 1, -1, -1):
+            self.bits.append((value >> i) & 1)
+
+    def put_difference(self, codes, difference):
+        """The magnitude category's code and the difference's extra bits, 10918-1, F.1.2.1, P. 88"""
+        ssss = abs(difference).bit_length()
+        self.put(*codes[ssss])
+        if 0 < ssss < 16:
+            self.put(difference if difference > 0 else difference + (1 << ssss) - 1, ssss)
+
+    def bytes(self):
+        """Padded with 1 bits, with a 0 stuffed after each 0xFF"""
+        bits = self.bits + [1] * (-len(self.bits) % 8)
+        out = bytearray()
+        for i in range(0, len(bits), 8):
+            byte = int("".join(map(str, bits[i:i + 8])), 2)
+            out.append(byte)
+            if byte == 0xFF:
+                out.append(0)
+        return bytes(out)
+
+
+def segment(marker, body):
+    return bytes([0xFF, marker]) + (len(body) + 2).to_bytes(2, "big") + bytes(body)
+
+
+def frame_header(marker, size):
+    return segment(marker, [8, 0, size, 0, size, 3, 1, 0x11, 0, 2, 0x11, 0, 3, 0x11, 0])
+
+
+def scan_header(components, s_s, s_e):
+    """`components` are pairs of identifiers and table selectors, the same for DC and AC"""
+    body = [len(components)]
+    for c_s, table in components:
+        body += [c_s, table << 4 | table]
+    return segment(0xDA, body + [s_s, s_e, 0])
+
+
+def dht(t_c, t_h, bits, values):
+    return segment(0xC4, [t_c << 4 | t_h] + bits + values)
+
+
+# DC differences of up to 11 bits, and an AC table of just EOB
+DC_BITS = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0]
+DC_VALUES = list(range(12))
+AC_BITS = [1] + [0] * 15
+AC_VALUES = [0]
+DC = huffman_codes(DC_BITS, DC_VALUES)
+EOB = huffman_codes(AC_BITS, AC_VALUES)[0]
+
+
+def dc_only_blocks(writer, dc_terms, predictor=0):
+    """Blocks of DC terms with an EOB each, the DC terms coded as differences from the last one"""
+    for dc in dc_terms:
+        writer.put_difference(DC, dc - predictor)
+        writer.put(*EOB)
+        predictor = dc
+
+
+def first_frame():
+    """The 8x8 frame, its quantization table of all ones giving samples of DC / 8 + 128"""
+    out = segment(0xDB, [0] + [1] * 64)
+    out += dht(0, 0, DC_BITS, DC_VALUES) + dht(1, 0, AC_BITS, AC_VALUES)
+    out += frame_header(0xC1, 8)
+    out += scan_header([(1, 0), (2, 0), (3, 0)], 0, 63)
+    writer = BitWriter()
+    # each component has a DC prediction of its own, starting at 0
+    for dc in [(100 - 128) * 8, (90 - 128) * 8, (160 - 128) * 8]:
+        writer.put_difference(DC, dc)
+        writer.put(*EOB)
+    return out + writer.bytes()
+
+
+def hierarchical_image(last_frame):
+    out = bytearray(b"\xff\xd8")
+    out += frame_header(0xDE, 16)  # DHP
+    out += first_frame()
+    out += segment(0xDF, [0x11])  # EXP, both ways
+    out += last_frame
+    out += b"\xff\xd9"
+    return bytes(out)
+
+
+def differential_dct_frame():
+    """Each component's quarters, in raster order, differ from the first frame by this much"""
+    quarters = {1: [8, -8, 16, 0], 2: [-10, 0, 0, 10], 3: [0, 4, -4, 0]}
+    out = frame_header(0xC5, 16)
+    for c_s, differences in quarters.items():
+        out += scan_header([(c_s, 0)], 0, 63)
+        writer = BitWriter()
+        dc_only_blocks(writer, [difference * 8 for difference in differences])
+        out += writer.bytes()
+    return out
+
+
+# every difference of the lossless frame is coded in 5 bits, each table giving the categories in another order
+LOSSLESS_BITS = [0, 0, 0, 0, 17] + [0] * 11
+LOSSLESS_VALUES = [list(range(17)), list(range(16, -1, -1)), list(range(8, 17)) + list(range(8))]
+
+
+def differential_lossless_frame():
+    differences = {1: lambda x, y: x, 2: lambda x, y: y, 3: lambda x, y: -x}
+    out = b"".join(dht(0, table, LOSSLESS_BITS, values) for table, values in enumerate(LOSSLESS_VALUES))
+    out += frame_header(0xC7, 16)
+    for table, (c_s, difference) in enumerate(differences.items()):
+        codes = huffman_codes(LOSSLESS_BITS, LOSSLESS_VALUES[table])
+        out += scan_header([(c_s, table)], 0, 0)
+        writer = BitWriter()
+        for y in range(16):
+            for x in range(16):
+                writer.put_difference(codes, difference(x, y))
+        out += writer.bytes()
+    return out
+
+
+if __name__ == "__main__":
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, last_frame in [
+        ("hierarchical_ycbcr.jpg", differential_dct_frame()),
+        ("hierarchical_lossless_components.jpg", differential_lossless_frame()),
+    ]:
+        with open(os.path.join(directory, name), "wb") as file:
+            file.write(hierarchical_image(last_frame))
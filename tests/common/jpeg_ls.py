@@ -0,0 +1,375 @@
+#!/usr/bin/env python3
+"""Writes jpeg_ls.raw, a 23x13 three component image, and the JPEG-LS images of it:
+jpeg_ls_sample_interleaved.jls (lossless, ILV 2), jpeg_ls_line_interleaved_near_3.jls (NEAR 3, ILV 1)
+and jpeg_ls_non_interleaved.jls (lossless, a scan for each component, with thresholds set by LSE),
+as well as jpeg_ls_line_interleaved_near_3.raw, the samples a decoder reconstructs from the NEAR 3 image.
+
+No JPEG-LS codec is at hand, so the coded data comes from this encoder, written from ITU T.87,
+A.1 to A.7, P. 8 to 25, without looking at the decoder in src/jpeg_ls.rs. As T.87 has it, the encoder
+reconstructs each sample as the decoder does, to predict the ones that follow, which is what gets
+written to jpeg_ls_line_interleaved_near_3.raw. The 4x4 example of T.87, H.3, P. 64 is coded first,
+as a check, and must come out as in H.3.
+
+Run from this directory: python3 jpeg_ls.py
+"""
+import math
+import os
+
+# the order of run lengths for each run index, T.87, A.7.1.1, P. 21
+J = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+
+
+class BitWriter:
+    def __init__(self):
+        self.bits = []
+
+    def put(self, value, length):
+        for i in range(length - 1, -1, -1):
+            self.bits.append((value >> i) & 1)
+
+    def bytes(self):
+        """Only 7 bits follow each 0xFF, as T.87, A.1, P. 8 has it, and the last byte is padded with 0 bits"""
+        out = bytearray()
+        i = 0
+        while i < len(self.bits):
+            length = 7 if out and out[-1] == 0xFF else 8
+            chunk = self.bits[i:i + length]
+            chunk += [0] * (length - len(chunk))
+            i += length
+            out.append(int("".join(map(str, chunk)), 2))
+        if out and out[-1] == 0xFF:
+            out.append(0)
+        return bytes(out)
+
+
+class Parameters:
+    """The parameters of a scan, T.87, A.2.1, P. 9, with the default thresholds of C.2.4.1.1, P. 33
+    for any of MAXVAL, T1, T2, T3 and RESET that `presets` leaves as 0"""
+
+    def __init__(self, precision, near, presets=(0, 0, 0, 0, 0)):
+        maxval, t1, t2, t3, reset = presets
+        self.maxval = maxval or (1 << precision) - 1
+        self.near = near
+        self.range = (self.maxval + 2 * near) // (2 * near + 1) + 1
+        self.qbpp = (self.range - 1).bit_length()
+        bpp = max(2, self.maxval.bit_length())
+        self.limit = 2 * (bpp + max(8, bpp))
+
+        def clamp(value, low, high):
+            return low if value > high or value < low else value
+
+        if self.maxval >= 128:
+            factor = (min(self.maxval, 4095) + 128) // 256
+            default_t1 = clamp(factor + 2 + 3 * near, near + 1, self.maxval)
+            default_t2 = clamp(factor * 4 + 3 + 5 * near, default_t1, self.maxval)
+            default_t3 = clamp(factor * 17 + 4 + 7 * near, default_t2, self.maxval)
+        else:
+            factor = 256 // (self.maxval + 1)
+            default_t1 = clamp(max(2, 3 // factor + 3 * near), near + 1, self.maxval)
+            default_t2 = clamp(max(3, 7 // factor + 5 * near), default_t1, self.maxval)
+            default_t3 = clamp(max(4, 21 // factor + 7 * near), default_t2, self.maxval)
+        self.t1 = t1 or default_t1
+        self.t2 = t2 or default_t2
+        self.t3 = t3 or default_t3
+        self.reset = reset or 64
+
+    def quantize_gradient(self, d):
+        """A.3.3, P. 12"""
+        if d <= -self.t3:
+            return -4
+        if d <= -self.t2:
+            return -3
+        if d <= -self.t1:
+            return -2
+        if d < -self.near:
+            return -1
+        if d <= self.near:
+            return 0
+        if d < self.t1:
+            return 1
+        if d < self.t2:
+            return 2
+        if d < self.t3:
+            return 3
+        return 4
+
+    def context(self, a, b, c, d):
+        return 81 * self.quantize_gradient(d - b) + 9 * self.quantize_gradient(b - c) + self.quantize_gradient(c - a)
+
+    def quantize_error(self, error):
+        """A.4.4, P. 15"""
+        n = self.near
+        if n == 0:
+            return error
+        return (error + n) // (2 * n + 1) if error > 0 else -((n - error) // (2 * n + 1))
+
+    def reduce_error(self, error):
+        """Modulo RANGE, A.4.5, P. 15"""
+        if error < 0:
+            error += self.range
+        if error >= (self.range + 1) // 2:
+            error -= self.range
+        return error
+
+    def reconstruct(self, prediction, error):
+        """A.4.4, P. 15"""
+        value = prediction + error * (2 * self.near + 1)
+        if value < -self.near:
+            value += self.range * (2 * self.near + 1)
+        elif value > self.maxval + self.near:
+            value -= self.range * (2 * self.near + 1)
+        return min(max(value, 0), self.maxval)
+
+
+def median_edge_detector(a, b, c):
+    """A.4.1, P. 13"""
+    if c >= max(a, b):
+        return min(a, b)
+    if c <= min(a, b):
+        return max(a, b)
+    return a + b - c
+
+
+class Encoder:
+    def __init__(self, parameters):
+        self.parameters = parameters
+        self.writer = BitWriter()
+        a_init = max(2, (parameters.range + 32) // 64)
+        # A, B, C and N of each regular mode context, and A, N and Nn of the two run interruption ones, A.2.1, P. 10
+        self.contexts = [[a_init, 0, 0, 1] for _ in range(365)]
+        self.run_contexts = [[a_init, 1, 0, 0], [a_init, 1, 0, 1]]
+
+    def golomb(self, k, value, limit):
+        """A.5.3, P. 17, with the escape for values too long to code"""
+        qbpp = self.parameters.qbpp
+        high = value >> k
+        if high < limit - qbpp - 1:
+            self.writer.put(0, high)
+            self.writer.put(1, 1)
+            if k:
+                self.writer.put(value & ((1 << k) - 1), k)
+        else:
+            self.writer.put(0, limit - qbpp - 1)
+            self.writer.put(1, 1)
+            self.writer.put(value - 1, qbpp)
+
+    def regular(self, q, prediction, x):
+        """Codes a sample in regular mode, A.4 to A.6, P. 13 to 19, returning its reconstruction"""
+        p = self.parameters
+        sign = -1 if q < 0 else 1
+        context = self.contexts[abs(q)]
+        a, b, c, n = context
+        k = 0
+        while (n << k) < a:
+            k += 1
+        prediction = min(max(prediction + sign * c, 0), p.maxval)
+        error = p.reduce_error(p.quantize_error(sign * (x - prediction)))
+        mapped = -error - 1 if k == 0 and p.near == 0 and 2 * b + n - 1 < 0 else error
+        self.golomb(k, 2 * mapped if mapped >= 0 else -2 * mapped - 1, p.limit)
+
+        # A.6, P. 18
+        context[1] += error * (2 * p.near + 1)
+        context[0] += abs(error)
+        if context[3] == p.reset:
+            context[0] >>= 1
+            context[1] >>= 1
+            context[3] >>= 1
+        context[3] += 1
+        if context[1] <= -context[3]:
+            context[1] += context[3]
+            if context[2] > -128:
+                context[2] -= 1
+            if context[1] <= -context[3]:
+                context[1] = -context[3] + 1
+        elif context[1] > 0:
+            context[1] -= context[3]
+            if context[2] < 127:
+                context[2] += 1
+            if context[1] > 0:
+                context[1] = 0
+        return p.reconstruct(prediction, sign * error)
+
+    def run_interruption_error(self, r_itype, error, run_index):
+        """A.7.2, P. 22"""
+        context = self.run_contexts[r_itype]
+        a, n, nn = context[:3]
+        k = 0
+        while (n << k) < a + (n >> 1) * r_itype:
+            k += 1
+        mapping = (k == 0 and error > 0 and 2 * nn < n) or (error < 0 and 2 * nn >= n) or (error < 0 and k != 0)
+        mapped = 2 * abs(error) - r_itype - int(mapping)
+        self.golomb(k, mapped, self.parameters.limit - J[run_index] - 1)
+
+        if error < 0:
+            context[2] += 1
+        context[0] += (mapped + 1 - r_itype) >> 1
+        if context[1] == self.parameters.reset:
+            context[0] >>= 1
+            context[1] >>= 1
+            context[2] >>= 1
+        context[1] += 1
+
+    def run_length(self, run, end_of_line, run_index):
+        """A.7.1.2, P. 21, returning the run index that follows"""
+        while run >= 1 << J[run_index]:
+            self.writer.put(1, 1)
+            run -= 1 << J[run_index]
+            run_index = min(31, run_index + 1)
+        if end_of_line:
+            if run > 0:
+                self.writer.put(1, 1)
+        else:
+            self.writer.put(0, 1)
+            if J[run_index]:
+                self.writer.put(run, J[run_index])
+        return run_index
+
+
+def encode(image, width, height, components, parameters, interleave):
+    """Codes the scan of `image`, indexed by line, sample and component, lossless or near-lossless,
+    returning its coded data and the image as it's reconstructed"""
+    encoder = Encoder(parameters)
+    p = parameters
+    previous = [[0] * components for _ in range(width)]
+    first_above = [0] * components
+    run_indices = [0] * components
+    reconstructed = []
+    for y in range(height):
+        current = [[None] * components for _ in range(width)]
+        # Rc of the first sample of a line is Rb of the line above's first sample, A.2.1, P. 10
+        above_left = first_above[:]
+        for c in range(components):
+            first_above[c] = previous[0][c]
+
+        def neighbours(x, c):
+            b = previous[x][c]
+            a, c_ = (b, above_left[c]) if x == 0 else (current[x - 1][c], previous[x - 1][c])
+            d = b if x == width - 1 else previous[x + 1][c]
+            return a, b, c_, d
+
+        if interleave == 2:
+            # sample interleaved, B.3, P. 29, with a run only where every component's context is 0
+            x = 0
+            while x < width:
+                samples = [neighbours(x, c) for c in range(components)]
+                contexts = [p.context(*sample) for sample in samples]
+                if all(q == 0 for q in contexts):
+                    run_values = [sample[0] for sample in samples]
+                    run = 0
+                    while x + run < width and all(
+                        abs(image[y][x + run][c] - run_values[c]) <= p.near for c in range(components)
+                    ):
+                        current[x + run] = run_values[:]
+                        run += 1
+                    end_of_line = x + run == width
+                    run_indices[0] = encoder.run_length(run, end_of_line, run_indices[0])
+                    x += run
+                    if end_of_line:
+                        break
+                    for c in range(components):
+                        a, b = run_values[c], previous[x][c]
+                        sign = -1 if b - a < 0 else 1
+                        error = p.reduce_error(p.quantize_error(sign * (image[y][x][c] - b)))
+                        encoder.run_interruption_error(0, error, run_indices[0])
+                        current[x][c] = p.reconstruct(b, error * sign)
+                    run_indices[0] = max(0, run_indices[0] - 1)
+                else:
+                    for c in range(components):
+                        a, b, c_, _ = samples[c]
+                        current[x][c] = encoder.regular(
+                            contexts[c], median_edge_detector(a, b, c_), image[y][x][c]
+                        )
+                x += 1
+        else:
+            # a line of each component in turn for line interleaved scans, B.2, P. 28
+            for c in range(components):
+                x = 0
+                while x < width:
+                    a, b, c_, d = neighbours(x, c)
+                    q = p.context(a, b, c_, d)
+                    if q == 0:
+                        run = 0
+                        while x + run < width and abs(image[y][x + run][c] - a) <= p.near:
+                            current[x + run][c] = a
+                            run += 1
+                        end_of_line = x + run == width
+                        run_indices[c] = encoder.run_length(run, end_of_line, run_indices[c])
+                        x += run
+                        if end_of_line:
+                            break
+                        b = previous[x][c]
+                        if abs(a - b) <= p.near:
+                            error = p.reduce_error(p.quantize_error(image[y][x][c] - a))
+                            encoder.run_interruption_error(1, error, run_indices[c])
+                            current[x][c] = p.reconstruct(a, error)
+                        else:
+                            sign = -1 if b - a < 0 else 1
+                            error = p.reduce_error(p.quantize_error(sign * (image[y][x][c] - b)))
+                            encoder.run_interruption_error(0, error, run_indices[c])
+                            current[x][c] = p.reconstruct(b, error * sign)
+                        run_indices[c] = max(0, run_indices[c] - 1)
+                    else:
+                        current[x][c] = encoder.regular(q, median_edge_detector(a, b, c_), image[y][x][c])
+                    x += 1
+        reconstructed.append(current)
+        previous = current
+    return encoder.writer.bytes(), reconstructed
+
+
+def frame_header(width, height, components):
+    out = bytes([0xFF, 0xD8, 0xFF, 0xF7, 0, 8 + 3 * components, 8, 0, height, 0, width, components])
+    return out + b"".join(bytes([c + 1, 0x11, 0]) for c in range(components))
+
+
+def scan_header(component_identifiers, near, interleave):
+    out = bytes([0xFF, 0xDA, 0, 6 + 2 * len(component_identifiers), len(component_identifiers)])
+    out += b"".join(bytes([c, 0]) for c in component_identifiers)
+    return out + bytes([near, interleave, 0])
+
+
+def main():
+    example = [[[v] for v in row] for row in [[0, 0, 90, 74], [68, 50, 43, 205], [64, 145, 145, 145], [100, 145, 145, 145]]]
+    coded, _ = encode(example, 4, 4, 1, Parameters(8, 0), 0)
+    assert coded.hex() == "c000006c80208e01c00000574000006ee6000001bc18000005d800009160"
+
+    width, height = 23, 13
+    seed = 7
+    image = [[[0, 0, 0] for _ in range(width)] for _ in range(height)]
+    for y in range(height):
+        for x in range(width):
+            seed = (seed * 1103515245 + 12345) & 0x7FFFFFFF
+            noise = (seed >> 16) % 9
+            image[y][x] = [
+                min(255, x * 9 + y * 3 + (noise if x > 10 else 0)),
+                200 if (x // 6 + y // 4) % 2 else 40,
+                int(128 + 90 * math.sin(x / 4 + y / 3)),
+            ]
+
+    here = os.path.dirname(os.path.abspath(__file__))
+
+    def write(name, contents):
+        with open(os.path.join(here, name), "wb") as file:
+            file.write(contents)
+
+    write("jpeg_ls.raw", bytes(sample for row in image for pixel in row for sample in pixel))
+
+    coded, _ = encode(image, width, height, 3, Parameters(8, 0), 2)
+    write("jpeg_ls_sample_interleaved.jls", frame_header(width, height, 3) + scan_header([1, 2, 3], 0, 2) + coded + b"\xff\xd9")
+
+    coded, reconstructed = encode(image, width, height, 3, Parameters(8, 3), 1)
+    write("jpeg_ls_line_interleaved_near_3.jls", frame_header(width, height, 3) + scan_header([1, 2, 3], 3, 1) + coded + b"\xff\xd9")
+    write("jpeg_ls_line_interleaved_near_3.raw", bytes(sample for row in reconstructed for pixel in row for sample in pixel))
+
+    # MAXVAL, T1, T2, T3 and RESET, with MAXVAL and RESET left as their defaults
+    presets = (0, 5, 12, 30, 32)
+    out = frame_header(width, height, 3) + bytes([0xFF, 0xF8, 0, 13, 1])
+    out += b"".join(value.to_bytes(2, "big") for value in presets)
+    for c in range(3):
+        plane = [[[image[y][x][c]] for x in range(width)] for y in range(height)]
+        coded, _ = encode(plane, width, height, 1, Parameters(8, 0, presets), 0)
+        out += scan_header([c + 1], 0, 0) + coded
+    write("jpeg_ls_non_interleaved.jls", out + b"\xff\xd9")
+
+
+if __name__ == "__main__":
+    main()